@@ -0,0 +1,168 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Fields, Ident, Index, Lit, Meta, NestedMeta, Path, Result};
+
+const INSTANCE_ATTRIBUTE_NAME: &str = "instance";
+
+fn crate_path() -> Path {
+    syn::parse_quote!(bevy_instanced_mesh_material_pipeline)
+}
+
+pub fn derive_instance_data(ast: DeriveInput) -> Result<TokenStream> {
+    let crate_path = crate_path();
+    let struct_name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let mut shaders = Vec::new();
+    for meta in instance_attributes(&ast.attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(name_value))
+                if name_value.path.is_ident("vertex_shader")
+                    || name_value.path.is_ident("prepass_vertex_shader")
+                    || name_value.path.is_ident("mesh2d_vertex_shader") =>
+            {
+                let Lit::Str(path) = name_value.lit else {
+                    return Err(Error::new_spanned(
+                        name_value.lit,
+                        "expected a string literal",
+                    ));
+                };
+                let function = name_value.path.get_ident();
+                shaders.push(quote! {
+                    fn #function() -> bevy::render::render_resource::ShaderRef {
+                        #path.into()
+                    }
+                });
+            }
+            meta => return Err(Error::new_spanned(meta, "unknown instance attribute")),
+        }
+    }
+
+    let Data::Struct(data) = &ast.data else {
+        return Err(Error::new_spanned(
+            ast,
+            "InstanceData can only be derived for structs",
+        ));
+    };
+
+    let mut fields = Vec::new();
+    let mut checks = Vec::new();
+    let mut transform = None;
+    let field_members = match &data.fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| {
+                let ident = &field.ident;
+                (field, quote!(#ident))
+            })
+            .collect::<Vec<_>>(),
+        Fields::Unnamed(fields) => fields
+            .unnamed
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                let index = Index::from(index);
+                (field, quote!(#index))
+            })
+            .collect(),
+        Fields::Unit => Vec::new(),
+    };
+
+    for (field, member) in &field_members {
+        let field_ty = &field.ty;
+        let mut binding = quote!(#crate_path::InstanceBinding::Next);
+        for meta in instance_attributes(&field.attrs)? {
+            binding = match meta {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => {
+                    quote!(#crate_path::InstanceBinding::Skip)
+                }
+                NestedMeta::Meta(Meta::Path(path)) => {
+                    let Some(semantic) = path.get_ident() else {
+                        return Err(Error::new_spanned(path, "expected an instance semantic"));
+                    };
+                    if semantic == "transform" {
+                        transform = Some(quote! {
+                            fn transform(&self) -> bevy::math::Affine3A {
+                                self.#member.into()
+                            }
+                        });
+                    }
+                    let variant =
+                        Ident::new(&to_upper_camel_case(&semantic.to_string()), semantic.span());
+                    quote!(#crate_path::InstanceBinding::Semantic(
+                        #crate_path::InstanceSemantic::#variant
+                    ))
+                }
+                NestedMeta::Meta(Meta::NameValue(name_value))
+                    if name_value.path.is_ident("location") =>
+                {
+                    let Lit::Int(location) = name_value.lit else {
+                        return Err(Error::new_spanned(
+                            name_value.lit,
+                            "expected an integer literal",
+                        ));
+                    };
+                    quote!(#crate_path::InstanceBinding::Location(#location))
+                }
+                meta => return Err(Error::new_spanned(meta, "unknown instance attribute")),
+            };
+        }
+        fields.push(quote! {
+            builder.field::<#field_ty>(#binding);
+        });
+        checks.push(quote! {
+            .field::<#field_ty>(#binding)
+        });
+    }
+
+    // Generic instances are only checked when their layout is built.
+    let check = ast.generics.params.is_empty().then(|| {
+        quote! {
+            const _: () = #crate_path::InstanceLayoutCheck::new()
+                #(#checks)*
+                .build::<#struct_name>();
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics #crate_path::InstanceData for #struct_name #ty_generics #where_clause {
+            fn layout() -> #crate_path::InstanceLayout {
+                let mut builder = #crate_path::InstanceLayoutBuilder::new();
+                #(#fields)*
+                builder.build::<Self>()
+            }
+
+            #(#shaders)*
+            #transform
+        }
+
+        #check
+    })
+}
+
+fn instance_attributes(attrs: &[syn::Attribute]) -> Result<Vec<NestedMeta>> {
+    let mut nested = Vec::new();
+    for attr in attrs {
+        if !attr.path.is_ident(INSTANCE_ATTRIBUTE_NAME) {
+            continue;
+        }
+        match attr.parse_meta()? {
+            Meta::List(list) => nested.extend(list.nested),
+            meta => return Err(Error::new_spanned(meta, "expected #[instance(...)]")),
+        }
+    }
+    Ok(nested)
+}
+
+fn to_upper_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
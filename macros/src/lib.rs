@@ -0,0 +1,30 @@
+mod instance_data;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+/// Implements `InstanceData`, laying out one per-instance vertex attribute per field.
+///
+/// Struct attributes:
+/// - `#[instance(vertex_shader = "path.wgsl")]` replaces the built-in instanced vertex shader.
+/// - `#[instance(prepass_vertex_shader = "path.wgsl")]` replaces the built-in instanced prepass
+///   vertex shader.
+/// - `#[instance(mesh2d_vertex_shader = "path.wgsl")]` replaces the built-in instanced 2D mesh
+///   vertex shader.
+///
+/// Field attributes:
+/// - `#[instance(<semantic>)]` binds the field to the `InstanceSemantic` of that name,
+///   e.g. `#[instance(transform)]`.
+/// - `#[instance(location = 13)]` binds the field starting at the given shader location.
+/// - `#[instance(skip)]` uploads the field without binding it.
+///
+/// Instances fail to compile if their fields leave padding, bind a location twice, bind a
+/// semantic with the wrong type, or have vertex formats not covering the whole field.
+#[proc_macro_derive(InstanceData, attributes(instance))]
+pub fn derive_instance_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    instance_data::derive_instance_data(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
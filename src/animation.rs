@@ -0,0 +1,191 @@
+use std::ops::Range;
+
+use bevy::{
+    asset::load_internal_asset,
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    math::Affine3A,
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        render_asset::{PrepareAssetError, PrepareAssetSet, RenderAsset, RenderAssetPlugin},
+        render_resource::{Buffer, BufferInitDescriptor, BufferUsages},
+        renderer::RenderDevice,
+        RenderApp, RenderSet,
+    },
+};
+
+use crate::{
+    pipeline::{prepare_instance_bind_groups, INSTANCE_ANIMATION_SHADER_HANDLE},
+    InstanceData, InstanceSemantic, InstanceStorage, InstanceSystems, InstanceTransform,
+};
+
+/// Skinning poses of the clips a crowd of skinned meshes plays, shared by every entity with the
+/// same `Handle<AnimationPoses>`.
+///
+/// Each frame of a clip holds one matrix per joint, the joint's transform relative to the entity
+/// times its inverse bindpose. Instances select a clip and a phase with their
+/// [`InstanceAnimation`](crate::InstanceAnimation), and are skinned with the two frames around
+/// the phase blended, instead of with the entity's `SkinnedMesh`. The entity still needs a
+/// `SkinnedMesh` for bevy to bind the skinned mesh layout.
+///
+/// Poses are only read with [`InstanceStorage::StorageBuffer`], elsewhere instances are drawn
+/// in their rest pose and a warning is logged.
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "8a3f5c1e-2d47-4b90-b6e8-71c9d2a4f053"]
+pub struct AnimationPoses {
+    joint_count: u32,
+    /// Frames of each clip in `joints`.
+    clips: Vec<Range<u32>>,
+    joints: Vec<InstanceTransform>,
+}
+
+impl AnimationPoses {
+    pub fn new(joint_count: u32) -> Self {
+        Self {
+            joint_count,
+            clips: Vec::new(),
+            joints: Vec::new(),
+        }
+    }
+
+    /// Adds a clip with the joint matrices of each of its `frames`, returning its index.
+    ///
+    /// # Panics
+    ///
+    /// If a frame does not have a matrix for every joint, or the clip has no frames.
+    pub fn add_clip<F>(&mut self, frames: impl IntoIterator<Item = F>) -> u32
+    where
+        F: IntoIterator<Item = Mat4>,
+    {
+        let first_frame = self.frame_total();
+        for frame in frames {
+            let len = self.joints.len();
+            self.joints.extend(
+                frame
+                    .into_iter()
+                    .map(|matrix| InstanceTransform::from(Affine3A::from_mat4(matrix))),
+            );
+            assert_eq!(
+                self.joints.len() - len,
+                self.joint_count as usize,
+                "every frame of a clip needs one matrix per joint"
+            );
+        }
+        let frames = first_frame..self.frame_total();
+        assert!(!frames.is_empty(), "a clip needs at least one frame");
+
+        self.clips.push(frames);
+        self.clips.len() as u32 - 1
+    }
+
+    pub fn joint_count(&self) -> u32 {
+        self.joint_count
+    }
+
+    pub fn clip_count(&self) -> u32 {
+        self.clips.len() as u32
+    }
+
+    pub fn frame_count(&self, clip: u32) -> Option<u32> {
+        self.clips
+            .get(clip as usize)
+            .map(|frames| frames.len() as u32)
+    }
+
+    fn frame_total(&self) -> u32 {
+        self.joints.len() as u32 / self.joint_count.max(1)
+    }
+
+    /// Contents of the storage buffer read by `instance_animation.wgsl`: a header with the joint
+    /// and clip counts, the first frame and frame count of each clip, then the rows of every
+    /// joint matrix, frame after frame.
+    pub(crate) fn storage_words(&self) -> Vec<[u32; 4]> {
+        let mut words = Vec::with_capacity(1 + self.clips.len() + self.joints.len() * 3);
+        words.push([self.joint_count, self.clip_count(), 0, 0]);
+        words.extend(
+            self.clips
+                .iter()
+                .map(|frames| [frames.start, frames.len() as u32, 0, 0]),
+        );
+        words.extend(
+            self.joints
+                .iter()
+                .flat_map(|joint| joint.rows)
+                .map(bytemuck::cast::<Vec4, [u32; 4]>),
+        );
+        words
+    }
+}
+
+/// The storage buffer of [`AnimationPoses`].
+pub struct GpuAnimationPoses {
+    pub buffer: Buffer,
+}
+
+impl RenderAsset for AnimationPoses {
+    type ExtractedAsset = Vec<[u32; 4]>;
+    type PreparedAsset = GpuAnimationPoses;
+    type Param = SRes<RenderDevice>;
+
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.storage_words()
+    }
+
+    fn prepare_asset(
+        words: Self::ExtractedAsset,
+        render_device: &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        Ok(GpuAnimationPoses {
+            buffer: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("animation poses buffer"),
+                contents: bytemuck::cast_slice(&words),
+                usage: BufferUsages::STORAGE,
+            }),
+        })
+    }
+}
+
+/// Adds [`AnimationPoses`] and binds the instances of
+/// [`InstanceStorage::StorageBuffer`](crate::InstanceStorage) with them. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct InstanceAnimationPlugin;
+
+impl Plugin for InstanceAnimationPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_ANIMATION_SHADER_HANDLE,
+            "instance_animation.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_asset::<AnimationPoses>()
+            .add_plugin(RenderAssetPlugin::<AnimationPoses>::default())
+            .add_plugin(ExtractComponentPlugin::<Handle<AnimationPoses>>::default());
+        app.sub_app_mut(RenderApp).add_system(
+            prepare_instance_bind_groups
+                .in_set(RenderSet::Prepare)
+                .in_set(InstanceSystems::PrepareBindGroups)
+                .after(InstanceSystems::PrepareBuffers)
+                // Binds the prepared poses, vertex animations and images.
+                .after(PrepareAssetSet::AssetPrepare),
+        );
+    }
+}
+
+/// Warns that instances of `I` with an [`InstanceSemantic::Animation`] keep their rest pose when
+/// bound with `instance_storage`.
+pub(crate) fn warn_without_animation_poses<I: InstanceData>(instance_storage: InstanceStorage) {
+    if instance_storage == InstanceStorage::VertexBuffer
+        && I::layout()
+            .semantic_offset(InstanceSemantic::Animation)
+            .is_some()
+    {
+        warn!(
+            "Instances of {} are not animated, since animation poses are only read with \
+             InstanceStorage::StorageBuffer",
+            std::any::type_name::<I>()
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use bevy::{
+    asset::HandleId,
+    math::{Affine3A, Vec3A},
+    pbr::{MeshUniform, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::{
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::{FloatOrd, HashMap, HashSet},
+};
+
+use crate::{
+    Instance, InstanceBuffer, InstanceBuffers, InstanceSystems,
+    InstancedMeshMaterialPipelinePlugin, RenderInstances,
+};
+
+/// Draws an ordinary entity as one instance of a batch: every visible entity with this marker,
+/// the same `Handle<Mesh>` and the same material is drawn with a single instanced draw, from an
+/// [`Instance`] buffer built from their `GlobalTransform`s at extract time.
+///
+/// Needs an [`AutoInstancePlugin`] for the entity's material, which moves the entity's
+/// `Handle<M>` into an [`AutoInstancedMaterial`] so that bevy's `MaterialPlugin` no longer
+/// extracts and draws the entity by itself. Batches are not culled or sorted on the GPU, and
+/// are sorted with the other transparent meshes by the position of one of their entities.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct AutoInstance;
+
+/// The material of an entity with [`AutoInstance`], moved out of its `Handle<M>`. Inserting a
+/// `Handle<M>` again replaces it, and removing [`AutoInstance`] moves it back.
+#[derive(Component, Clone, Debug, Default)]
+pub struct AutoInstancedMaterial<M: Material>(pub Handle<M>);
+
+/// Batches entities with [`AutoInstance`] and the material `M`, drawing them with the
+/// [`InstancedMeshMaterialPipelinePlugin`] of `M`, which it adds if needed.
+pub struct AutoInstancePlugin<M>(PhantomData<M>);
+
+impl<M> Default for AutoInstancePlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M> Plugin for AutoInstancePlugin<M>
+where
+    M: Material,
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<InstancedMeshMaterialPipelinePlugin<M>>() {
+            app.add_plugin(InstancedMeshMaterialPipelinePlugin::<M>::default());
+        }
+        if !app.is_plugin_added::<AutoInstanceBufferPlugin>() {
+            app.add_plugin(AutoInstanceBufferPlugin);
+        }
+
+        app.add_system(move_auto_instanced_materials::<M>.in_base_set(CoreSet::PostUpdate));
+        app.sub_app_mut(RenderApp)
+            .add_system(extract_auto_instances::<M>.in_schedule(ExtractSchedule));
+    }
+}
+
+/// Uploads the instances of the batches of every [`AutoInstancePlugin`]. Added by
+/// [`AutoInstancePlugin`].
+pub struct AutoInstanceBufferPlugin;
+
+impl Plugin for AutoInstanceBufferPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<AutoInstancedEntities>()
+            .init_resource::<AutoInstanceBuffers>()
+            .add_system(
+                prepare_auto_instance_buffers
+                    .in_set(RenderSet::Prepare)
+                    .in_set(InstanceSystems::PrepareBuffers),
+            )
+            .add_system(
+                copy_auto_instance_mesh_uniforms
+                    .after(RenderSet::ExtractCommands)
+                    .before(RenderSet::Prepare),
+            )
+            .add_system(cleanup_auto_instance_buffers.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// The mesh, material and shadow settings shared by the entities of a batch.
+type BatchKey = (HandleId, HandleId, bool, bool);
+
+/// A batch extracted this frame: the render entity drawing it, the entity its instances are
+/// relative to and its instances.
+struct ExtractedBatch {
+    key: BatchKey,
+    entity: Entity,
+    representative: Entity,
+    instances: Vec<Instance>,
+}
+
+/// The batches of entities with [`AutoInstance`] this frame.
+#[derive(Resource, Default)]
+pub struct AutoInstancedEntities {
+    /// The render entity drawing the batch of each visible entity with [`AutoInstance`].
+    batches: HashMap<Entity, Entity>,
+    /// The entities of each batch, in the order of its instances.
+    members: HashMap<Entity, Vec<Entity>>,
+    extracted: Vec<ExtractedBatch>,
+}
+
+impl AutoInstancedEntities {
+    /// The render entity drawing the batch of `entity`, if it has [`AutoInstance`].
+    pub fn batch_of(&self, entity: Entity) -> Option<Entity> {
+        self.batches.get(&entity).copied()
+    }
+
+    /// The entities drawn by the instances of `batch`, in order.
+    pub fn members(&self, batch: Entity) -> Option<&[Entity]> {
+        self.members.get(&batch).map(Vec::as_slice)
+    }
+
+    /// A copy of the entities of every batch, to tell them apart after the frame.
+    pub fn batch_members(&self) -> HashMap<Entity, Vec<Entity>> {
+        self.members.clone()
+    }
+
+    /// The entities drawing the `visible_entities` of a view: the batch of entities with
+    /// [`AutoInstance`], once per view, and the other entities themselves.
+    pub fn drawn_entities<'a>(
+        &'a self,
+        visible_entities: &'a [Entity],
+        queued_batches: &'a mut HashSet<Entity>,
+    ) -> impl Iterator<Item = Entity> + 'a {
+        queued_batches.clear();
+        visible_entities
+            .iter()
+            .filter_map(move |&entity| match self.batches.get(&entity) {
+                Some(&batch) => queued_batches.insert(batch).then_some(batch),
+                None => Some(entity),
+            })
+    }
+}
+
+/// Instance buffers of the batches, kept across frames while their batch is drawn.
+#[derive(Resource, Default)]
+struct AutoInstanceBuffers(HashMap<BatchKey, (InstanceBuffer, Vec<Instance>)>);
+
+/// Moves the `Handle<M>` of entities with [`AutoInstance`] into their [`AutoInstancedMaterial`],
+/// and back once they lose [`AutoInstance`].
+fn move_auto_instanced_materials<M: Material>(
+    mut commands: Commands,
+    mut removed_auto_instances: RemovedComponents<AutoInstance>,
+    added_materials: Query<(Entity, &Handle<M>), With<AutoInstance>>,
+    auto_instanced_materials: Query<&AutoInstancedMaterial<M>, Without<AutoInstance>>,
+) {
+    for (entity, material) in &added_materials {
+        commands
+            .entity(entity)
+            .remove::<Handle<M>>()
+            .insert(AutoInstancedMaterial(material.clone()));
+    }
+    for entity in removed_auto_instances.iter() {
+        if let Ok(AutoInstancedMaterial(material)) = auto_instanced_materials.get(entity) {
+            commands
+                .entity(entity)
+                .remove::<AutoInstancedMaterial<M>>()
+                .insert(material.clone());
+        }
+    }
+}
+
+/// Gathers the visible entities with [`AutoInstance`] and the material `M` into batches, and
+/// spawns a render entity drawing each batch relative to the entity nearest its center, whose
+/// `MeshUniform` it takes in [`copy_auto_instance_mesh_uniforms`].
+#[allow(clippy::type_complexity)]
+fn extract_auto_instances<M: Material>(
+    mut commands: Commands,
+    mut auto_instanced: ResMut<AutoInstancedEntities>,
+    mut batches: Local<HashMap<BatchKey, (Handle<Mesh>, Handle<M>, Vec<(Entity, Affine3A)>)>>,
+    auto_instances: Extract<
+        Query<
+            (
+                Entity,
+                &ComputedVisibility,
+                &GlobalTransform,
+                &Handle<Mesh>,
+                &AutoInstancedMaterial<M>,
+                Option<With<NotShadowReceiver>>,
+                Option<With<NotShadowCaster>>,
+            ),
+            With<AutoInstance>,
+        >,
+    >,
+) {
+    for (
+        entity,
+        visibility,
+        transform,
+        mesh,
+        AutoInstancedMaterial(material),
+        not_receiver,
+        not_caster,
+    ) in &auto_instances
+    {
+        if !visibility.is_visible() {
+            continue;
+        }
+        let key = (
+            mesh.id(),
+            material.id(),
+            not_receiver.is_some(),
+            not_caster.is_some(),
+        );
+        batches
+            .entry(key)
+            .or_insert_with(|| (mesh.clone_weak(), material.clone_weak(), Vec::new()))
+            .2
+            .push((entity, transform.affine()));
+    }
+
+    let AutoInstancedEntities {
+        batches: batch_entities,
+        members: batch_members,
+        extracted,
+    } = &mut *auto_instanced;
+    for (key, (mesh, material, mut members)) in batches.drain() {
+        // A stable order keeps the instances that did not move from being uploaded again.
+        members.sort_unstable_by_key(|(entity, _)| *entity);
+
+        let center = members
+            .iter()
+            .map(|(_, transform)| transform.translation)
+            .sum::<Vec3A>()
+            / members.len() as f32;
+        let (representative, representative_transform) = members
+            .iter()
+            .min_by_key(|(_, transform)| FloatOrd(transform.translation.distance_squared(center)))
+            .copied()
+            .expect("batches have at least one entity");
+
+        let (_, _, _, not_caster) = key;
+        let transform = Mat4::from(representative_transform);
+        let mut batch_entity = commands.spawn((
+            mesh,
+            material,
+            MeshUniform {
+                transform,
+                inverse_transpose_model: transform.inverse().transpose(),
+                flags: 0,
+            },
+        ));
+        if not_caster {
+            batch_entity.insert(NotShadowCaster);
+        }
+        let batch_entity = batch_entity.id();
+
+        batch_entities.extend(members.iter().map(|(entity, _)| (*entity, batch_entity)));
+        batch_members.insert(
+            batch_entity,
+            members.iter().map(|(entity, _)| *entity).collect(),
+        );
+        let to_representative = representative_transform.inverse();
+        extracted.push(ExtractedBatch {
+            key,
+            entity: batch_entity,
+            representative,
+            instances: members
+                .into_iter()
+                .map(|(_, transform)| Instance {
+                    transform: (to_representative * transform).into(),
+                })
+                .collect(),
+        });
+    }
+}
+
+/// Gives each batch the `MeshUniform` bevy extracted for its representative entity, whose flags
+/// follow the entity's `NotShadowReceiver` and the handedness of its transform.
+fn copy_auto_instance_mesh_uniforms(
+    auto_instanced: Res<AutoInstancedEntities>,
+    mut mesh_uniforms: Query<&mut MeshUniform>,
+) {
+    for batch in &auto_instanced.extracted {
+        let Ok(mesh_uniform) = mesh_uniforms.get(batch.representative).cloned() else {
+            continue;
+        };
+        if let Ok(mut batch_uniform) = mesh_uniforms.get_mut(batch.entity) {
+            *batch_uniform = mesh_uniform;
+        }
+    }
+}
+
+/// Uploads the instances of each batch and lends its buffer to the batch's render entity for
+/// the frame.
+fn prepare_auto_instance_buffers(
+    mut auto_instanced: ResMut<AutoInstancedEntities>,
+    mut auto_instance_buffers: ResMut<AutoInstanceBuffers>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    mut render_instances: ResMut<RenderInstances<Instance>>,
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+) {
+    for batch in std::mem::take(&mut auto_instanced.extracted) {
+        let (mut instance_buffer, previous) = match auto_instance_buffers.0.remove(&batch.key) {
+            Some(buffers) => buffers,
+            None => (
+                InstanceBuffer::new(&render_device, &render_adapter, &batch.instances),
+                Vec::new(),
+            ),
+        };
+        instance_buffer.write(
+            &render_device,
+            &render_adapter,
+            &render_queue,
+            &previous,
+            &batch.instances,
+        );
+
+        instance_buffers.insert(batch.entity, instance_buffer);
+        render_instances.insert(batch.entity, batch.instances);
+        auto_instanced.extracted.push(ExtractedBatch {
+            instances: Vec::new(),
+            ..batch
+        });
+    }
+    // Batches no longer drawn free their buffers.
+    auto_instance_buffers.0.clear();
+}
+
+/// Takes the buffers of the batches back from their render entities, which do not outlive the
+/// frame.
+fn cleanup_auto_instance_buffers(
+    mut auto_instanced: ResMut<AutoInstancedEntities>,
+    mut auto_instance_buffers: ResMut<AutoInstanceBuffers>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    mut render_instances: ResMut<RenderInstances<Instance>>,
+) {
+    auto_instanced.batches.clear();
+    auto_instanced.members.clear();
+    for batch in auto_instanced.extracted.drain(..) {
+        if let (Some(instance_buffer), Some(instances)) = (
+            instance_buffers.remove(&batch.entity),
+            render_instances.remove(&batch.entity),
+        ) {
+            auto_instance_buffers
+                .0
+                .insert(batch.key, (instance_buffer, instances));
+        }
+    }
+}
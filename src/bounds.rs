@@ -0,0 +1,177 @@
+use bevy::{
+    math::{Affine3A, Mat3A, Vec3A},
+    prelude::*,
+    render::primitives::Aabb,
+    utils::HashSet,
+};
+
+use crate::{InstanceData, Instances};
+
+/// Grows the [`Aabb`] of entities with [`Instances`] of `I` to enclose every instance, so bevy's
+/// frustum culling does not hide instances away from the mesh's origin.
+///
+/// Runs when the instances, the mesh handle or the mesh itself change, when the mesh is loaded,
+/// and when bevy first computes the entity's [`Aabb`] from its mesh.
+#[allow(clippy::type_complexity)]
+pub fn update_instanced_aabbs<I: InstanceData>(
+    meshes: Res<Assets<Mesh>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut instanced_meshes: Query<(Ref<Instances<I>>, Ref<Handle<Mesh>>, &mut Aabb)>,
+) {
+    let changed_meshes: HashSet<_> = mesh_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle.id()),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect();
+
+    for (instances, mesh_handle, mut aabb) in &mut instanced_meshes {
+        if !instances.is_changed()
+            && !mesh_handle.is_changed()
+            && !aabb.is_added()
+            && !changed_meshes.contains(&mesh_handle.id())
+        {
+            continue;
+        }
+
+        let Some(mesh_aabb) = meshes.get(&*mesh_handle).and_then(Mesh::compute_aabb) else {
+            continue;
+        };
+        *aabb = instanced_aabb(&mesh_aabb, &instances).unwrap_or(mesh_aabb);
+    }
+}
+
+/// Union of `mesh_aabb` transformed by each instance, or `None` if there are no instances.
+pub fn instanced_aabb<I: InstanceData>(mesh_aabb: &Aabb, instances: &[I]) -> Option<Aabb> {
+    let mut bounds: Option<(Vec3A, Vec3A)> = None;
+    for instance in instances {
+        let (min, max) = transformed_min_max(mesh_aabb, &instance.transform());
+        bounds = Some(match bounds {
+            Some((bounds_min, bounds_max)) => (bounds_min.min(min), bounds_max.max(max)),
+            None => (min, max),
+        });
+    }
+
+    bounds.map(|(min, max)| Aabb {
+        center: 0.5 * (max + min),
+        half_extents: 0.5 * (max - min),
+    })
+}
+
+pub(crate) fn transformed_min_max(aabb: &Aabb, transform: &Affine3A) -> (Vec3A, Vec3A) {
+    let center = transform.transform_point3a(aabb.center);
+    let matrix = transform.matrix3;
+    let half_extents = Mat3A::from_cols(
+        matrix.x_axis.abs(),
+        matrix.y_axis.abs(),
+        matrix.z_axis.abs(),
+    ) * aabb.half_extents;
+    (center - half_extents, center + half_extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::{AssetPlugin, HandleId},
+        core::TaskPoolPlugin,
+        render::mesh::shape,
+    };
+
+    use super::*;
+    use crate::Instance;
+
+    fn min_max(aabb: Aabb) -> (Vec3, Vec3) {
+        (aabb.min().into(), aabb.max().into())
+    }
+
+    fn assert_near(a: (Vec3, Vec3), b: (Vec3, Vec3)) {
+        assert!(
+            a.0.abs_diff_eq(b.0, 1e-5) && a.1.abs_diff_eq(b.1, 1e-5),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn instanced_aabb_of_no_instances_is_none() {
+        let mesh_aabb = Aabb::from_min_max(Vec3::splat(-1.0), Vec3::splat(1.0));
+        assert!(instanced_aabb::<Instance>(&mesh_aabb, &[]).is_none());
+    }
+
+    #[test]
+    fn instanced_aabb_bounds_each_transformed_instance() {
+        let mesh_aabb = Aabb::from_min_max(Vec3::new(0.0, -1.0, -2.0), Vec3::new(4.0, 1.0, 2.0));
+
+        let rotated = Instance::from(Transform::from_rotation(Quat::from_rotation_z(
+            std::f32::consts::FRAC_PI_2,
+        )));
+        assert_near(
+            min_max(instanced_aabb(&mesh_aabb, &[rotated]).unwrap()),
+            (Vec3::new(-1.0, 0.0, -2.0), Vec3::new(1.0, 4.0, 2.0)),
+        );
+
+        let scaled = Instance::from(Transform::from_scale(Vec3::new(2.0, 1.0, 0.5)));
+        assert_near(
+            min_max(instanced_aabb(&mesh_aabb, &[scaled]).unwrap()),
+            (Vec3::new(0.0, -1.0, -1.0), Vec3::new(8.0, 1.0, 1.0)),
+        );
+
+        let instances = [
+            Instance::from_translation(Vec3::new(-10.0, 0.0, 0.0)),
+            Instance::from_translation(Vec3::new(0.0, 5.0, 3.0)),
+        ];
+        assert_near(
+            min_max(instanced_aabb(&mesh_aabb, &instances).unwrap()),
+            (Vec3::new(-10.0, -1.0, -2.0), Vec3::new(4.0, 6.0, 5.0)),
+        );
+    }
+
+    #[test]
+    fn update_instanced_aabbs_once_the_mesh_is_loaded() {
+        let mut app = App::new();
+        app.add_plugin(TaskPoolPlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_system(update_instanced_aabbs::<Instance>);
+        let mesh = app
+            .world
+            .resource::<Assets<Mesh>>()
+            .get_handle(HandleId::random::<Mesh>());
+        let entity = app
+            .world
+            .spawn((
+                Instances(vec![Instance::from_translation(Vec3::new(10.0, 0.0, 0.0))]),
+                mesh.clone(),
+                Aabb::default(),
+            ))
+            .id();
+        app.update();
+
+        app.world
+            .resource_mut::<Assets<Mesh>>()
+            .set_untracked(mesh, Mesh::from(shape::Cube { size: 2.0 }));
+        // Asset events are sent after the systems reading them.
+        app.update();
+        app.update();
+        assert_near(
+            min_max(*app.world.get::<Aabb>(entity).unwrap()),
+            (Vec3::new(9.0, -1.0, -1.0), Vec3::new(11.0, 1.0, 1.0)),
+        );
+    }
+
+    #[test]
+    fn instanced_aabb_bounds_rotated_corners_tightly() {
+        let mesh_aabb = Aabb::from_min_max(Vec3::splat(-1.0), Vec3::splat(1.0));
+        let rotated = Instance::from(Transform::from_rotation(Quat::from_rotation_y(
+            std::f32::consts::FRAC_PI_4,
+        )));
+        let extent = std::f32::consts::SQRT_2;
+        assert_near(
+            min_max(instanced_aabb(&mesh_aabb, &[rotated]).unwrap()),
+            (
+                Vec3::new(-extent, -1.0, -extent),
+                Vec3::new(extent, 1.0, extent),
+            ),
+        );
+    }
+}
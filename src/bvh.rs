@@ -0,0 +1,702 @@
+use std::{marker::PhantomData, ops::Range};
+
+use bevy::{
+    asset::HandleId,
+    ecs::query::ReadOnlyWorldQuery,
+    math::Vec3A,
+    prelude::*,
+    render::primitives::{Aabb, Frustum},
+    utils::HashMap,
+};
+
+use crate::{bounds::transformed_min_max, Instance, InstanceData, Instances};
+
+/// Most instances in a leaf of an [`InstanceBvh`].
+const LEAF_SIZE: usize = 4;
+
+/// Keeps the [`InstanceBvh`] of every entity with [`Instances`] of `I` up to date, for spatial
+/// queries on its instances.
+pub struct InstanceBvhPlugin<I = Instance>(PhantomData<I>);
+
+impl<I> Default for InstanceBvhPlugin<I> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<I: InstanceData> Plugin for InstanceBvhPlugin<I> {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<MeshVersionsPlugin>() {
+            app.add_plugin(MeshVersionsPlugin);
+        }
+        app.add_system(update_instance_bvhs::<I, ()>.in_base_set(CoreSet::PostUpdate));
+    }
+}
+
+/// Counts the modifications of every mesh in [`MeshVersions`]. Added by [`InstanceBvhPlugin`] and
+/// [`CpuInstanceCullingPlugin`](crate::cpu_culling::CpuInstanceCullingPlugin).
+pub struct MeshVersionsPlugin;
+
+impl Plugin for MeshVersionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MeshVersions>()
+            // Asset events are sent right before.
+            .add_system(update_mesh_versions.in_base_set(CoreSet::Last));
+    }
+}
+
+/// How many times each mesh was modified, to tell whether an [`InstanceBvh`] was built from a
+/// mesh as it is now.
+#[derive(Resource, Default, Debug)]
+pub struct MeshVersions(HashMap<HandleId, u32>);
+
+impl MeshVersions {
+    pub fn get(&self, mesh: &Handle<Mesh>) -> u32 {
+        self.0.get(&mesh.id()).copied().unwrap_or_default()
+    }
+}
+
+fn update_mesh_versions(
+    mut mesh_versions: ResMut<MeshVersions>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+) {
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Modified { handle } => {
+                let version = mesh_versions.0.entry(handle.id()).or_default();
+                *version = version.wrapping_add(1);
+            }
+            AssetEvent::Removed { handle } => {
+                mesh_versions.0.remove(&handle.id());
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+}
+
+/// Bounding volume hierarchy over the instances of an entity, in the entity's space: each
+/// instance is bounded by its mesh's [`Aabb`] transformed by the instance.
+///
+/// Kept up to date on entities with [`Instances`] by an [`InstanceBvhPlugin`], and on entities
+/// with [`CpuInstanceCulling`](crate::CpuInstanceCulling). Queries return the indices of the
+/// instances whose bounds intersect the query volume, in no particular order.
+#[derive(Component, Clone, Debug, Default)]
+pub struct InstanceBvh {
+    nodes: Vec<BvhNode>,
+    /// Instance indices, each leaf referring to a range of them.
+    indices: Vec<u32>,
+    /// Bounds of each instance.
+    bounds: Vec<(Vec3A, Vec3A)>,
+    /// When the instances it was built from last changed, and their mesh and its version.
+    built_from: Option<(u32, HandleId, u32)>,
+}
+
+/// How bounds relate to a query volume.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Overlap {
+    Outside,
+    Intersects,
+    Inside,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    min: Vec3A,
+    max: Vec3A,
+    /// The first of the two children of an inner node, or the first index of a leaf.
+    first: u32,
+    /// Number of indices of a leaf, 0 for inner nodes.
+    count: u32,
+}
+
+impl InstanceBvh {
+    pub fn new<I: InstanceData>(mesh_aabb: &Aabb, instances: &[I]) -> Self {
+        let bounds: Vec<_> = instances
+            .iter()
+            .map(|instance| transformed_min_max(mesh_aabb, &instance.transform()))
+            .collect();
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            indices: (0..instances.len() as u32).collect(),
+            bounds: Vec::new(),
+            built_from: None,
+        };
+        if !instances.is_empty() {
+            bvh.nodes.push(BvhNode {
+                min: Vec3A::ZERO,
+                max: Vec3A::ZERO,
+                first: 0,
+                count: 0,
+            });
+            bvh.build(0, 0..instances.len(), &bounds);
+        }
+        bvh.bounds = bounds;
+        bvh
+    }
+
+    fn build(&mut self, node: usize, range: Range<usize>, bounds: &[(Vec3A, Vec3A)]) {
+        let indices = &mut self.indices[range.clone()];
+        let instance_bounds = |index: &u32| bounds[*index as usize];
+        let centroid = |index: &u32| {
+            let (min, max) = instance_bounds(index);
+            0.5 * (min + max)
+        };
+
+        let (mut min, mut max) = (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY));
+        let (mut centroid_min, mut centroid_max) =
+            (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY));
+        for index in indices.iter() {
+            let (instance_min, instance_max) = instance_bounds(index);
+            min = min.min(instance_min);
+            max = max.max(instance_max);
+            centroid_min = centroid_min.min(centroid(index));
+            centroid_max = centroid_max.max(centroid(index));
+        }
+
+        // Instances sharing a centroid cannot be told apart, so they stay in one leaf.
+        let extent = centroid_max - centroid_min;
+        if indices.len() <= LEAF_SIZE || extent.max_element() <= 0.0 {
+            self.nodes[node] = BvhNode {
+                min,
+                max,
+                first: range.start as u32,
+                count: indices.len() as u32,
+            };
+            return;
+        }
+
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let half = indices.len() / 2;
+        indices
+            .select_nth_unstable_by(half, |a, b| centroid(a)[axis].total_cmp(&centroid(b)[axis]));
+
+        let first = self.nodes.len();
+        let empty = self.nodes[node];
+        self.nodes.extend([empty, empty]);
+        self.nodes[node] = BvhNode {
+            min,
+            max,
+            first: first as u32,
+            count: 0,
+        };
+        let middle = range.start + half;
+        self.build(first, range.start..middle, bounds);
+        self.build(first + 1, middle..range.end, bounds);
+    }
+
+    /// Number of instances in the hierarchy.
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Bounds of all the instances, or `None` if there are none.
+    pub fn aabb(&self) -> Option<Aabb> {
+        self.nodes
+            .first()
+            .map(|root| Aabb::from_min_max(root.min.into(), root.max.into()))
+    }
+
+    /// Whether the hierarchy was built from `instances` and `mesh` as they are now. Modifying
+    /// the mesh itself is only seen in [`MeshVersions`] at the end of the frame.
+    pub fn is_current<I: InstanceData>(
+        &self,
+        instances: &Ref<Instances<I>>,
+        mesh: &Handle<Mesh>,
+        mesh_versions: &MeshVersions,
+    ) -> bool {
+        self.built_from == Some((instances.last_changed(), mesh.id(), mesh_versions.get(mesh)))
+    }
+
+    /// Instances within `radius` of `center`.
+    pub fn instances_in_sphere(&self, center: Vec3, radius: f32) -> Vec<usize> {
+        let center = Vec3A::from(center);
+        let radius_squared = radius * radius;
+        let mut indices = Vec::new();
+        self.visit(
+            |min, max| {
+                if (center - center.clamp(min, max)).length_squared() > radius_squared {
+                    Overlap::Outside
+                } else if (center - min)
+                    .abs()
+                    .max((center - max).abs())
+                    .length_squared()
+                    <= radius_squared
+                {
+                    Overlap::Inside
+                } else {
+                    Overlap::Intersects
+                }
+            },
+            |index| indices.push(index),
+        );
+        indices
+    }
+
+    /// Instances within `aabb`.
+    pub fn instances_in_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        let (aabb_min, aabb_max) = (aabb.min(), aabb.max());
+        let mut indices = Vec::new();
+        self.visit(
+            |min, max| {
+                if max.cmplt(aabb_min).any() || min.cmpgt(aabb_max).any() {
+                    Overlap::Outside
+                } else if min.cmpge(aabb_min).all() && max.cmple(aabb_max).all() {
+                    Overlap::Inside
+                } else {
+                    Overlap::Intersects
+                }
+            },
+            |index| indices.push(index),
+        );
+        indices
+    }
+
+    /// Instances within `frustum`, where `model` maps the space of the hierarchy to the space of
+    /// the frustum, usually the entity's `GlobalTransform`.
+    pub fn instances_in_frustum(&self, frustum: &Frustum, model: &Mat4) -> Vec<usize> {
+        let mut indices = Vec::new();
+        self.for_each_in_frustum(frustum, model, true, |index| indices.push(index));
+        indices
+    }
+
+    /// Visits the instances within `frustum`, ignoring its near plane unless `intersect_near`,
+    /// like [`Frustum::intersects_obb`].
+    pub(crate) fn for_each_in_frustum(
+        &self,
+        frustum: &Frustum,
+        model: &Mat4,
+        intersect_near: bool,
+        visit: impl FnMut(usize),
+    ) {
+        // Planes brought into the space of the hierarchy, where `plane · (x, 1) >= 0` inside.
+        let planes = frustum
+            .planes
+            .map(|plane| model.transpose() * plane.normal_d());
+        self.visit(
+            |min, max| {
+                let center = (0.5 * (min + max)).extend(1.0);
+                let half_extents = 0.5 * (max - min);
+                let mut overlap = Overlap::Inside;
+                for (index, plane) in planes.iter().enumerate() {
+                    // The near plane comes after the four side planes.
+                    if index == 4 && !intersect_near {
+                        continue;
+                    }
+                    let distance = plane.dot(center);
+                    let radius = Vec3A::from(plane.truncate().abs()).dot(half_extents);
+                    if distance < -radius {
+                        return Overlap::Outside;
+                    }
+                    if distance < radius {
+                        overlap = Overlap::Intersects;
+                    }
+                }
+                overlap
+            },
+            visit,
+        );
+    }
+
+    /// Visits the instances whose bounds are not [`Overlap::Outside`], testing nodes before
+    /// their instances and skipping the tests inside nodes that are [`Overlap::Inside`].
+    fn visit(&self, overlap: impl Fn(Vec3A, Vec3A) -> Overlap, mut visit: impl FnMut(usize)) {
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push((0, false));
+        }
+        while let Some((node, inside)) = stack.pop() {
+            let node = &self.nodes[node];
+            let inside = inside
+                || match overlap(node.min, node.max) {
+                    Overlap::Outside => continue,
+                    Overlap::Intersects => false,
+                    Overlap::Inside => true,
+                };
+            if node.count == 0 {
+                stack.push((node.first as usize, inside));
+                stack.push((node.first as usize + 1, inside));
+                continue;
+            }
+
+            let leaf = node.first as usize..(node.first + node.count) as usize;
+            for &index in &self.indices[leaf] {
+                let index = index as usize;
+                let (min, max) = self.bounds[index];
+                if inside || overlap(min, max) != Overlap::Outside {
+                    visit(index);
+                }
+            }
+        }
+    }
+
+    /// The nearest hit of `ray`, in the space of the hierarchy, among the instances whose bounds
+    /// it crosses. `cast_instance` returns how far along the ray it hits an instance, if it does.
+    ///
+    /// Of instances hit at the same distance, the lowest index wins.
+    pub fn nearest_ray_hit(
+        &self,
+        ray: Ray,
+        mut cast_instance: impl FnMut(usize) -> Option<f32>,
+    ) -> Option<(usize, f32)> {
+        let entry_distance = |node: &BvhNode| ray_bounds_distance(ray, node.min, node.max);
+
+        let mut nearest: Option<(usize, f32)> = None;
+        let mut stack = Vec::new();
+        if let Some(root) = self.nodes.first() {
+            stack.extend(entry_distance(root).map(|entry| (0, entry)));
+        }
+        while let Some((node, entry)) = stack.pop() {
+            if matches!(nearest, Some((_, distance)) if entry > distance) {
+                continue;
+            }
+            let node = &self.nodes[node];
+            if node.count > 0 {
+                let leaf = node.first as usize..(node.first + node.count) as usize;
+                for &index in &self.indices[leaf] {
+                    let index = index as usize;
+                    let Some(distance) = cast_instance(index) else {
+                        continue;
+                    };
+                    let closer = match nearest {
+                        Some((nearest_index, nearest_distance)) => {
+                            distance < nearest_distance
+                                || (distance == nearest_distance && index < nearest_index)
+                        }
+                        None => true,
+                    };
+                    if closer {
+                        nearest = Some((index, distance));
+                    }
+                }
+                continue;
+            }
+
+            // The nearer child is visited first, so the farther one is more likely pruned.
+            let first = node.first as usize;
+            let mut children = [first, first + 1]
+                .map(|child| entry_distance(&self.nodes[child]).map(|entry| (child, entry)));
+            if let [Some((_, a)), Some((_, b))] = children {
+                if a < b {
+                    children.swap(0, 1);
+                }
+            }
+            stack.extend(children.into_iter().flatten());
+        }
+        nearest
+    }
+}
+
+/// How far along `ray` it enters the bounds from `min` to `max`, or 0 if it starts inside.
+pub(crate) fn ray_bounds_distance(ray: Ray, min: Vec3A, max: Vec3A) -> Option<f32> {
+    let origin = Vec3A::from(ray.origin);
+    let inverse_direction = Vec3A::from(ray.direction).recip();
+    let near = (min - origin) * inverse_direction;
+    let far = (max - origin) * inverse_direction;
+    let entry = near.min(far).max_element().max(0.0);
+    let exit = near.max(far).min_element();
+    (entry <= exit).then_some(entry)
+}
+
+/// Builds the [`InstanceBvh`] of entities with [`Instances`] of `I` matching `F`, and builds it
+/// again when the instances, the mesh handle or the mesh itself change.
+#[allow(clippy::type_complexity)]
+pub fn update_instance_bvhs<I: InstanceData, F: ReadOnlyWorldQuery>(
+    mut commands: Commands,
+    meshes: Res<Assets<Mesh>>,
+    mesh_versions: Res<MeshVersions>,
+    mut removed_instances: RemovedComponents<Instances<I>>,
+    mut instanced_meshes: Query<
+        (
+            Entity,
+            Ref<Instances<I>>,
+            Ref<Handle<Mesh>>,
+            Option<&mut InstanceBvh>,
+        ),
+        F,
+    >,
+) {
+    for entity in removed_instances.iter() {
+        commands.add(move |world: &mut World| {
+            if let Some(mut entity) = world.get_entity_mut(entity) {
+                entity.remove::<InstanceBvh>();
+            }
+        });
+    }
+
+    for (entity, instances, mesh_handle, bvh) in &mut instanced_meshes {
+        if matches!(&bvh, Some(bvh) if bvh.is_current(&instances, &mesh_handle, &mesh_versions)) {
+            continue;
+        }
+
+        // Meshes still loading are tried again next frame.
+        let Some(mesh_aabb) = meshes.get(&*mesh_handle).and_then(Mesh::compute_aabb) else {
+            continue;
+        };
+        let mut new_bvh = InstanceBvh::new(&mesh_aabb, &instances);
+        new_bvh.built_from = Some((
+            instances.last_changed(),
+            mesh_handle.id(),
+            mesh_versions.get(&mesh_handle),
+        ));
+        match bvh {
+            Some(mut bvh) => *bvh = new_bvh,
+            None => {
+                commands.entity(entity).insert(new_bvh);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{asset::AssetPlugin, core::TaskPoolPlugin, render::mesh::shape};
+
+    use super::*;
+    use crate::test_utils::{random_instances, random_numbers};
+
+    fn unit_aabb() -> Aabb {
+        Aabb::from_min_max(Vec3::splat(-1.0), Vec3::splat(1.0))
+    }
+
+    fn min_max(aabb: Option<Aabb>) -> Option<(Vec3A, Vec3A)> {
+        aabb.map(|aabb| (aabb.min(), aabb.max()))
+    }
+
+    #[test]
+    fn ray_bounds_distance_enters_or_starts_inside() {
+        let distance = |origin, direction| {
+            ray_bounds_distance(
+                Ray { origin, direction },
+                Vec3A::splat(-1.0),
+                Vec3A::splat(1.0),
+            )
+        };
+
+        assert_eq!(distance(Vec3::new(0.0, 0.0, 5.0), Vec3::NEG_Z), Some(4.0));
+        assert_eq!(distance(Vec3::new(5.0, 5.0, 0.0), Vec3::NEG_X), None);
+        assert_eq!(distance(Vec3::ZERO, Vec3::X), Some(0.0));
+        assert_eq!(distance(Vec3::new(0.0, 0.0, 5.0), Vec3::Z), None);
+        // Along a face, where the direction has zero components.
+        assert_eq!(distance(Vec3::new(1.0, 0.0, 5.0), Vec3::NEG_Z), Some(4.0));
+    }
+
+    #[test]
+    fn build_covers_every_instance_once() {
+        let mut random = random_numbers(0x9e37_79b9);
+        let instances = random_instances(1000, &mut random);
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+
+        assert_eq!(bvh.len(), instances.len());
+        let mut leaves = vec![0; instances.len()];
+        for node in &bvh.nodes {
+            if node.count == 0 {
+                for child in [node.first, node.first + 1] {
+                    let child = &bvh.nodes[child as usize];
+                    assert!(child.min.cmpge(node.min).all() && child.max.cmple(node.max).all());
+                }
+                continue;
+            }
+            assert!(node.count as usize <= LEAF_SIZE);
+            for &index in &bvh.indices[node.first as usize..(node.first + node.count) as usize] {
+                let (min, max) = bvh.bounds[index as usize];
+                assert!(min.cmpge(node.min).all() && max.cmple(node.max).all());
+                leaves[index as usize] += 1;
+            }
+        }
+        assert!(leaves.iter().all(|&count| count == 1));
+
+        let (min, max) = bvh.bounds.iter().fold(
+            (Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY)),
+            |(min, max), bounds| (min.min(bounds.0), max.max(bounds.1)),
+        );
+        assert_eq!(min_max(bvh.aabb()), Some((min, max)));
+        assert!(InstanceBvh::new::<Instance>(&unit_aabb(), &[])
+            .aabb()
+            .is_none());
+    }
+
+    #[test]
+    fn build_keeps_instances_sharing_a_centroid_in_one_leaf() {
+        let instances = vec![Instance::default(); 10];
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].count, 10);
+    }
+
+    #[test]
+    fn nearest_ray_hit_matches_brute_force() {
+        let mut random = random_numbers(0x1234_5678);
+        let instances = random_instances(1000, &mut random);
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+
+        for _ in 0..200 {
+            let origin = Vec3::new(random(), random(), random()) * 160.0 - 80.0;
+            let target = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let ray = Ray {
+                origin,
+                direction: target - origin,
+            };
+            let cast = |index: usize| {
+                let (min, max) = bvh.bounds[index];
+                ray_bounds_distance(ray, min, max)
+            };
+            let brute_force = (0..instances.len())
+                .filter_map(|index| cast(index).map(|distance| (index, distance)))
+                .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            assert_eq!(bvh.nearest_ray_hit(ray, cast), brute_force);
+        }
+    }
+
+    #[test]
+    fn nearest_ray_hit_breaks_ties_by_lowest_index() {
+        // Spread along the ray so they land in different leaves, and all hit past every entry
+        // distance so none is pruned.
+        let instances: Vec<_> = (0..20)
+            .map(|i| Instance::from_translation(Vec3::new(0.0, 0.0, i as f32 * 3.0)))
+            .collect();
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 100.0),
+            direction: Vec3::NEG_Z,
+        };
+        for tied in [[3, 17], [17, 3], [0, 19]] {
+            let hit = bvh.nearest_ray_hit(ray, |index| tied.contains(&index).then_some(99.0));
+            assert_eq!(hit, Some((tied[0].min(tied[1]), 99.0)));
+        }
+    }
+
+    fn sorted(mut indices: Vec<usize>) -> Vec<usize> {
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn instances_in_aabb_match_brute_force() {
+        let mut random = random_numbers(0x0bad_cafe);
+        let instances = random_instances(1000, &mut random);
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+
+        for _ in 0..100 {
+            let center = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let half_extents = Vec3::new(random(), random(), random()) * 30.0;
+            let aabb = Aabb::from_min_max(center - half_extents, center + half_extents);
+            let brute_force = (0..instances.len())
+                .filter(|&index| {
+                    let (min, max) = bvh.bounds[index];
+                    max.cmpge(aabb.min()).all() && min.cmple(aabb.max()).all()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(sorted(bvh.instances_in_aabb(&aabb)), brute_force);
+        }
+    }
+
+    #[test]
+    fn instances_in_sphere_match_brute_force() {
+        let mut random = random_numbers(0xdead_beef);
+        let instances = random_instances(1000, &mut random);
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+
+        for _ in 0..100 {
+            let center = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let radius = random() * 30.0;
+            let brute_force = (0..instances.len())
+                .filter(|&index| {
+                    let (min, max) = bvh.bounds[index];
+                    let center = Vec3A::from(center);
+                    (center - center.clamp(min, max)).length() <= radius
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(sorted(bvh.instances_in_sphere(center, radius)), brute_force);
+        }
+    }
+
+    #[test]
+    fn instances_in_frustum_match_brute_force() {
+        let mut random = random_numbers(0x5eed_1e55);
+        let instances = random_instances(1000, &mut random);
+        let bvh = InstanceBvh::new(&unit_aabb(), &instances);
+        let model = Transform::from_xyz(5.0, -3.0, 2.0)
+            .with_rotation(Quat::from_rotation_y(0.7))
+            .with_scale(Vec3::splat(1.5))
+            .compute_matrix();
+        let projection = Mat4::perspective_rh(1.0, 1.5, 20.0, 80.0);
+
+        for _ in 0..50 {
+            let eye = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let target = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let view = Mat4::look_at_rh(eye, target, Vec3::Y);
+            let frustum = Frustum::from_view_projection(&(projection * view));
+
+            for intersect_near in [true, false] {
+                let brute_force = (0..instances.len())
+                    .filter(|&index| {
+                        let (min, max) = bvh.bounds[index];
+                        let aabb = Aabb::from_min_max(min.into(), max.into());
+                        frustum.intersects_obb(&aabb, &model, intersect_near, true)
+                    })
+                    .collect::<Vec<_>>();
+                let mut indices = Vec::new();
+                bvh.for_each_in_frustum(&frustum, &model, intersect_near, |index| {
+                    indices.push(index);
+                });
+                assert_eq!(sorted(indices), brute_force);
+            }
+            assert_eq!(
+                sorted(bvh.instances_in_frustum(&frustum, &model)),
+                (0..instances.len())
+                    .filter(|&index| {
+                        let (min, max) = bvh.bounds[index];
+                        let aabb = Aabb::from_min_max(min.into(), max.into());
+                        frustum.intersects_obb(&aabb, &model, true, true)
+                    })
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn update_instance_bvhs_rebuilds_when_the_mesh_is_modified() {
+        let mut app = App::new();
+        app.add_plugin(TaskPoolPlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_plugin(InstanceBvhPlugin::<Instance>::default());
+        let mesh = app
+            .world
+            .resource_mut::<Assets<Mesh>>()
+            .add(Mesh::from(shape::Cube { size: 2.0 }));
+        let entity = app
+            .world
+            .spawn((Instances(vec![Instance::default()]), mesh.clone()))
+            .id();
+        let bvh_aabb = |app: &App| min_max(app.world.get::<InstanceBvh>(entity).unwrap().aabb());
+
+        app.update();
+        assert_eq!(
+            bvh_aabb(&app),
+            Some((Vec3A::splat(-1.0), Vec3A::splat(1.0)))
+        );
+
+        *app.world
+            .resource_mut::<Assets<Mesh>>()
+            .get_mut(&mesh)
+            .unwrap() = Mesh::from(shape::Cube { size: 4.0 });
+        // The modification is seen at the end of the frame, and rebuilt from the next one.
+        app.update();
+        app.update();
+        assert_eq!(
+            bvh_aabb(&app),
+            Some((Vec3A::splat(-2.0), Vec3A::splat(2.0)))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use bevy::{
+    pbr::{LightEntity, MeshUniform},
+    prelude::*,
+    render::{
+        primitives::Frustum,
+        render_resource::*,
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewSet},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    bvh::{update_instance_bvhs, InstanceBvh, MeshVersions, MeshVersionsPlugin},
+    culling::InstanceCullingBounds,
+    indirect::IndirectInstances,
+    lod::InstancedLodEntities,
+    pipeline::{instance_bind_group, InstanceBindGroupLayout, InstanceBindingsKey},
+    sorting::{GpuFrontToBackSorting, GpuInstanceSorting},
+    storage_instances_supported, InstanceBuffers, InstanceData, InstanceSystems, Instances,
+    RenderInstances,
+};
+
+/// Culls the instances of an entity against every view on the CPU, walking its [`InstanceBvh`]
+/// so groups of instances outside the view are rejected together, and uploads only the
+/// instances inside each view for it.
+///
+/// Suits very large sets of instances that seldom change, since the hierarchy is built again
+/// whenever they do. Instances are tested with their mesh's bounds, and keep no particular
+/// order. Entities with [`GpuInstanceCulling`](crate::GpuInstanceCulling),
+/// [`InstancedLods`](crate::InstancedLods), [`IndirectInstances`], [`GpuInstanceSorting`] or
+/// [`GpuFrontToBackSorting`] are not culled on the CPU.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct CpuInstanceCulling;
+
+/// Adds the resources behind [`CpuInstanceCulling`]. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct CpuInstanceCullingPlugin;
+
+impl Plugin for CpuInstanceCullingPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<MeshVersionsPlugin>() {
+            app.add_plugin(MeshVersionsPlugin);
+        }
+        app.sub_app_mut(RenderApp)
+            .init_resource::<RenderInstanceBvhs>()
+            .init_resource::<CpuCulledInstanceBuffers>()
+            .add_system(forget_instance_bvhs.in_schedule(ExtractSchedule))
+            .add_system(cleanup_cpu_culled_instance_buffers.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Adds culling of the instances of `I` to [`CpuInstanceCullingPlugin`].
+pub(crate) fn add_cpu_instance_culling<I: InstanceData>(app: &mut App) {
+    if !app.is_plugin_added::<CpuInstanceCullingPlugin>() {
+        app.add_plugin(CpuInstanceCullingPlugin);
+    }
+    app.add_system(
+        update_instance_bvhs::<I, With<CpuInstanceCulling>>.in_base_set(CoreSet::PostUpdate),
+    );
+    app.sub_app_mut(RenderApp)
+        .add_system(extract_instance_bvhs::<I>.in_schedule(ExtractSchedule))
+        .add_system(
+            prepare_cpu_instance_culling::<I>
+                .in_set(RenderSet::Prepare)
+                .after(InstanceSystems::PrepareBindGroups)
+                // Shadow views are spawned before the view uniforms are prepared.
+                .after(ViewSet::PrepareUniforms),
+        );
+}
+
+/// The [`InstanceBvh`] of every entity with [`CpuInstanceCulling`], copied when it is rebuilt.
+///
+/// Only hierarchies built from the instances and mesh as they are now are kept, so instances
+/// changed since are drawn unculled until their hierarchy is rebuilt.
+#[derive(Resource, Default, Deref)]
+pub struct RenderInstanceBvhs(HashMap<Entity, InstanceBvh>);
+
+/// Drops the hierarchies of entities no longer culled on the CPU.
+#[allow(clippy::type_complexity)]
+fn forget_instance_bvhs(
+    mut render_bvhs: ResMut<RenderInstanceBvhs>,
+    culled: Extract<Query<(), (With<InstanceBvh>, With<CpuInstanceCulling>)>>,
+) {
+    render_bvhs.0.retain(|entity, _| culled.contains(*entity));
+}
+
+#[allow(clippy::type_complexity)]
+fn extract_instance_bvhs<I: InstanceData>(
+    mut render_bvhs: ResMut<RenderInstanceBvhs>,
+    mesh_versions: Extract<Res<MeshVersions>>,
+    culled: Extract<
+        Query<
+            (Entity, Ref<InstanceBvh>, Ref<Instances<I>>, &Handle<Mesh>),
+            With<CpuInstanceCulling>,
+        >,
+    >,
+) {
+    for (entity, bvh, instances, mesh) in &culled {
+        if !bvh.is_current(&instances, mesh, &mesh_versions) {
+            render_bvhs.0.remove(&entity);
+        } else if bvh.is_changed() || !render_bvhs.0.contains_key(&entity) {
+            render_bvhs.0.insert(entity, bvh.clone());
+        }
+    }
+}
+
+/// Instances of one entity inside the frustum of one view.
+pub struct CpuCulledInstances {
+    instances: Buffer,
+    bind_group: Option<(InstanceBindingsKey, BindGroup)>,
+    capacity: u64,
+    len: u32,
+}
+
+impl CpuCulledInstances {
+    fn new(render_device: &RenderDevice, render_adapter: &RenderAdapter, capacity: u64) -> Self {
+        Self {
+            instances: render_device.create_buffer(&BufferDescriptor {
+                label: Some("cpu culled instance data buffer"),
+                size: capacity,
+                usage: if storage_instances_supported(render_device, render_adapter) {
+                    BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::STORAGE
+                } else {
+                    BufferUsages::VERTEX | BufferUsages::COPY_DST
+                },
+                mapped_at_creation: false,
+            }),
+            bind_group: None,
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn instances(&self) -> &Buffer {
+        &self.instances
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Binds the instances for [`InstanceStorage::StorageBuffer`](crate::InstanceStorage).
+    pub fn bind_group(&self) -> Option<&BindGroup> {
+        self.bind_group.as_ref().map(|(_, bind_group)| bind_group)
+    }
+}
+
+#[derive(Default)]
+struct CpuCulledInstancesPool {
+    culled_instances: Vec<CpuCulledInstances>,
+    used: usize,
+}
+
+/// Culled instances of every entity with [`CpuInstanceCulling`] for every view this frame.
+///
+/// Buffers are pooled per entity and reused across frames, since shadow views are spawned anew
+/// every frame.
+#[derive(Resource, Default)]
+pub struct CpuCulledInstanceBuffers {
+    pools: HashMap<Entity, CpuCulledInstancesPool>,
+    views: HashMap<(Entity, Entity), usize>,
+}
+
+impl CpuCulledInstanceBuffers {
+    /// The instances of `entity` inside `view`, if they were culled this frame.
+    pub fn get(&self, view: Entity, entity: Entity) -> Option<&CpuCulledInstances> {
+        let index = self.views.get(&(view, entity))?;
+        self.pools.get(&entity)?.culled_instances.get(*index)
+    }
+}
+
+/// Gathers the instances of `I` inside each view from the [`InstanceBvh`] of every entity with
+/// [`CpuInstanceCulling`], and uploads them for the view.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prepare_cpu_instance_culling<I: InstanceData>(
+    mut cpu_culled_instance_buffers: ResMut<CpuCulledInstanceBuffers>,
+    mut visible_instances: Local<Vec<u8>>,
+    render_bvhs: Res<RenderInstanceBvhs>,
+    instance_bind_group_layout: Res<InstanceBindGroupLayout>,
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+    render_instances: Res<RenderInstances<I>>,
+    instance_buffers: Res<InstanceBuffers>,
+    lod_entities: Res<InstancedLodEntities>,
+    views: Query<(Entity, &ExtractedView, Option<&LightEntity>)>,
+    culled_meshes: Query<
+        &MeshUniform,
+        (
+            Without<InstanceCullingBounds>,
+            Without<IndirectInstances>,
+            Without<GpuInstanceSorting>,
+            Without<GpuFrontToBackSorting>,
+        ),
+    >,
+) {
+    let stride = std::mem::size_of::<I>() as u64;
+    let CpuCulledInstanceBuffers {
+        pools,
+        views: culled_views,
+    } = &mut *cpu_culled_instance_buffers;
+
+    for (view_entity, view, light_entity) in &views {
+        let view_projection = view
+            .view_projection
+            .unwrap_or_else(|| view.projection * view.transform.compute_matrix().inverse());
+        let frustum = Frustum::from_view_projection(&view_projection);
+        // Like bevy, instances in front of the near plane of a directional light's cascade still
+        // cast shadows into it.
+        let intersect_near = !matches!(light_entity, Some(LightEntity::Directional { .. }));
+
+        for (&entity, bvh) in render_bvhs.iter() {
+            // Levels of detail draw their own selection of the instances.
+            if lod_entities.get(entity).is_some() {
+                continue;
+            }
+            let (Ok(mesh_uniform), Some(instances), Some(instance_buffer)) = (
+                culled_meshes.get(entity),
+                render_instances.get(&entity),
+                instance_buffers.get(&entity),
+            ) else {
+                continue;
+            };
+            visible_instances.clear();
+            bvh.for_each_in_frustum(&frustum, &mesh_uniform.transform, intersect_near, |index| {
+                visible_instances.extend_from_slice(bytemuck::bytes_of(&instances[index]));
+            });
+
+            let pool = pools.entry(entity).or_default();
+            let capacity = (visible_instances.len() as u64)
+                .max(stride)
+                .next_power_of_two();
+            if pool.used == pool.culled_instances.len() {
+                pool.culled_instances.push(CpuCulledInstances::new(
+                    &render_device,
+                    &render_adapter,
+                    capacity,
+                ));
+            }
+            let culled_instances = &mut pool.culled_instances[pool.used];
+            if culled_instances.capacity < visible_instances.len() as u64 {
+                *culled_instances =
+                    CpuCulledInstances::new(&render_device, &render_adapter, capacity);
+            }
+            culled_views.insert((view_entity, entity), pool.used);
+            pool.used += 1;
+
+            culled_instances.len = (visible_instances.len() as u64 / stride) as u32;
+            if !visible_instances.is_empty() {
+                render_queue.write_buffer(&culled_instances.instances, 0, &visible_instances);
+            }
+
+            if let (Some(layout), Some(bindings)) =
+                (&**instance_bind_group_layout, &instance_buffer.bindings)
+            {
+                let key = bindings.key();
+                if !matches!(&culled_instances.bind_group, Some((bound, _)) if *bound == key) {
+                    let bind_group = instance_bind_group(
+                        &render_device,
+                        layout,
+                        &culled_instances.instances,
+                        bindings,
+                    );
+                    culled_instances.bind_group = Some((key, bind_group));
+                }
+            }
+        }
+    }
+}
+
+fn cleanup_cpu_culled_instance_buffers(
+    mut cpu_culled_instance_buffers: ResMut<CpuCulledInstanceBuffers>,
+) {
+    cpu_culled_instance_buffers.views.clear();
+    cpu_culled_instance_buffers.pools.retain(|_, pool| {
+        pool.culled_instances.truncate(pool.used);
+        pool.used = 0;
+        !pool.culled_instances.is_empty()
+    });
+}
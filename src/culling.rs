@@ -0,0 +1,489 @@
+use bevy::{
+    asset::{load_internal_asset, HandleId},
+    pbr::{LightEntity, MeshUniform},
+    prelude::*,
+    render::{
+        mesh::GpuBufferInfo,
+        primitives::Frustum,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewSet},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    indirect::IndirectInstances,
+    lod::InstancedLodEntities,
+    pipeline::{
+        instance_bind_group, InstanceBindGroupLayout, InstanceBindingsKey,
+        INSTANCE_CULLING_SHADER_HANDLE,
+    },
+    sorting::{GpuFrontToBackSorting, GpuInstanceSorting},
+    InstanceBuffers, InstanceData, InstanceSemantic, InstanceSystems, RenderInstances,
+};
+
+pub const INSTANCE_CULLING: &str = "instance_culling";
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Culls the instances of an entity against every view on the GPU, so only instances inside a
+/// view's frustum are drawn in it.
+///
+/// Instances are tested with the bounding sphere of the entity's mesh. Entities with
+/// [`GpuInstanceSorting`] or [`GpuFrontToBackSorting`] are not culled. Has no effect where
+/// compute shaders are not supported, such as on WebGL2.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct GpuInstanceCulling;
+
+/// Mesh space bounding sphere of an entity culled with [`GpuInstanceCulling`]: center in xyz,
+/// radius in w.
+#[derive(Component, Clone, Copy, Debug, Deref)]
+pub struct InstanceCullingBounds(pub Vec4);
+
+/// Adds the compute pass behind [`GpuInstanceCulling`]. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct InstanceCullingPlugin;
+
+impl Plugin for InstanceCullingPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_CULLING_SHADER_HANDLE,
+            "instance_culling.wgsl",
+            Shader::from_wgsl
+        );
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<InstanceCullingPipeline>()
+            .init_resource::<CulledInstanceBuffers>()
+            .add_system(extract_instance_culling_bounds.in_schedule(ExtractSchedule))
+            .add_system(cleanup_culled_instance_buffers.in_set(RenderSet::Cleanup));
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node(INSTANCE_CULLING, InstanceCullingNode);
+        render_graph.add_node_edge(
+            INSTANCE_CULLING,
+            bevy::render::main_graph::node::CAMERA_DRIVER,
+        );
+    }
+}
+
+/// Adds culling of the instances of `I` to [`InstanceCullingPlugin`].
+pub(crate) fn add_instance_culling<I: InstanceData>(app: &mut App) {
+    if !app.is_plugin_added::<InstanceCullingPlugin>() {
+        app.add_plugin(InstanceCullingPlugin);
+    }
+    app.sub_app_mut(RenderApp).add_system(
+        prepare_instance_culling::<I>
+            .in_set(RenderSet::Prepare)
+            .after(InstanceSystems::PrepareBindGroups)
+            // Shadow views are spawned before the view uniforms are prepared.
+            .after(ViewSet::PrepareUniforms),
+    );
+}
+
+#[allow(clippy::type_complexity)]
+fn extract_instance_culling_bounds(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    mut mesh_bounds: Local<HashMap<HandleId, Vec4>>,
+    mut mesh_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    meshes: Extract<Res<Assets<Mesh>>>,
+    culled_meshes: Extract<Query<(Entity, &Handle<Mesh>), With<GpuInstanceCulling>>>,
+) {
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Created { .. } => {}
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                mesh_bounds.remove(&handle.id());
+            }
+        }
+    }
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, mesh_handle) in &culled_meshes {
+        let bounds = match mesh_bounds.get(&mesh_handle.id()) {
+            Some(bounds) => *bounds,
+            None => {
+                let Some(aabb) = meshes.get(mesh_handle).and_then(Mesh::compute_aabb) else {
+                    continue;
+                };
+                let bounds = aabb.center.extend(aabb.half_extents.length());
+                mesh_bounds.insert(mesh_handle.id(), bounds);
+                bounds
+            }
+        };
+        values.push((entity, InstanceCullingBounds(bounds)));
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct InstanceCullingUniform {
+    planes: [Vec4; 5],
+    model: Mat4,
+    bounds: Vec4,
+    instance_count: u32,
+    stride: u32,
+    transform_offset: u32,
+    plane_count: u32,
+}
+
+#[derive(Resource)]
+pub struct InstanceCullingPipeline {
+    pub layout: BindGroupLayout,
+    pub pipeline: CachedComputePipelineId,
+}
+
+impl FromWorld for InstanceCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_culling_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(
+                            std::mem::size_of::<InstanceCullingUniform>() as u64,
+                        ),
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+            ],
+        });
+
+        let pipeline = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("instance_culling_pipeline".into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: INSTANCE_CULLING_SHADER_HANDLE.typed(),
+                shader_defs: Vec::new(),
+                entry_point: "cull".into(),
+            });
+
+        Self { layout, pipeline }
+    }
+}
+
+/// Instances of one entity that survived culling against one view, with the arguments to draw
+/// them indirectly.
+pub struct CulledInstances {
+    uniform: Buffer,
+    instances: Buffer,
+    indirect: Buffer,
+    bind_group: Option<(BufferId, BindGroup)>,
+    /// Keyed by the [`InstanceBindings`](crate::pipeline::InstanceBindings) bound with the instances.
+    instance_bind_group: Option<(InstanceBindingsKey, BindGroup)>,
+    capacity: u64,
+    instance_count: u32,
+}
+
+impl CulledInstances {
+    fn new(render_device: &RenderDevice, capacity: u64) -> Self {
+        Self {
+            uniform: render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance culling uniform buffer"),
+                size: std::mem::size_of::<InstanceCullingUniform>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            indirect: render_device.create_buffer(&BufferDescriptor {
+                label: Some("culled instance indirect buffer"),
+                size: std::mem::size_of::<[u32; 5]>() as u64,
+                usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            instances: render_device.create_buffer(&BufferDescriptor {
+                label: Some("culled instance data buffer"),
+                size: capacity,
+                usage: BufferUsages::VERTEX | BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+            bind_group: None,
+            instance_bind_group: None,
+            capacity,
+            instance_count: 0,
+        }
+    }
+
+    pub fn instances(&self) -> &Buffer {
+        &self.instances
+    }
+
+    pub fn indirect(&self) -> &Buffer {
+        &self.indirect
+    }
+
+    /// Binds the culled instances for [`InstanceStorage::StorageBuffer`](crate::InstanceStorage).
+    pub fn bind_group(&self) -> Option<&BindGroup> {
+        self.instance_bind_group
+            .as_ref()
+            .map(|(_, bind_group)| bind_group)
+    }
+}
+
+#[derive(Default)]
+struct CulledInstancesPool {
+    culled_instances: Vec<CulledInstances>,
+    used: usize,
+}
+
+/// Culled instances of every entity with [`GpuInstanceCulling`] for every view this frame.
+///
+/// Buffers are pooled per entity and reused across frames, since shadow views are spawned anew
+/// every frame.
+#[derive(Resource, Default)]
+pub struct CulledInstanceBuffers {
+    pools: HashMap<Entity, CulledInstancesPool>,
+    views: HashMap<(Entity, Entity), usize>,
+}
+
+impl CulledInstanceBuffers {
+    /// The instances of `entity` visible from `view`, if they were culled this frame.
+    pub fn get(&self, view: Entity, entity: Entity) -> Option<&CulledInstances> {
+        let index = self.views.get(&(view, entity))?;
+        self.pools.get(&entity)?.culled_instances.get(*index)
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prepare_instance_culling<I: InstanceData>(
+    mut culled_instance_buffers: ResMut<CulledInstanceBuffers>,
+    instance_culling_pipeline: Res<InstanceCullingPipeline>,
+    instance_bind_group_layout: Res<InstanceBindGroupLayout>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_instances: Res<RenderInstances<I>>,
+    instance_buffers: Res<InstanceBuffers>,
+    lod_entities: Res<InstancedLodEntities>,
+    views: Query<(Entity, &ExtractedView, Option<&LightEntity>)>,
+    culled_meshes: Query<
+        (Entity, &MeshUniform, &Handle<Mesh>, &InstanceCullingBounds),
+        // Their instance count is decided on the GPU, which the culling pass does not read, and
+        // culling would undo the order of sorted instances.
+        (
+            Without<IndirectInstances>,
+            Without<GpuInstanceSorting>,
+            Without<GpuFrontToBackSorting>,
+        ),
+    >,
+) {
+    // Until the pipeline is compiled, instances are drawn without culling.
+    if pipeline_cache
+        .get_compute_pipeline(instance_culling_pipeline.pipeline)
+        .is_none()
+    {
+        return;
+    }
+
+    let layout = I::layout();
+    let stride = std::mem::size_of::<I>() as u64;
+    if !stride.is_multiple_of(4) {
+        return;
+    }
+    let transform_offset = layout
+        .semantic_offset(InstanceSemantic::Transform)
+        .map_or(u32::MAX, |offset| (offset / 4) as u32);
+
+    let CulledInstanceBuffers {
+        pools,
+        views: culled_views,
+    } = &mut *culled_instance_buffers;
+    for (view_entity, view, light_entity) in &views {
+        let view_projection = view
+            .view_projection
+            .unwrap_or_else(|| view.projection * view.transform.compute_matrix().inverse());
+        let frustum = Frustum::from_view_projection(&view_projection);
+        let mut planes = [Vec4::ZERO; 5];
+        for (plane, frustum_plane) in planes.iter_mut().zip(&frustum.planes) {
+            *plane = frustum_plane.normal_d();
+        }
+        // Like bevy, instances in front of the near plane of a directional light's cascade still
+        // cast shadows into it.
+        let plane_count = match light_entity {
+            Some(LightEntity::Directional { .. }) => 4,
+            _ => 5,
+        };
+
+        for (entity, mesh_uniform, mesh_handle, bounds) in &culled_meshes {
+            // Levels of detail draw their own selection of the instances.
+            if !render_instances.contains_key(&entity) || lod_entities.get(entity).is_some() {
+                continue;
+            }
+            let (Some(instance_buffer), Some(gpu_mesh)) = (
+                instance_buffers.get(&entity),
+                render_meshes.get(mesh_handle),
+            ) else {
+                continue;
+            };
+            if instance_buffer.is_empty() {
+                continue;
+            }
+            let count = match &gpu_mesh.buffer_info {
+                GpuBufferInfo::Indexed { count, .. } => *count,
+                GpuBufferInfo::NonIndexed { vertex_count } => *vertex_count,
+            };
+
+            let pool = pools.entry(entity).or_default();
+            if pool.used == pool.culled_instances.len() {
+                pool.culled_instances.push(CulledInstances::new(
+                    &render_device,
+                    instance_buffer.capacity(),
+                ));
+            }
+            let culled_instances = &mut pool.culled_instances[pool.used];
+            if culled_instances.capacity < instance_buffer.capacity() {
+                *culled_instances =
+                    CulledInstances::new(&render_device, instance_buffer.capacity());
+            }
+            culled_views.insert((view_entity, entity), pool.used);
+            pool.used += 1;
+
+            let source = instance_buffer.buffer();
+            if !matches!(&culled_instances.bind_group, Some((id, _)) if *id == source.id()) {
+                let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("instance_culling_bind_group"),
+                    layout: &instance_culling_pipeline.layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: culled_instances.uniform.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: source.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 2,
+                            resource: culled_instances.instances.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 3,
+                            resource: culled_instances.indirect.as_entire_binding(),
+                        },
+                    ],
+                });
+                culled_instances.bind_group = Some((source.id(), bind_group));
+            }
+            if let (Some(layout), Some(bindings)) =
+                (&**instance_bind_group_layout, &instance_buffer.bindings)
+            {
+                let key = bindings.key();
+                if !matches!(&culled_instances.instance_bind_group, Some((bound, _)) if *bound == key)
+                {
+                    let bind_group = instance_bind_group(
+                        &render_device,
+                        layout,
+                        &culled_instances.instances,
+                        bindings,
+                    );
+                    culled_instances.instance_bind_group = Some((key, bind_group));
+                }
+            }
+
+            let instance_count = instance_buffer.len() as u32;
+            culled_instances.instance_count = instance_count;
+            render_queue.write_buffer(
+                &culled_instances.uniform,
+                0,
+                bytemuck::bytes_of(&InstanceCullingUniform {
+                    planes,
+                    model: mesh_uniform.transform,
+                    bounds: **bounds,
+                    instance_count,
+                    stride: (stride / 4) as u32,
+                    transform_offset,
+                    plane_count,
+                }),
+            );
+            // The compute pass counts the surviving instances up from zero.
+            render_queue.write_buffer(
+                &culled_instances.indirect,
+                0,
+                bytemuck::cast_slice(&[count, 0, 0, 0, 0]),
+            );
+        }
+    }
+}
+
+fn cleanup_culled_instance_buffers(mut culled_instance_buffers: ResMut<CulledInstanceBuffers>) {
+    culled_instance_buffers.views.clear();
+    culled_instance_buffers.pools.retain(|_, pool| {
+        pool.culled_instances.truncate(pool.used);
+        pool.used = 0;
+        !pool.culled_instances.is_empty()
+    });
+}
+
+/// Dispatches the culling compute shader for every entity and view prepared this frame.
+pub struct InstanceCullingNode;
+
+impl Node for InstanceCullingNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let culled_instance_buffers = world.resource::<CulledInstanceBuffers>();
+        let instance_culling_pipeline = world.resource::<InstanceCullingPipeline>();
+        let Some(pipeline) = world
+            .resource::<PipelineCache>()
+            .get_compute_pipeline(instance_culling_pipeline.pipeline)
+        else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("instance_culling_pass"),
+                });
+        pass.set_pipeline(pipeline);
+        for pool in culled_instance_buffers.pools.values() {
+            for culled_instances in &pool.culled_instances[..pool.used] {
+                let Some((_, bind_group)) = &culled_instances.bind_group else {
+                    continue;
+                };
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.dispatch_workgroups(
+                    culled_instances.instance_count.div_ceil(WORKGROUP_SIZE),
+                    1,
+                    1,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,751 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    asset::HandleId,
+    core_pipeline::{
+        clear_color::ClearColorConfig,
+        core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+        tonemapping::Tonemapping,
+    },
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        camera::{CameraOutputMode, RenderTarget, ScalingMode, Viewport},
+        mesh::{Indices, MeshVertexBufferLayout},
+        primitives::Aabb,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_phase::{CachedRenderPipelinePhaseItem, RenderPhase},
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, CachedPipelineState, CachedRenderPipelineId,
+            Extent3d, ImageCopyTexture, Origin3d, PipelineCache, PrimitiveTopology,
+            RenderPipelineDescriptor, ShaderDefVal, ShaderRef, SpecializedMeshPipelineError,
+            TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            TextureViewDescriptor, TextureViewDimension,
+        },
+        renderer::RenderContext,
+        view::RenderLayers,
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    pipeline::{INSTANCED_IMPOSTOR_PREPASS_SHADER_HANDLE, INSTANCED_IMPOSTOR_SHADER_HANDLE},
+    InstancedLods,
+};
+
+/// Render layer of the meshes and cameras baking impostors, which other cameras should not see.
+pub const IMPOSTOR_BAKE_LAYER: u8 = 31;
+
+/// Where impostors are baked, far enough from the scene that its shadows and its point and spot
+/// lights do not reach the bakes.
+pub const IMPOSTOR_BAKE_ORIGIN: Vec3 = Vec3::new(0.0, -10_000.0, 0.0);
+
+/// Graph node copying the views of each rendered bake into the layers of its impostor.
+pub const IMPOSTOR_BAKE_COPY: &str = "impostor_bake_copy";
+
+/// The far level of [`InstancedLods`]: beyond the last mesh level, instances are drawn as quads
+/// facing the camera, showing the entity's mesh and material as seen from the nearest of the
+/// directions of an octahedral grid around it.
+///
+/// The views are baked into the layers of the texture array of an
+/// [`InstancedImpostorMaterial`] when the entity's mesh and material have loaded, once per mesh,
+/// material and settings. Bakes are rendered around [`IMPOSTOR_BAKE_ORIGIN`], where only the
+/// directional and ambient lights of the scene, which bevy applies to every view, light them.
+/// Drawing them needs a `MaterialPlugin::<InstancedImpostorMaterial>` and an
+/// [`InstancedMeshMaterialPipelinePlugin`](crate::InstancedMeshMaterialPipelinePlugin) for
+/// `InstancedImpostorMaterial` and the entity's instances.
+#[derive(Clone, Debug)]
+pub struct InstancedImpostor {
+    pub max_distance: f32,
+    /// Views along each side of the octahedral grid of directions the mesh is baked from, which
+    /// covers every direction around the mesh with `frames * frames` views.
+    pub frames: u32,
+    /// Width and height in pixels of each view.
+    pub resolution: u32,
+}
+
+impl InstancedImpostor {
+    pub fn new(max_distance: f32) -> Self {
+        Self {
+            max_distance,
+            frames: 8,
+            resolution: 128,
+        }
+    }
+}
+
+/// The baked impostor of an entity with an [`InstancedImpostor`], added once its bake was
+/// rendered.
+#[derive(Component, Clone, Debug)]
+pub struct BakedImpostor {
+    /// A quad enclosing the mesh from every direction.
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<InstancedImpostorMaterial>,
+}
+
+/// Unlit material showing the view of an impostor baked from the direction an instance is seen
+/// from. `texture` is an array of the `frames * frames` views of an octahedral grid of
+/// directions, row by row, each centered on `center`.
+#[derive(AsBindGroup, TypeUuid, Clone, Debug)]
+#[uuid = "e2b64f17-9c3a-4d85-a0f1-6b7d93c25e48"]
+#[bind_group_data(InstancedImpostorMaterialKey)]
+#[uniform(0, ImpostorMaterialUniform)]
+pub struct InstancedImpostorMaterial {
+    /// Multiplied with the texture.
+    pub color: Color,
+    #[texture(1, dimension = "2d_array")]
+    #[sampler(2)]
+    pub texture: Handle<Image>,
+    pub frames: u32,
+    /// Center of the views in the space of the mesh, which the quads turn around.
+    pub center: Vec3,
+    /// Alpha below which fragments are discarded.
+    pub alpha_cutoff: f32,
+}
+
+impl InstancedImpostorMaterial {
+    pub fn new(texture: Handle<Image>, frames: u32) -> Self {
+        Self {
+            color: Color::WHITE,
+            texture,
+            frames,
+            center: Vec3::ZERO,
+            alpha_cutoff: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct InstancedImpostorMaterialKey {
+    frames: u32,
+}
+
+impl From<&InstancedImpostorMaterial> for InstancedImpostorMaterialKey {
+    fn from(material: &InstancedImpostorMaterial) -> Self {
+        Self {
+            frames: material.frames.max(1),
+        }
+    }
+}
+
+impl AsBindGroupShaderType<ImpostorMaterialUniform> for InstancedImpostorMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> ImpostorMaterialUniform {
+        ImpostorMaterialUniform {
+            color: self.color.as_linear_rgba_f32().into(),
+            center: self.center,
+            alpha_cutoff: self.alpha_cutoff,
+        }
+    }
+}
+
+impl Material for InstancedImpostorMaterial {
+    fn fragment_shader() -> ShaderRef {
+        INSTANCED_IMPOSTOR_SHADER_HANDLE.typed().into()
+    }
+
+    fn prepass_fragment_shader() -> ShaderRef {
+        INSTANCED_IMPOSTOR_PREPASS_SHADER_HANDLE.typed().into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Mask(self.alpha_cutoff)
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        descriptor.vertex.shader_defs.extend([
+            "INSTANCE_IMPOSTOR".into(),
+            ShaderDefVal::UInt("IMPOSTOR_FRAMES".into(), key.bind_group_data.frames),
+        ]);
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            fragment.shader_defs.push("INSTANCE_IMPOSTOR".into());
+        }
+        Ok(())
+    }
+}
+
+shader_uniform! {
+    /// The uniform of [`InstancedImpostorMaterial`](super::InstancedImpostorMaterial).
+    #[derive(Clone, Default)]
+    pub struct ImpostorMaterialUniform {
+        pub color: Vec4,
+        pub center: Vec3,
+        pub alpha_cutoff: f32,
+    }
+}
+
+/// The mesh, material, frames and resolution of a bake.
+type ImpostorBakeKey = (HandleId, HandleId, u32, u32);
+
+/// Impostors baked so far, and the bakes in progress or failed.
+#[derive(Resource, Default)]
+pub struct ImpostorBakes {
+    baked: HashMap<ImpostorBakeKey, BakedImpostor>,
+    /// The entity of each bake in progress.
+    baking: HashMap<ImpostorBakeKey, Entity>,
+    /// Bakes whose pipeline could not be created, tried again once their mesh or material
+    /// changes.
+    failed: HashSet<ImpostorBakeKey>,
+    /// How far along x from [`IMPOSTOR_BAKE_ORIGIN`] the next bake is rendered, so that bakes
+    /// in progress do not see each other.
+    next_offset: f32,
+}
+
+/// The mesh and cameras baking an impostor, despawned once the bake was rendered or failed.
+#[derive(Component)]
+pub struct ImpostorBake {
+    key: ImpostorBakeKey,
+    baked_impostor: BakedImpostor,
+    mesh: Entity,
+    cameras: Vec<Entity>,
+    /// The texture the cameras render the views into side by side, copied into the layers of
+    /// `texture` once rendered.
+    atlas: Handle<Image>,
+    texture: Handle<Image>,
+}
+
+enum ImpostorBakeState {
+    Rendered,
+    Failed,
+}
+
+/// The bakes the render world rendered or failed to render, shared by the main and render
+/// worlds.
+#[derive(Resource, Clone, Default)]
+struct ImpostorBakeStates(Arc<Mutex<HashMap<Entity, ImpostorBakeState>>>);
+
+/// Adds the baking of [`InstancedImpostor`]s. Added by
+/// [`InstancedMeshMaterialPipelinePlugin`](crate::InstancedMeshMaterialPipelinePlugin).
+pub struct InstancedImpostorPlugin;
+
+impl Plugin for InstancedImpostorPlugin {
+    fn build(&self, app: &mut App) {
+        let bake_states = ImpostorBakeStates::default();
+        // Bakes need the assets even in apps that never draw impostors.
+        app.add_asset::<InstancedImpostorMaterial>()
+            .init_resource::<ImpostorBakes>()
+            .insert_resource(bake_states.clone())
+            .add_system(finish_impostor_bakes);
+        app.sub_app_mut(RenderApp)
+            .insert_resource(bake_states)
+            .init_resource::<ExtractedImpostorBakes>()
+            .init_resource::<ImpostorBakeCopies>()
+            .add_system(extract_impostor_bakes.in_schedule(ExtractSchedule))
+            .add_system(check_impostor_bakes.in_set(RenderSet::PhaseSort));
+
+        let mut render_graph = app
+            .sub_app_mut(RenderApp)
+            .world
+            .resource_mut::<RenderGraph>();
+        render_graph.add_node(IMPOSTOR_BAKE_COPY, ImpostorBakeCopyNode);
+        render_graph.add_node_edge(
+            bevy::render::main_graph::node::CAMERA_DRIVER,
+            IMPOSTOR_BAKE_COPY,
+        );
+    }
+}
+
+/// Bakes the [`InstancedImpostor`] of every entity with the material `M` without a
+/// [`BakedImpostor`], or shares an earlier bake of the same mesh and material.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(crate) fn bake_impostors<M: Material>(
+    mut commands: Commands,
+    mut impostor_bakes: ResMut<ImpostorBakes>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    mut material_events: EventReader<AssetEvent<M>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut impostor_materials: ResMut<Assets<InstancedImpostorMaterial>>,
+    materials: Res<Assets<M>>,
+    lods: Query<(Entity, &InstancedLods, &Handle<Mesh>, &Handle<M>), Without<BakedImpostor>>,
+) {
+    let modified: HashSet<HandleId> = mesh_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } => Some(handle.id()),
+            _ => None,
+        })
+        .chain(material_events.iter().filter_map(|event| match event {
+            AssetEvent::Modified { handle } => Some(handle.id()),
+            _ => None,
+        }))
+        .collect();
+    if !modified.is_empty() {
+        impostor_bakes.failed.retain(|(mesh, material, ..)| {
+            !modified.contains(mesh) && !modified.contains(material)
+        });
+    }
+    if impostor_bakes.baking.is_empty() {
+        impostor_bakes.next_offset = 0.0;
+    }
+
+    for (entity, lods, mesh, material) in &lods {
+        let Some(impostor) = &lods.impostor else {
+            continue;
+        };
+        let frames = impostor.frames.max(1);
+        let resolution = impostor.resolution.max(1);
+
+        let key = (mesh.id(), material.id(), frames, resolution);
+        if let Some(baked_impostor) = impostor_bakes.baked.get(&key) {
+            commands.entity(entity).insert(baked_impostor.clone());
+            continue;
+        }
+        if impostor_bakes.baking.contains_key(&key) || impostor_bakes.failed.contains(&key) {
+            continue;
+        }
+        if materials.get(material).is_none() {
+            continue;
+        }
+        let Some(aabb) = meshes.get(mesh).and_then(Mesh::compute_aabb) else {
+            continue;
+        };
+        // The views of a bake lie within twice its radius of the origin of its mesh.
+        let extent = 2.0 * (Vec3::from(aabb.center).length() + impostor_radius(&aabb));
+        let origin = IMPOSTOR_BAKE_ORIGIN + Vec3::X * (impostor_bakes.next_offset + extent);
+        impostor_bakes.next_offset += 2.0 * extent;
+        let bake = spawn_impostor_bake(
+            &mut commands,
+            &mut meshes,
+            &mut images,
+            &mut impostor_materials,
+            key,
+            mesh,
+            material,
+            &aabb,
+            origin,
+        );
+        impostor_bakes.baking.insert(key, bake);
+    }
+}
+
+/// Renders `mesh` with `material` at `origin` from each view of the octahedral grid into a cell
+/// of a new atlas, with one orthographic camera per view sharing the atlas through their
+/// viewports. Returns the entity of the bake.
+#[allow(clippy::too_many_arguments)]
+fn spawn_impostor_bake<M: Material>(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    images: &mut Assets<Image>,
+    impostor_materials: &mut Assets<InstancedImpostorMaterial>,
+    key: ImpostorBakeKey,
+    mesh: &Handle<Mesh>,
+    material: &Handle<M>,
+    aabb: &Aabb,
+    origin: Vec3,
+) -> Entity {
+    let (_, _, frames, resolution) = key;
+    let center = Vec3::from(aabb.center);
+    let radius = impostor_radius(aabb);
+    let distance = 2.0 * radius;
+
+    let atlas_size = Extent3d {
+        width: resolution * frames,
+        height: resolution * frames,
+        depth_or_array_layers: 1,
+    };
+    let mut atlas = Image {
+        texture_descriptor: TextureDescriptor {
+            label: Some("impostor atlas"),
+            size: atlas_size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST
+                | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        },
+        ..default()
+    };
+    atlas.resize(atlas_size);
+    let atlas = images.add(atlas);
+
+    let texture_size = Extent3d {
+        width: resolution,
+        height: resolution,
+        depth_or_array_layers: frames * frames,
+    };
+    let mut texture = Image {
+        texture_descriptor: TextureDescriptor {
+            label: Some("impostor texture"),
+            size: texture_size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        texture_view_descriptor: Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        }),
+        ..default()
+    };
+    texture.resize(texture_size);
+    let texture = images.add(texture);
+
+    let bake_mesh = commands
+        .spawn((
+            MaterialMeshBundle::<M> {
+                mesh: mesh.clone(),
+                material: material.clone(),
+                transform: Transform::from_translation(origin),
+                ..default()
+            },
+            NotShadowCaster,
+            NotShadowReceiver,
+            RenderLayers::layer(IMPOSTOR_BAKE_LAYER),
+        ))
+        .id();
+    let views = frames * frames;
+    let cameras: Vec<Entity> = (0..views)
+        .map(|view| {
+            let cell = UVec2::new(view % frames, view / frames);
+            let direction = octahedral_direction(cell, frames);
+            commands
+                .spawn((
+                    Camera3dBundle {
+                        camera: Camera {
+                            viewport: Some(Viewport {
+                                physical_position: cell * resolution,
+                                physical_size: UVec2::splat(resolution),
+                                ..default()
+                            }),
+                            order: view as isize,
+                            target: RenderTarget::Image(atlas.clone()),
+                            // The views share the main texture, which the last one writes out.
+                            output_mode: if view + 1 == views {
+                                CameraOutputMode::default()
+                            } else {
+                                CameraOutputMode::Skip
+                            },
+                            ..default()
+                        },
+                        camera_3d: Camera3d {
+                            clear_color: if view == 0 {
+                                ClearColorConfig::Custom(Color::NONE)
+                            } else {
+                                ClearColorConfig::None
+                            },
+                            ..default()
+                        },
+                        projection: OrthographicProjection {
+                            near: 0.0,
+                            far: 2.0 * distance,
+                            scaling_mode: ScalingMode::Fixed {
+                                width: 2.0 * radius,
+                                height: 2.0 * radius,
+                            },
+                            ..default()
+                        }
+                        .into(),
+                        tonemapping: Tonemapping::None,
+                        transform: Transform::from_translation(
+                            origin + center + direction * distance,
+                        )
+                        .looking_at(origin + center, impostor_up(direction)),
+                        ..default()
+                    },
+                    RenderLayers::layer(IMPOSTOR_BAKE_LAYER),
+                ))
+                .id()
+        })
+        .collect();
+
+    let baked_impostor = BakedImpostor {
+        mesh: meshes.add(impostor_quad(radius)),
+        material: impostor_materials.add(InstancedImpostorMaterial {
+            center,
+            ..InstancedImpostorMaterial::new(texture.clone(), frames)
+        }),
+    };
+    let mut bake = commands.spawn((
+        SpatialBundle::default(),
+        ImpostorBake {
+            key,
+            baked_impostor,
+            mesh: bake_mesh,
+            cameras: cameras.clone(),
+            atlas,
+            texture,
+        },
+    ));
+    bake.push_children(&[bake_mesh]).push_children(&cameras);
+    bake.id()
+}
+
+/// Radius of the sphere around the center of `aabb` enclosing it.
+fn impostor_radius(aabb: &Aabb) -> f32 {
+    Vec3::from(aabb.half_extents).length().max(f32::EPSILON)
+}
+
+/// The direction from the center of an impostor towards the camera baking `cell` of its
+/// octahedral grid of `frames` by `frames` views, in the space of the mesh. Mirrors
+/// `impostor_direction` in `instance_impostor.wgsl`.
+fn octahedral_direction(cell: UVec2, frames: u32) -> Vec3 {
+    let p = (cell.as_vec2() + 0.5) / frames as f32 * 2.0 - 1.0;
+    let y = 1.0 - p.x.abs() - p.y.abs();
+    // The lower half of the sphere is folded over the corners of the grid.
+    let xz = if y < 0.0 {
+        (1.0 - Vec2::new(p.y, p.x).abs()) * p.signum()
+    } else {
+        p
+    };
+    Vec3::new(xz.x, y, xz.y).normalize()
+}
+
+/// The up direction of the camera baking `direction`, which the quads share.
+fn impostor_up(direction: Vec3) -> Vec3 {
+    if direction.y.abs() > 0.999 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    }
+}
+
+/// A quad in the xy plane from `-radius` to `radius`, facing +z.
+fn impostor_quad(radius: f32) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        vec![
+            [-radius, -radius, 0.0],
+            [radius, -radius, 0.0],
+            [radius, radius, 0.0],
+            [-radius, radius, 0.0],
+        ],
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; 4]);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_UV_0,
+        vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]],
+    );
+    mesh.set_indices(Some(Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+    mesh
+}
+
+/// Despawns the bakes rendered or failed last frame, keeping the impostors of the rendered ones.
+fn finish_impostor_bakes(
+    mut commands: Commands,
+    mut impostor_bakes: ResMut<ImpostorBakes>,
+    bake_states: Res<ImpostorBakeStates>,
+    bakes: Query<&ImpostorBake>,
+) {
+    let Ok(mut bake_states) = bake_states.0.lock() else {
+        return;
+    };
+    for (entity, state) in bake_states.drain() {
+        // Already finished by an earlier state.
+        let Ok(bake) = bakes.get(entity) else {
+            continue;
+        };
+        impostor_bakes.baking.remove(&bake.key);
+        match state {
+            ImpostorBakeState::Rendered => {
+                impostor_bakes
+                    .baked
+                    .insert(bake.key, bake.baked_impostor.clone());
+            }
+            ImpostorBakeState::Failed => {
+                warn!("Could not bake an impostor: the pipeline of its mesh and material failed");
+                impostor_bakes.failed.insert(bake.key);
+            }
+        }
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+struct ExtractedImpostorBake {
+    entity: Entity,
+    mesh: Entity,
+    cameras: Vec<Entity>,
+    atlas: Handle<Image>,
+    texture: Handle<Image>,
+    frames: u32,
+    resolution: u32,
+}
+
+/// Every bake in progress.
+#[derive(Resource, Default)]
+struct ExtractedImpostorBakes(Vec<ExtractedImpostorBake>);
+
+fn extract_impostor_bakes(
+    mut extracted_bakes: ResMut<ExtractedImpostorBakes>,
+    bakes: Extract<Query<(Entity, &ImpostorBake)>>,
+) {
+    extracted_bakes.0.clear();
+    extracted_bakes
+        .0
+        .extend(bakes.iter().map(|(entity, bake)| ExtractedImpostorBake {
+            entity,
+            mesh: bake.mesh,
+            cameras: bake.cameras.clone(),
+            atlas: bake.atlas.clone_weak(),
+            texture: bake.texture.clone_weak(),
+            frames: bake.key.2,
+            resolution: bake.key.3,
+        }));
+}
+
+/// The bakes whose views [`ImpostorBakeCopyNode`] copies into the layers of their impostor's
+/// texture this frame, as the atlas, texture, frames and resolution of each.
+#[derive(Resource, Default)]
+struct ImpostorBakeCopies(Vec<(Handle<Image>, Handle<Image>, u32, u32)>);
+
+/// Reports the bakes whose mesh every camera draws this frame with a ready pipeline, and the
+/// bakes whose pipeline failed. Meshes are only queued once they, their material and its images
+/// are prepared. The views of the reported bakes are copied into their impostor's texture once
+/// the cameras have rendered them.
+#[allow(clippy::too_many_arguments)]
+fn check_impostor_bakes(
+    extracted_bakes: Res<ExtractedImpostorBakes>,
+    bake_states: Res<ImpostorBakeStates>,
+    mut bake_copies: ResMut<ImpostorBakeCopies>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<Image>>,
+    opaque_phases: Query<&RenderPhase<Opaque3d>>,
+    alpha_mask_phases: Query<&RenderPhase<AlphaMask3d>>,
+    transparent_phases: Query<&RenderPhase<Transparent3d>>,
+) {
+    bake_copies.0.clear();
+    let Ok(mut bake_states) = bake_states.0.lock() else {
+        return;
+    };
+    'bakes: for bake in &extracted_bakes.0 {
+        for &camera in &bake.cameras {
+            let pipeline = queued_pipeline(&opaque_phases, camera, bake.mesh)
+                .or_else(|| queued_pipeline(&alpha_mask_phases, camera, bake.mesh))
+                .or_else(|| queued_pipeline(&transparent_phases, camera, bake.mesh));
+            let Some(pipeline) = pipeline else {
+                continue 'bakes;
+            };
+            match pipeline_cache.get_render_pipeline_state(pipeline) {
+                CachedPipelineState::Ok(_) => {}
+                CachedPipelineState::Err(_) => {
+                    bake_states.insert(bake.entity, ImpostorBakeState::Failed);
+                    continue 'bakes;
+                }
+                CachedPipelineState::Queued => continue 'bakes,
+            }
+        }
+        if !gpu_images.contains_key(&bake.atlas) || !gpu_images.contains_key(&bake.texture) {
+            continue;
+        }
+        bake_copies.0.push((
+            bake.atlas.clone_weak(),
+            bake.texture.clone_weak(),
+            bake.frames,
+            bake.resolution,
+        ));
+        bake_states.insert(bake.entity, ImpostorBakeState::Rendered);
+    }
+}
+
+/// Copies the views of the bakes rendered this frame from their atlas into the layers of their
+/// impostor's texture, after the cameras have run.
+struct ImpostorBakeCopyNode;
+
+impl Node for ImpostorBakeCopyNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let gpu_images = world.resource::<RenderAssets<Image>>();
+        for (atlas, texture, frames, resolution) in &world.resource::<ImpostorBakeCopies>().0 {
+            let (Some(atlas), Some(texture)) = (gpu_images.get(atlas), gpu_images.get(texture))
+            else {
+                continue;
+            };
+            for layer in 0..frames * frames {
+                let cell = UVec2::new(layer % frames, layer / frames) * *resolution;
+                render_context.command_encoder().copy_texture_to_texture(
+                    ImageCopyTexture {
+                        texture: &atlas.texture,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: cell.x,
+                            y: cell.y,
+                            z: 0,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    ImageCopyTexture {
+                        texture: &texture.texture,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: layer,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    Extent3d {
+                        width: *resolution,
+                        height: *resolution,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The pipeline `view` draws `entity` with in its phase of `P`, if it is queued there.
+fn queued_pipeline<P: CachedRenderPipelinePhaseItem>(
+    phases: &Query<&RenderPhase<P>>,
+    view: Entity,
+    entity: Entity,
+) -> Option<CachedRenderPipelineId> {
+    phases
+        .get(view)
+        .ok()?
+        .items
+        .iter()
+        .find(|item| item.entity() == entity)
+        .map(CachedRenderPipelinePhaseItem::cached_pipeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octahedral_directions_cover_both_hemispheres() {
+        let frames = 8;
+        let directions: Vec<Vec3> = (0..frames * frames)
+            .map(|view| octahedral_direction(UVec2::new(view % frames, view / frames), frames))
+            .collect();
+
+        for direction in &directions {
+            assert!((direction.length() - 1.0).abs() < 1e-5);
+        }
+        // The center of the grid looks down from above, its corners up from below.
+        assert!(octahedral_direction(UVec2::new(3, 3), frames).y > 0.9);
+        assert!(octahedral_direction(UVec2::new(0, 0), frames).y < -0.9);
+        assert!(octahedral_direction(UVec2::new(7, 7), frames).y < -0.9);
+        assert_eq!(
+            directions
+                .iter()
+                .filter(|direction| direction.y > 0.0)
+                .count(),
+            directions
+                .iter()
+                .filter(|direction| direction.y < 0.0)
+                .count()
+        );
+    }
+}
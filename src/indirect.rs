@@ -0,0 +1,113 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        mesh::GpuBufferInfo,
+        render_asset::RenderAssets,
+        render_resource::{Buffer, BufferDescriptor, BufferUsages},
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        RenderApp, RenderSet,
+    },
+};
+
+use crate::{storage_instances_supported, InstanceBuffers, InstanceSystems};
+
+/// Draws the instances of an entity indirectly, so compute shaders can decide how many of them
+/// are drawn without a readback.
+///
+/// The arguments are in [`InstanceBuffer::indirect`](crate::InstanceBuffer::indirect), laid out as
+/// `DrawIndexedIndirect` for indexed meshes and as `DrawIndirect` otherwise. They are written
+/// when the buffer is created and whenever the mesh or the number of [`Instances`](crate::Instances)
+/// changes, with the instance count set to the number of instances. In between, compute shaders
+/// may write up to as many instances as fit into the instance buffer and set the count.
+///
+/// Entities with [`GpuInstanceCulling`](crate::GpuInstanceCulling) are not culled while they
+/// draw indirectly. Has no effect where compute shaders are not supported, such as on WebGL2.
+#[derive(Component, Clone, Copy, Debug, Default, ExtractComponent)]
+pub struct IndirectInstances;
+
+/// Extracts [`IndirectInstances`] and writes their draw arguments. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct IndirectInstancesPlugin;
+
+impl Plugin for IndirectInstancesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ExtractComponentPlugin::<IndirectInstances>::default());
+        app.sub_app_mut(RenderApp).add_system(
+            prepare_indirect_instances
+                .in_set(RenderSet::Prepare)
+                .after(InstanceSystems::PrepareBuffers),
+        );
+    }
+}
+
+/// Indirect draw arguments of one entity and the values they were last written with.
+pub(crate) struct IndirectArgs {
+    buffer: Buffer,
+    count: u32,
+    instance_count: u32,
+}
+
+impl IndirectArgs {
+    pub(crate) fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+fn prepare_indirect_instances(
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    indirect_meshes: Query<(Entity, &Handle<Mesh>), With<IndirectInstances>>,
+) {
+    if !storage_instances_supported(&render_device, &render_adapter) {
+        return;
+    }
+
+    for (entity, instance_buffer) in instance_buffers.iter_mut() {
+        if instance_buffer.indirect.is_some() && !indirect_meshes.contains(*entity) {
+            instance_buffer.indirect = None;
+        }
+    }
+
+    for (entity, mesh_handle) in &indirect_meshes {
+        let (Some(instance_buffer), Some(gpu_mesh)) = (
+            instance_buffers.get_mut(&entity),
+            render_meshes.get(mesh_handle),
+        ) else {
+            continue;
+        };
+        let count = match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed { count, .. } => *count,
+            GpuBufferInfo::NonIndexed { vertex_count } => *vertex_count,
+        };
+        let instance_count = instance_buffer.len() as u32;
+
+        if let Some(indirect) = &instance_buffer.indirect {
+            if indirect.count == count && indirect.instance_count == instance_count {
+                continue;
+            }
+        }
+        let indirect = instance_buffer
+            .indirect
+            .get_or_insert_with(|| IndirectArgs {
+                buffer: render_device.create_buffer(&BufferDescriptor {
+                    label: Some("instance indirect buffer"),
+                    size: std::mem::size_of::<[u32; 5]>() as u64,
+                    usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                count,
+                instance_count,
+            });
+        indirect.count = count;
+        indirect.instance_count = instance_count;
+        render_queue.write_buffer(
+            &indirect.buffer,
+            0,
+            bytemuck::cast_slice(&[count, instance_count, 0, 0, 0]),
+        );
+    }
+}
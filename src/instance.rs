@@ -0,0 +1,588 @@
+use bevy::{
+    math::Affine3A,
+    prelude::*,
+    render::render_resource::{
+        ShaderDefVal, ShaderRef, VertexAttribute, VertexBufferLayout, VertexFormat, VertexStepMode,
+    },
+};
+use bevy_instanced_mesh_material_pipeline_macros::InstanceData;
+use bytemuck::{Pod, Zeroable};
+
+/// Per-instance data uploaded to the GPU for every element of [`Instances`](crate::Instances).
+///
+/// Usually derived, which lays out one vertex attribute per field in declaration order:
+///
+/// ```ignore
+/// #[derive(Clone, Copy, Pod, Zeroable, InstanceData)]
+/// #[repr(C)]
+/// #[instance(vertex_shader = "shaders/tinted_instances.wgsl")]
+/// #[instance(prepass_vertex_shader = "shaders/tinted_instances_prepass.wgsl")]
+/// struct TintedInstance {
+///     #[instance(transform)]
+///     transform: InstanceTransform,
+///     #[instance(location = 13)]
+///     tint: Vec4,
+///     phase: f32,
+/// }
+/// ```
+///
+/// Fields marked with an [`InstanceSemantic`] are bound at the location the built-in instanced
+/// shaders expect and enable the matching shader def. Other fields continue from the location
+/// after the previous field, starting at [`InstanceLayoutBuilder::FIRST_LOCATION`], unless they
+/// set `location` explicitly. Fields marked `skip` are uploaded but not bound.
+pub trait InstanceData: Pod + Send + Sync + 'static {
+    fn layout() -> InstanceLayout;
+
+    /// Vertex shader used instead of the built-in instanced vertex shader.
+    fn vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Vertex shader used instead of the built-in instanced prepass vertex shader, which also
+    /// renders shadows.
+    fn prepass_vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Vertex shader used instead of the built-in instanced 2D mesh vertex shader.
+    fn mesh2d_vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Transform of the instance relative to its entity.
+    fn transform(&self) -> Affine3A {
+        Affine3A::IDENTITY
+    }
+}
+
+/// How one instance is laid out in the instance buffer.
+#[derive(Clone, Debug)]
+pub struct InstanceLayout {
+    pub stride: u64,
+    pub attributes: Vec<VertexAttribute>,
+    pub shader_defs: Vec<ShaderDefVal>,
+    /// Byte offsets of the fields bound to an [`InstanceSemantic`].
+    pub semantics: Vec<(InstanceSemantic, u64)>,
+}
+
+impl InstanceLayout {
+    pub fn vertex_buffer_layout(&self) -> VertexBufferLayout {
+        VertexBufferLayout {
+            array_stride: self.stride,
+            step_mode: VertexStepMode::Instance,
+            attributes: self.attributes.clone(),
+        }
+    }
+
+    /// Shader defs of [`InstanceStorage::StorageBuffer`](crate::InstanceStorage): the stride and
+    /// the offsets of the semantics in words, in addition to [`InstanceLayout::shader_defs`].
+    pub fn storage_shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = self.shader_defs.clone();
+        shader_defs.push("INSTANCE_STORAGE".into());
+        shader_defs.push(ShaderDefVal::UInt(
+            "INSTANCE_STRIDE".into(),
+            (self.stride / 4) as u32,
+        ));
+        for (semantic, offset) in &self.semantics {
+            shader_defs.push(ShaderDefVal::UInt(
+                format!("{}_OFFSET", semantic.shader_def()),
+                (offset / 4) as u32,
+            ));
+        }
+        shader_defs
+    }
+
+    pub fn semantic_offset(&self, semantic: InstanceSemantic) -> Option<u64> {
+        self.semantics
+            .iter()
+            .find(|(other, _)| *other == semantic)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+/// Well-known per-instance inputs of the built-in instanced shaders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InstanceSemantic {
+    /// An [`InstanceTransform`] applied before the entity's transform.
+    Transform,
+    /// A linear RGBA `Vec4` multiplied with the base color of a `StandardMaterial`.
+    Color,
+    /// A linear RGB `Vec4` added to the emissive color of a `StandardMaterial`. Alpha is unused.
+    Emissive,
+    /// A `Vec2` scaling the metallic (x) and perceptual roughness (y) of a `StandardMaterial`.
+    MetallicRoughness,
+    /// A `u32` index of a texture array layer or atlas cell, read by
+    /// [`InstancedTextureArrayMaterial`](crate::InstancedTextureArrayMaterial) and
+    /// [`InstancedTextureAtlasMaterial`](crate::InstancedTextureAtlasMaterial).
+    Layer,
+    /// An [`InstanceAnimation`] selecting the pose of a skinned mesh from the entity's
+    /// [`AnimationPoses`](crate::AnimationPoses). Only read with
+    /// [`InstanceStorage::StorageBuffer`](crate::InstanceStorage::StorageBuffer).
+    Animation,
+    /// An [`InstanceVertexAnimation`] playing a clip of the entity's
+    /// [`VertexAnimationTexture`](crate::VertexAnimationTexture). Only read with
+    /// [`InstanceStorage::StorageBuffer`](crate::InstanceStorage::StorageBuffer).
+    VertexAnimation,
+}
+
+impl InstanceSemantic {
+    pub const fn location(self) -> u32 {
+        match self {
+            InstanceSemantic::Transform => 10,
+            InstanceSemantic::Color => 13,
+            InstanceSemantic::Emissive => 14,
+            InstanceSemantic::MetallicRoughness => 15,
+            InstanceSemantic::Layer => 9,
+            InstanceSemantic::Animation => 8,
+            InstanceSemantic::VertexAnimation => 7,
+        }
+    }
+
+    pub fn shader_def(self) -> &'static str {
+        match self {
+            InstanceSemantic::Transform => "INSTANCE_TRANSFORM",
+            InstanceSemantic::Color => "INSTANCE_COLOR",
+            InstanceSemantic::Emissive => "INSTANCE_EMISSIVE",
+            InstanceSemantic::MetallicRoughness => "INSTANCE_METALLIC_ROUGHNESS",
+            InstanceSemantic::Layer => "INSTANCE_LAYER",
+            InstanceSemantic::Animation => "INSTANCE_ANIMATION",
+            InstanceSemantic::VertexAnimation => "INSTANCE_VERTEX_ANIMATION",
+        }
+    }
+
+    /// Vertex formats the built-in instanced shaders expect for the semantic.
+    pub const fn formats(self) -> &'static [VertexFormat] {
+        match self {
+            InstanceSemantic::Transform => InstanceTransform::FORMATS,
+            InstanceSemantic::Color | InstanceSemantic::Emissive => Vec4::FORMATS,
+            InstanceSemantic::MetallicRoughness => Vec2::FORMATS,
+            InstanceSemantic::Layer => u32::FORMATS,
+            InstanceSemantic::Animation => InstanceAnimation::FORMATS,
+            InstanceSemantic::VertexAnimation => InstanceVertexAnimation::FORMATS,
+        }
+    }
+}
+
+/// Where a field of an instance is bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstanceBinding {
+    /// The location after the previous field.
+    Next,
+    Location(u32),
+    Semantic(InstanceSemantic),
+    /// Uploaded with the instance but not bound to any location.
+    Skip,
+}
+
+/// Builds an [`InstanceLayout`] field by field. Used by `#[derive(InstanceData)]`.
+pub struct InstanceLayoutBuilder {
+    offset: u64,
+    location: u32,
+    attributes: Vec<VertexAttribute>,
+    shader_defs: Vec<ShaderDefVal>,
+    semantics: Vec<(InstanceSemantic, u64)>,
+}
+
+impl InstanceLayoutBuilder {
+    pub const FIRST_LOCATION: u32 = 10;
+
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            location: Self::FIRST_LOCATION,
+            attributes: Vec::new(),
+            shader_defs: Vec::new(),
+            semantics: Vec::new(),
+        }
+    }
+
+    pub fn field<T: InstanceAttribute>(&mut self, binding: InstanceBinding) -> &mut Self {
+        let location = match binding {
+            InstanceBinding::Next => self.location,
+            InstanceBinding::Location(location) => location,
+            InstanceBinding::Semantic(semantic) => {
+                assert_eq!(
+                    T::FORMATS,
+                    semantic.formats(),
+                    "field bound to {semantic:?} has the wrong type"
+                );
+                self.shader_defs.push(semantic.shader_def().into());
+                self.semantics.push((semantic, self.offset));
+                semantic.location()
+            }
+            InstanceBinding::Skip => {
+                self.offset += std::mem::size_of::<T>() as u64;
+                return self;
+            }
+        };
+
+        assert_eq!(
+            T::FORMATS.iter().map(VertexFormat::size).sum::<u64>(),
+            std::mem::size_of::<T>() as u64,
+            "vertex formats of an instance attribute must cover the whole field"
+        );
+
+        for (index, format) in T::FORMATS.iter().enumerate() {
+            self.attributes.push(VertexAttribute {
+                format: *format,
+                offset: self.offset,
+                shader_location: location + index as u32,
+            });
+            self.offset += format.size();
+        }
+        self.location = location + T::FORMATS.len() as u32;
+
+        self
+    }
+
+    pub fn build<I: InstanceData>(&mut self) -> InstanceLayout {
+        let stride = std::mem::size_of::<I>() as u64;
+        assert_eq!(
+            self.offset, stride,
+            "instance fields must cover the whole instance"
+        );
+        for (index, attribute) in self.attributes.iter().enumerate() {
+            assert!(
+                self.attributes[..index]
+                    .iter()
+                    .all(|other| other.shader_location != attribute.shader_location),
+                "instance attribute location {} is bound more than once",
+                attribute.shader_location
+            );
+        }
+
+        InstanceLayout {
+            stride,
+            attributes: std::mem::take(&mut self.attributes),
+            shader_defs: std::mem::take(&mut self.shader_defs),
+            semantics: std::mem::take(&mut self.semantics),
+        }
+    }
+}
+
+impl Default for InstanceLayoutBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks the layout [`InstanceLayoutBuilder`] would build at compile time, so
+/// `#[derive(InstanceData)]` rejects invalid instances when they are declared rather than when
+/// their pipeline is first specialized.
+#[doc(hidden)]
+#[derive(Clone, Copy)]
+pub struct InstanceLayoutCheck {
+    offset: usize,
+    location: u32,
+    /// Bit `n` is set once location `n` is bound.
+    locations: u128,
+}
+
+impl InstanceLayoutCheck {
+    pub const fn new() -> Self {
+        Self {
+            offset: 0,
+            location: InstanceLayoutBuilder::FIRST_LOCATION,
+            locations: 0,
+        }
+    }
+
+    pub const fn field<T: InstanceAttribute>(mut self, binding: InstanceBinding) -> Self {
+        let location = match binding {
+            InstanceBinding::Next => self.location,
+            InstanceBinding::Location(location) => location,
+            InstanceBinding::Semantic(semantic) => {
+                assert!(
+                    same_formats(T::FORMATS, semantic.formats()),
+                    "field bound to an instance semantic has the wrong type"
+                );
+                semantic.location()
+            }
+            InstanceBinding::Skip => {
+                self.offset += std::mem::size_of::<T>();
+                return self;
+            }
+        };
+
+        let mut size = 0;
+        let mut index = 0;
+        while index < T::FORMATS.len() {
+            size += T::FORMATS[index].size();
+            let shader_location = location + index as u32;
+            assert!(
+                shader_location < u128::BITS,
+                "instance attribute location is out of range"
+            );
+            assert!(
+                self.locations & (1 << shader_location) == 0,
+                "instance attribute location is bound more than once"
+            );
+            self.locations |= 1 << shader_location;
+            index += 1;
+        }
+        assert!(
+            size == std::mem::size_of::<T>() as u64,
+            "vertex formats of an instance attribute must cover the whole field"
+        );
+        self.offset += std::mem::size_of::<T>();
+        self.location = location + T::FORMATS.len() as u32;
+
+        self
+    }
+
+    pub const fn build<I>(self) {
+        assert!(
+            self.offset == std::mem::size_of::<I>(),
+            "instance fields must cover the whole instance"
+        );
+    }
+}
+
+impl Default for InstanceLayoutCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const fn same_formats(a: &[VertexFormat], b: &[VertexFormat]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut index = 0;
+    while index < a.len() {
+        if a[index] as u32 != b[index] as u32 {
+            return false;
+        }
+        index += 1;
+    }
+    true
+}
+
+/// A field type that can be bound as one or more per-instance vertex attributes.
+pub trait InstanceAttribute: Pod {
+    /// Formats of the consecutive locations this type occupies.
+    const FORMATS: &'static [VertexFormat];
+}
+
+macro_rules! impl_instance_attribute {
+    ($($ty:ty => [$($format:ident),+]),+ $(,)?) => {
+        $(
+            impl InstanceAttribute for $ty {
+                const FORMATS: &'static [VertexFormat] = &[$(VertexFormat::$format),+];
+            }
+        )+
+    };
+}
+
+impl_instance_attribute!(
+    f32 => [Float32],
+    [f32; 2] => [Float32x2],
+    [f32; 3] => [Float32x3],
+    [f32; 4] => [Float32x4],
+    Vec2 => [Float32x2],
+    Vec3 => [Float32x3],
+    Vec4 => [Float32x4],
+    u32 => [Uint32],
+    [u32; 2] => [Uint32x2],
+    [u32; 3] => [Uint32x3],
+    [u32; 4] => [Uint32x4],
+    UVec2 => [Uint32x2],
+    UVec3 => [Uint32x3],
+    UVec4 => [Uint32x4],
+    i32 => [Sint32],
+    [i32; 2] => [Sint32x2],
+    [i32; 3] => [Sint32x3],
+    [i32; 4] => [Sint32x4],
+    IVec2 => [Sint32x2],
+    IVec3 => [Sint32x3],
+    IVec4 => [Sint32x4],
+    Mat4 => [Float32x4, Float32x4, Float32x4, Float32x4],
+    InstanceTransform => [Float32x4, Float32x4, Float32x4],
+    // Floats are read as the bits of an `f32`.
+    InstanceAnimation => [Uint32x2],
+    InstanceVertexAnimation => [Uint32x3],
+);
+
+/// Affine transform of a single instance, stored as the rows of its 3x4 matrix.
+///
+/// The instance transform is applied in mesh space, before the entity's own transform.
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct InstanceTransform {
+    pub rows: [Vec4; 3],
+}
+
+impl InstanceTransform {
+    pub const IDENTITY: Self = Self {
+        rows: [Vec4::X, Vec4::Y, Vec4::Z],
+    };
+
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self::from(Affine3A::from_translation(translation))
+    }
+}
+
+impl Default for InstanceTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl From<Affine3A> for InstanceTransform {
+    fn from(affine: Affine3A) -> Self {
+        let matrix = Mat4::from(affine).transpose();
+        Self {
+            rows: [matrix.x_axis, matrix.y_axis, matrix.z_axis],
+        }
+    }
+}
+
+impl From<Transform> for InstanceTransform {
+    fn from(transform: Transform) -> Self {
+        Self::from(transform.compute_affine())
+    }
+}
+
+impl From<InstanceTransform> for Affine3A {
+    fn from(transform: InstanceTransform) -> Self {
+        let [x, y, z] = transform.rows;
+        Affine3A::from_mat4(Mat4::from_cols(x, y, z, Vec4::W).transpose())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable, InstanceData)]
+#[repr(C)]
+pub struct Instance {
+    #[instance(transform)]
+    pub transform: InstanceTransform,
+}
+
+impl Instance {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Self {
+            transform: InstanceTransform::from_translation(translation),
+        }
+    }
+}
+
+impl From<Transform> for Instance {
+    fn from(transform: Transform) -> Self {
+        Self {
+            transform: transform.into(),
+        }
+    }
+}
+
+/// An [`Instance`] showing one layer of a texture array or one cell of a texture atlas.
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable, InstanceData)]
+#[repr(C)]
+pub struct LayeredInstance {
+    #[instance(transform)]
+    pub transform: InstanceTransform,
+    #[instance(layer)]
+    pub layer: u32,
+    #[instance(skip)]
+    _padding: [u32; 3],
+}
+
+impl LayeredInstance {
+    pub fn new(transform: impl Into<InstanceTransform>, layer: u32) -> Self {
+        Self {
+            transform: transform.into(),
+            layer,
+            _padding: [0; 3],
+        }
+    }
+}
+
+/// The clip of the entity's [`AnimationPoses`](crate::AnimationPoses) an instance plays, and
+/// how far into it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct InstanceAnimation {
+    pub clip: u32,
+    /// Position in the clip, where 0 is its start and 1 its end. Wraps around, so the clip loops.
+    pub phase: f32,
+}
+
+impl InstanceAnimation {
+    pub fn new(clip: u32, phase: f32) -> Self {
+        Self { clip, phase }
+    }
+}
+
+/// An [`Instance`] of a skinned mesh posed by its own [`InstanceAnimation`].
+///
+/// Poses are only read with
+/// [`InstanceStorage::StorageBuffer`](crate::InstanceStorage::StorageBuffer), so the
+/// [`InstancedMeshMaterialPipelinePlugin`](crate::InstancedMeshMaterialPipelinePlugin) drawing
+/// these instances needs it, and they keep their rest pose where it falls back to vertex buffers.
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable, InstanceData)]
+#[repr(C)]
+pub struct AnimatedInstance {
+    #[instance(transform)]
+    pub transform: InstanceTransform,
+    #[instance(animation)]
+    pub animation: InstanceAnimation,
+    #[instance(skip)]
+    _padding: [u32; 2],
+}
+
+impl AnimatedInstance {
+    pub fn new(transform: impl Into<InstanceTransform>, animation: InstanceAnimation) -> Self {
+        Self {
+            transform: transform.into(),
+            animation,
+            _padding: [0; 2],
+        }
+    }
+}
+
+/// The clip of the entity's [`VertexAnimationTexture`](crate::VertexAnimationTexture) an
+/// instance plays, and where and how fast it plays it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct InstanceVertexAnimation {
+    pub clip: u32,
+    /// Frame of the clip shown at time zero. May be fractional.
+    pub frame: f32,
+    /// Multiplies the frame rate of the clip. Negative speeds play it backwards.
+    pub speed: f32,
+}
+
+impl InstanceVertexAnimation {
+    pub fn new(clip: u32, frame: f32, speed: f32) -> Self {
+        Self { clip, frame, speed }
+    }
+}
+
+/// An [`Instance`] playing its own [`InstanceVertexAnimation`].
+///
+/// Vertex animations are only read with
+/// [`InstanceStorage::StorageBuffer`](crate::InstanceStorage::StorageBuffer), so the
+/// [`InstancedMeshMaterialPipelinePlugin`](crate::InstancedMeshMaterialPipelinePlugin) drawing
+/// these instances needs it, and they keep the rest pose of their mesh where it falls back to
+/// vertex buffers.
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable, InstanceData)]
+#[repr(C)]
+pub struct VertexAnimatedInstance {
+    #[instance(transform)]
+    pub transform: InstanceTransform,
+    #[instance(vertex_animation)]
+    pub vertex_animation: InstanceVertexAnimation,
+    #[instance(skip)]
+    _padding: u32,
+}
+
+impl VertexAnimatedInstance {
+    pub fn new(
+        transform: impl Into<InstanceTransform>,
+        vertex_animation: InstanceVertexAnimation,
+    ) -> Self {
+        Self {
+            transform: transform.into(),
+            vertex_animation,
+            _padding: 0,
+        }
+    }
+}
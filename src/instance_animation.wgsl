@@ -0,0 +1,54 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_animation
+
+#ifdef INSTANCE_STORAGE
+#ifdef INSTANCE_ANIMATION
+// Laid out by `AnimationPoses`: the joint and clip counts, the first frame and frame count of
+// each clip, then the rows of every joint matrix, frame after frame.
+@group(3) @binding(1)
+var<storage> animation_poses: array<vec4<f32>>;
+
+fn animation_header(index: u32) -> vec4<u32> {
+    return bitcast<vec4<u32>>(animation_poses[index]);
+}
+
+fn animation_joint(joint_count: u32, clip_count: u32, frame: u32, joint: u32) -> mat4x4<f32> {
+    let first = 1u + clip_count + (frame * joint_count + joint) * 3u;
+    return instance_transform(
+        animation_poses[first],
+        animation_poses[first + 1u],
+        animation_poses[first + 2u]
+    );
+}
+
+// Skinning matrix of a vertex in the pose of `clip` at `phase`, blending the two frames around
+// the phase. The clip loops, so the last frame blends into the first.
+fn animation_skin_model(
+    clip: u32,
+    phase: f32,
+    indexes: vec4<u32>,
+    weights: vec4<f32>,
+) -> mat4x4<f32> {
+    let counts = animation_header(0u);
+    let joint_count = counts.x;
+    let clip_count = counts.y;
+    if clip_count == 0u {
+        return instance_identity();
+    }
+
+    let frames = animation_header(1u + min(clip, clip_count - 1u));
+    let position = fract(phase) * f32(frames.y);
+    let frame = min(u32(position), frames.y - 1u);
+    let next_frame = (frame + 1u) % frames.y;
+    let blend = position - f32(frame);
+
+    var model = mat4x4<f32>(vec4<f32>(0.0), vec4<f32>(0.0), vec4<f32>(0.0), vec4<f32>(0.0));
+    for (var i = 0; i < 4; i += 1) {
+        let joint = min(indexes[i], joint_count - 1u);
+        let current = animation_joint(joint_count, clip_count, frames.x + frame, joint);
+        let next = animation_joint(joint_count, clip_count, frames.x + next_frame, joint);
+        model += weights[i] * ((1.0 - blend) * current + blend * next);
+    }
+    return model;
+}
+#endif
+#endif
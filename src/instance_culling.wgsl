@@ -0,0 +1,78 @@
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+
+struct InstanceCulling {
+    // Left, right, bottom, top and near planes of the view frustum, pointing inwards.
+    planes: array<vec4<f32>, 5>,
+    model: mat4x4<f32>,
+    // Bounding sphere of the mesh: center in xyz, radius in w.
+    bounds: vec4<f32>,
+    instance_count: u32,
+    // Size of one instance in words.
+    stride: u32,
+    // Word offset of the instance transform, or 0xffffffff if the instances have none.
+    transform_offset: u32,
+    // How many of the planes to test: 4 to ignore the near plane.
+    plane_count: u32,
+};
+
+// Arguments of both `draw_indexed_indirect` and `draw_indirect`. A non-indexed draw only reads
+// the first four words.
+struct DrawIndirect {
+    count: u32,
+    instance_count: atomic<u32>,
+    first: u32,
+    base_vertex: u32,
+    first_instance: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> culling: InstanceCulling;
+@group(0) @binding(1)
+var<storage> instances: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> culled_instances: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> draw_indirect: DrawIndirect;
+
+fn instance_row(index: u32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(vec4<u32>(
+        instances[index],
+        instances[index + 1u],
+        instances[index + 2u],
+        instances[index + 3u]
+    ));
+}
+
+@compute @workgroup_size(64)
+fn cull(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if index >= culling.instance_count {
+        return;
+    }
+    let first_word = index * culling.stride;
+
+    var instance = instance_identity();
+    if culling.transform_offset != 0xffffffffu {
+        let transform = first_word + culling.transform_offset;
+        instance = instance_transform(
+            instance_row(transform),
+            instance_row(transform + 4u),
+            instance_row(transform + 8u)
+        );
+    }
+
+    let model = culling.model * instance;
+    let center = model * vec4<f32>(culling.bounds.xyz, 1.0);
+    let scale = max(length(model[0].xyz), max(length(model[1].xyz), length(model[2].xyz)));
+    let radius = culling.bounds.w * scale;
+    for (var i = 0u; i < culling.plane_count; i = i + 1u) {
+        if dot(culling.planes[i], vec4<f32>(center.xyz, 1.0)) + radius <= 0.0 {
+            return;
+        }
+    }
+
+    let culled_first_word = atomicAdd(&draw_indirect.instance_count, 1u) * culling.stride;
+    for (var word = 0u; word < culling.stride; word = word + 1u) {
+        culled_instances[culled_first_word + word] = instances[first_word + word];
+    }
+}
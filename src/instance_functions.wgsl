@@ -0,0 +1,62 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_functions
+
+// Builds an instance transform uploaded as the rows of a 3x4 affine matrix.
+fn instance_transform(row_0: vec4<f32>, row_1: vec4<f32>, row_2: vec4<f32>) -> mat4x4<f32> {
+    return transpose(mat4x4<f32>(row_0, row_1, row_2, vec4<f32>(0.0, 0.0, 0.0, 1.0)));
+}
+
+fn instance_identity() -> mat4x4<f32> {
+    return mat4x4<f32>(
+        vec4<f32>(1.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 1.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 1.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 1.0)
+    );
+}
+
+// Transform of a skinned vertex of an instance. Joints place the skin in world space, so the
+// instance is applied after skinning, in the space of the entity given by `model` and its
+// `inverse_transpose_model`.
+fn instance_skin_model(
+    model: mat4x4<f32>,
+    inverse_transpose_model: mat4x4<f32>,
+    instance: mat4x4<f32>,
+    skin: mat4x4<f32>
+) -> mat4x4<f32> {
+    return model * instance * transpose(inverse_transpose_model) * skin;
+}
+
+// Cofactor matrix of `m`, which equals its inverse transpose scaled by the determinant.
+fn cofactor_3x3(m: mat3x3<f32>) -> mat3x3<f32> {
+    return mat3x3<f32>(
+        cross(m[1], m[2]),
+        cross(m[2], m[0]),
+        cross(m[0], m[1])
+    );
+}
+
+// Transforms a mesh space normal by the instance transform. The result is not normalized.
+// NOTE: Multiplying by the sign of the determinant keeps normals pointing outwards
+// for mirrored instances.
+fn instance_normal_local_to_mesh(instance: mat4x4<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let instance_3x3 = mat3x3<f32>(instance[0].xyz, instance[1].xyz, instance[2].xyz);
+    return cofactor_3x3(instance_3x3) * normal * sign(determinant(instance_3x3));
+}
+
+fn instance_sign_determinant(instance: mat4x4<f32>) -> f32 {
+    return sign(determinant(mat3x3<f32>(instance[0].xyz, instance[1].xyz, instance[2].xyz)));
+}
+
+// Whether the fragment at `frag_coord` of an instance cross-fading between levels of detail is
+// dithered away. A fade `f >= 0` keeps the fragments whose threshold is below `f`, and `f < 0`
+// those whose threshold is at least `1 + f`, so the two levels of an instance cover
+// complementary fragments.
+fn instance_lod_dithered(frag_coord: vec2<f32>, fade: f32) -> bool {
+    var bayer = array<u32, 16>(0u, 8u, 2u, 10u, 12u, 4u, 14u, 6u, 3u, 11u, 1u, 9u, 15u, 7u, 13u, 5u);
+    let pixel = vec2<u32>(frag_coord) % 4u;
+    let threshold = (f32(bayer[pixel.y * 4u + pixel.x]) + 0.5) / 16.0;
+    if fade >= 0.0 {
+        return threshold >= fade;
+    }
+    return threshold < 1.0 + fade;
+}
@@ -0,0 +1,69 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_impostor
+
+#ifdef INSTANCE_IMPOSTOR
+// The part of `InstancedImpostorMaterial` the vertex stage reads.
+struct ImpostorMaterial {
+    color: vec4<f32>,
+    // Center of the baked views in the space of the mesh.
+    center: vec3<f32>,
+    alpha_cutoff: f32,
+};
+
+@group(1) @binding(0)
+var<uniform> impostor_material: ImpostorMaterial;
+
+struct ImpostorVertex {
+    world_position: vec4<f32>,
+    world_normal: vec3<f32>,
+    // Layer of the impostor texture baked closest to the direction the instance is seen from.
+    layer: u32,
+};
+
+// Cell of the octahedral grid of views whose direction is closest to `direction`, in the space
+// of the mesh. Inverse of `octahedral_direction` in `impostor.rs`.
+fn impostor_cell(direction: vec3<f32>) -> vec2<u32> {
+    let d = direction / (abs(direction.x) + abs(direction.y) + abs(direction.z));
+    var p = d.xz;
+    // The lower half of the sphere is folded over the corners of the grid.
+    if d.y < 0.0 {
+        p = (1.0 - abs(d.zx)) * select(vec2<f32>(-1.0), vec2<f32>(1.0), d.xz >= vec2<f32>(0.0));
+    }
+    let frames = f32(#{IMPOSTOR_FRAMES}u);
+    let cell = clamp(floor((p * 0.5 + 0.5) * frames), vec2<f32>(0.0), vec2<f32>(frames - 1.0));
+    return vec2<u32>(cell);
+}
+
+// Turns a vertex of an impostor quad around the center of the views to face the camera, the way
+// the cameras baking the views faced the mesh.
+fn impostor_vertex(model: mat4x4<f32>, position: vec3<f32>) -> ImpostorVertex {
+    let center = impostor_material.center;
+    let world_center = (model * vec4<f32>(center, 1.0)).xyz;
+    // Maps world directions to the space of the mesh, and mesh normals to the world, for models
+    // without shear.
+    let axes = mat3x3<f32>(
+        model[0].xyz / dot(model[0].xyz, model[0].xyz),
+        model[1].xyz / dot(model[1].xyz, model[1].xyz),
+        model[2].xyz / dot(model[2].xyz, model[2].xyz),
+    );
+    let to_camera = (view.world_position.xyz - world_center) * axes;
+    var direction = vec3<f32>(0.0, 0.0, 1.0);
+    if length(to_camera) > 0.0001 {
+        direction = normalize(to_camera);
+    }
+
+    // Matches `Transform::looking_at` for the cameras baking the views.
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(direction.y) > 0.999 {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let right = normalize(cross(up, direction));
+    up = cross(direction, right);
+
+    var out: ImpostorVertex;
+    out.world_position = model * vec4<f32>(center + right * position.x + up * position.y, 1.0);
+    out.world_normal = normalize(axes * direction);
+    let cell = impostor_cell(direction);
+    out.layer = cell.y * #{IMPOSTOR_FRAMES}u + cell.x;
+    return out;
+}
+#endif
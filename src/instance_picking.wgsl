@@ -0,0 +1,17 @@
+struct InstancePicking {
+    // Low and high bits of the entity drawn.
+    entity: vec2<u32>,
+};
+
+@group(1) @binding(0)
+var<uniform> picking: InstancePicking;
+
+struct FragmentInput {
+    @location(3) @interpolate(flat) instance_index: u32,
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<u32> {
+    // The last channel tells instances from the cleared background.
+    return vec4<u32>(picking.entity, in.instance_index, 1u);
+}
@@ -0,0 +1,116 @@
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+
+struct InstanceSorting {
+    // Row of the inverse view matrix giving the view space depth of a world space position.
+    view_depth: vec4<f32>,
+    // Camera position in xyz, and the width of the distance buckets instances are sorted front to
+    // back in w, or 0 to sort them back to front by depth.
+    view_position: vec4<f32>,
+    model: mat4x4<f32>,
+    instance_count: u32,
+    // Number of sort keys, the instance count rounded up to a power of two.
+    key_count: u32,
+    // Size of one instance in words.
+    stride: u32,
+    // Word offset of the instance transform, or 0xffffffff if the instances have none.
+    transform_offset: u32,
+};
+
+// One step of the bitonic sort: keys `j` apart are compared within sequences of `k` keys.
+struct SortStep {
+    j: u32,
+    k: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> sorting: InstanceSorting;
+@group(0) @binding(1)
+var<storage> instances: array<u32>;
+// Depth key and index of each instance.
+@group(0) @binding(2)
+var<storage, read_write> keys: array<vec2<u32>>;
+@group(0) @binding(3)
+var<storage, read_write> sorted_instances: array<u32>;
+@group(1) @binding(0)
+var<uniform> step: SortStep;
+
+fn instance_row(index: u32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(vec4<u32>(
+        instances[index],
+        instances[index + 1u],
+        instances[index + 2u],
+        instances[index + 3u]
+    ));
+}
+
+// Orders floats like their bits as unsigned integers.
+fn sortable_bits(value: f32) -> u32 {
+    let bits = bitcast<u32>(value);
+    if (bits & 0x80000000u) != 0u {
+        return ~bits;
+    }
+    return bits | 0x80000000u;
+}
+
+@compute @workgroup_size(64)
+fn write_keys(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if index >= sorting.key_count {
+        return;
+    }
+    // Padding sorts after every instance.
+    if index >= sorting.instance_count {
+        keys[index] = vec2<u32>(0xffffffffu, index);
+        return;
+    }
+
+    var instance = instance_identity();
+    if sorting.transform_offset != 0xffffffffu {
+        let transform = index * sorting.stride + sorting.transform_offset;
+        instance = instance_transform(
+            instance_row(transform),
+            instance_row(transform + 4u),
+            instance_row(transform + 8u)
+        );
+    }
+    let position = sorting.model * instance * vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    if sorting.view_position.w > 0.0 {
+        let distance = length(position.xyz - sorting.view_position.xyz);
+        keys[index] = vec2<u32>(u32(distance / sorting.view_position.w), index);
+    } else {
+        // Views look down -z, so the farthest instances have the smallest depth and come first.
+        keys[index] = vec2<u32>(sortable_bits(dot(sorting.view_depth, position)), index);
+    }
+}
+
+@compute @workgroup_size(64)
+fn sort_keys(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let other = index ^ step.j;
+    if index >= sorting.key_count || other <= index {
+        return;
+    }
+
+    let key = keys[index];
+    let other_key = keys[other];
+    let greater = key.x > other_key.x || (key.x == other_key.x && key.y > other_key.y);
+    let ascending = (index & step.k) == 0u;
+    if greater == ascending {
+        keys[index] = other_key;
+        keys[other] = key;
+    }
+}
+
+@compute @workgroup_size(64)
+fn write_sorted_instances(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if index >= sorting.instance_count {
+        return;
+    }
+
+    let first_word = keys[index].y * sorting.stride;
+    let sorted_first_word = index * sorting.stride;
+    for (var word = 0u; word < sorting.stride; word = word + 1u) {
+        sorted_instances[sorted_first_word + word] = instances[first_word + word];
+    }
+}
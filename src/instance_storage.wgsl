@@ -0,0 +1,88 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_storage
+
+#ifdef INSTANCE_STORAGE
+@group(3) @binding(0)
+var<storage> instances: array<u32>;
+
+// How far each instance is faded into the level of detail it is drawn with.
+@group(3) @binding(5)
+var<storage> instance_lod_fades: array<f32>;
+
+// Index of the first word of a field of the instance at `instance_index`, given the word offset
+// of the field within the instance.
+fn instance_word(instance_index: u32, offset: u32) -> u32 {
+    return instance_index * #{INSTANCE_STRIDE}u + offset;
+}
+
+fn instance_load_vec4(word: u32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(vec4<u32>(
+        instances[word],
+        instances[word + 1u],
+        instances[word + 2u],
+        instances[word + 3u]
+    ));
+}
+
+fn instance_load_vec2(word: u32) -> vec2<f32> {
+    return bitcast<vec2<f32>>(vec2<u32>(instances[word], instances[word + 1u]));
+}
+
+#ifdef INSTANCE_TRANSFORM
+fn instance_storage_transform(instance_index: u32) -> mat4x4<f32> {
+    let word = instance_word(instance_index, #{INSTANCE_TRANSFORM_OFFSET}u);
+    return instance_transform(
+        instance_load_vec4(word),
+        instance_load_vec4(word + 4u),
+        instance_load_vec4(word + 8u)
+    );
+}
+#endif
+
+#ifdef INSTANCE_COLOR
+fn instance_storage_color(instance_index: u32) -> vec4<f32> {
+    return instance_load_vec4(instance_word(instance_index, #{INSTANCE_COLOR_OFFSET}u));
+}
+#endif
+
+#ifdef INSTANCE_EMISSIVE
+fn instance_storage_emissive(instance_index: u32) -> vec4<f32> {
+    return instance_load_vec4(instance_word(instance_index, #{INSTANCE_EMISSIVE_OFFSET}u));
+}
+#endif
+
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+fn instance_storage_metallic_roughness(instance_index: u32) -> vec2<f32> {
+    return instance_load_vec2(instance_word(instance_index, #{INSTANCE_METALLIC_ROUGHNESS_OFFSET}u));
+}
+#endif
+
+#ifdef INSTANCE_ANIMATION
+// The clip in x and the bits of the phase in y.
+fn instance_storage_animation(instance_index: u32) -> vec2<u32> {
+    let word = instance_word(instance_index, #{INSTANCE_ANIMATION_OFFSET}u);
+    return vec2<u32>(instances[word], instances[word + 1u]);
+}
+#endif
+
+#ifdef INSTANCE_VERTEX_ANIMATION
+// The clip in x and the bits of the frame and speed in y and z.
+fn instance_storage_vertex_animation(instance_index: u32) -> vec3<u32> {
+    let word = instance_word(instance_index, #{INSTANCE_VERTEX_ANIMATION_OFFSET}u);
+    return vec3<u32>(instances[word], instances[word + 1u], instances[word + 2u]);
+}
+#endif
+
+// Instances without a fade, such as those of entities without levels of detail, are not faded.
+fn instance_storage_lod_fade(instance_index: u32) -> f32 {
+    if instance_index >= arrayLength(&instance_lod_fades) {
+        return 1.0;
+    }
+    return instance_lod_fades[instance_index];
+}
+
+#ifdef INSTANCE_LAYER
+fn instance_storage_layer(instance_index: u32) -> u32 {
+    return instances[instance_word(instance_index, #{INSTANCE_LAYER_OFFSET}u)];
+}
+#endif
+#endif
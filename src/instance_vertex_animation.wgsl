@@ -0,0 +1,92 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_vertex_animation
+
+#ifdef INSTANCE_STORAGE
+#ifdef INSTANCE_VERTEX_ANIMATION
+// Laid out by `VertexAnimationTexture`: an unused word, the vertex count, whether there are normals and the clip
+// count, the bounds of the position offsets, then the first frame, frame count, frame rate and
+// time of each clip.
+@group(3) @binding(2)
+var<storage> vertex_animation: array<vec4<f32>>;
+@group(3) @binding(3)
+var vertex_animation_positions: texture_2d<f32>;
+@group(3) @binding(4)
+var vertex_animation_normals: texture_2d<f32>;
+
+// The two frames of the whole animation around the playback position of an instance, and how
+// far it is from the first to the second.
+struct VertexAnimationFrames {
+    frame: u32,
+    next_frame: u32,
+    blend: f32,
+    // Whether the entity has a vertex animation with the instance's clip.
+    playing: bool,
+};
+
+// Plays the clip in x from the frame in the bits of y at the speed in the bits of z.
+fn vertex_animation_frames(instance: vec3<u32>) -> VertexAnimationFrames {
+    var frames: VertexAnimationFrames;
+    let header = bitcast<vec4<u32>>(vertex_animation[0]);
+    let clip_count = header.w;
+    if header.y == 0u || clip_count == 0u {
+        frames.playing = false;
+        return frames;
+    }
+
+    let clip = bitcast<vec4<u32>>(vertex_animation[3u + min(instance.x, clip_count - 1u)]);
+    let frame_count = max(clip.y, 1u);
+    let frames_per_second = bitcast<f32>(clip.z);
+    let time = bitcast<f32>(clip.w);
+    let position = bitcast<f32>(instance.y) + bitcast<f32>(instance.z) * frames_per_second * time;
+    // Wraps negative positions around too, for clips played backwards.
+    let looped = position - floor(position / f32(frame_count)) * f32(frame_count);
+    let frame = min(u32(looped), frame_count - 1u);
+
+    frames.frame = clip.x + frame;
+    frames.next_frame = clip.x + (frame + 1u) % frame_count;
+    frames.blend = looped - f32(frame);
+    frames.playing = true;
+    return frames;
+}
+
+fn vertex_animation_texel(texture: texture_2d<f32>, frame: u32, vertex_index: u32) -> vec4<f32> {
+    let vertex_count = bitcast<vec4<u32>>(vertex_animation[0]).y;
+    let texel = frame * vertex_count + vertex_index;
+    let width = u32(textureDimensions(texture).x);
+    return textureLoad(texture, vec2<i32>(i32(texel % width), i32(texel / width)), 0);
+}
+
+// Mesh space position of a vertex at the playback position.
+fn vertex_animation_position(
+    frames: VertexAnimationFrames,
+    vertex_index: u32,
+    position: vec3<f32>,
+) -> vec3<f32> {
+    if !frames.playing {
+        return position;
+    }
+    let offset = mix(
+        vertex_animation_texel(vertex_animation_positions, frames.frame, vertex_index).xyz,
+        vertex_animation_texel(vertex_animation_positions, frames.next_frame, vertex_index).xyz,
+        frames.blend
+    );
+    return position + mix(vertex_animation[1].xyz, vertex_animation[2].xyz, offset);
+}
+
+// Mesh space normal of a vertex at the playback position, or `normal` without baked normals.
+fn vertex_animation_normal(
+    frames: VertexAnimationFrames,
+    vertex_index: u32,
+    normal: vec3<f32>,
+) -> vec3<f32> {
+    if !frames.playing || bitcast<vec4<u32>>(vertex_animation[0]).z == 0u {
+        return normal;
+    }
+    let encoded = mix(
+        vertex_animation_texel(vertex_animation_normals, frames.frame, vertex_index).xyz,
+        vertex_animation_texel(vertex_animation_normals, frames.next_frame, vertex_index).xyz,
+        frames.blend
+    );
+    return normalize(encoded * 2.0 - 1.0);
+}
+#endif
+#endif
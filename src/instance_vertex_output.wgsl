@@ -0,0 +1,21 @@
+#define_import_path bevy_instanced_mesh_material_pipeline::instance_vertex_output
+
+// Per-instance inputs forwarded to the fragment stage, after bevy's `mesh_vertex_output`.
+#ifdef INSTANCE_COLOR
+@location(5) instance_color: vec4<f32>,
+#endif
+#ifdef INSTANCE_EMISSIVE
+@location(6) instance_emissive: vec4<f32>,
+#endif
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+@location(7) instance_metallic_roughness: vec2<f32>,
+#endif
+#ifdef INSTANCE_LAYER
+@location(8) @interpolate(flat) instance_layer: u32,
+#endif
+#ifdef INSTANCE_STORAGE
+@location(9) @interpolate(flat) instance_lod_fade: f32,
+#endif
+#ifdef INSTANCE_IMPOSTOR
+@location(10) @interpolate(flat) impostor_layer: u32,
+#endif
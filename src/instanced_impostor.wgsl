@@ -0,0 +1,53 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_core_pipeline::tonemapping
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+
+// Unlit fragment shader of `InstancedImpostorMaterial`.
+struct ImpostorMaterial {
+    color: vec4<f32>,
+    center: vec3<f32>,
+    // Alpha below which fragments are discarded.
+    alpha_cutoff: f32,
+};
+
+@group(1) @binding(0)
+var<uniform> material: ImpostorMaterial;
+@group(1) @binding(1)
+var impostor_texture: texture_2d_array<f32>;
+@group(1) @binding(2)
+var impostor_sampler: sampler;
+
+struct FragmentInput {
+    @builtin(front_facing) is_front: bool,
+    @builtin(position) frag_coord: vec4<f32>,
+    #import bevy_pbr::mesh_vertex_output
+    #import bevy_instanced_mesh_material_pipeline::instance_vertex_output
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    var output_color = material.color;
+#ifdef VERTEX_UVS
+    output_color = output_color * textureSample(impostor_texture, impostor_sampler, in.uv, i32(in.impostor_layer));
+#endif
+#ifdef INSTANCE_COLOR
+    output_color = output_color * in.instance_color;
+#endif
+
+    if output_color.a < material.alpha_cutoff {
+        discard;
+    }
+#ifdef INSTANCE_STORAGE
+    if instance_lod_dithered(in.frag_coord.xy, in.instance_lod_fade) {
+        discard;
+    }
+#endif
+
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color);
+#endif
+#ifdef PREMULTIPLY_ALPHA
+    output_color = vec4<f32>(output_color.rgb * output_color.a, output_color.a);
+#endif
+    return output_color;
+}
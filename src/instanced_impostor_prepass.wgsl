@@ -0,0 +1,43 @@
+// Alpha tested prepass and shadow fragment shader of `InstancedImpostorMaterial`.
+struct ImpostorMaterial {
+    color: vec4<f32>,
+    center: vec3<f32>,
+    // Alpha below which fragments are discarded.
+    alpha_cutoff: f32,
+};
+
+@group(1) @binding(0)
+var<uniform> material: ImpostorMaterial;
+@group(1) @binding(1)
+var impostor_texture: texture_2d_array<f32>;
+@group(1) @binding(2)
+var impostor_sampler: sampler;
+
+struct FragmentInput {
+    @location(0) uv: vec2<f32>,
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#endif // NORMAL_PREPASS
+    @location(4) @interpolate(flat) impostor_layer: u32,
+};
+
+fn impostor_alpha(in: FragmentInput) -> f32 {
+    return material.color.a * textureSample(impostor_texture, impostor_sampler, in.uv, i32(in.impostor_layer)).a;
+}
+
+#ifdef NORMAL_PREPASS
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    if impostor_alpha(in) < material.alpha_cutoff {
+        discard;
+    }
+    return vec4(in.world_normal * 0.5 + vec3(0.5), 1.0);
+}
+#else // NORMAL_PREPASS
+@fragment
+fn fragment(in: FragmentInput) {
+    if impostor_alpha(in) < material.alpha_cutoff {
+        discard;
+    }
+}
+#endif // NORMAL_PREPASS
@@ -3,6 +3,11 @@
 
 // NOTE: Bindings must come before functions that use them!
 #import bevy_pbr::mesh_functions
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+#import bevy_instanced_mesh_material_pipeline::instance_storage
+#import bevy_instanced_mesh_material_pipeline::instance_animation
+#import bevy_instanced_mesh_material_pipeline::instance_vertex_animation
+#import bevy_instanced_mesh_material_pipeline::instance_impostor
 
 struct Vertex {
 #ifdef VERTEX_POSITIONS
@@ -24,52 +29,175 @@ struct Vertex {
     @location(5) joint_indices: vec4<u32>,
     @location(6) joint_weights: vec4<f32>,
 #endif
-    @location(10) instance_position: vec3<f32>,
+#ifdef INSTANCE_STORAGE
+    @builtin(instance_index) instance_index: u32,
+    @builtin(vertex_index) vertex_index: u32,
+#else
+#ifdef INSTANCE_LAYER
+    @location(9) instance_layer: u32,
+#endif
+#ifdef INSTANCE_TRANSFORM
+    @location(10) instance_transform_0: vec4<f32>,
+    @location(11) instance_transform_1: vec4<f32>,
+    @location(12) instance_transform_2: vec4<f32>,
+#endif
+#ifdef INSTANCE_COLOR
+    @location(13) instance_color: vec4<f32>,
+#endif
+#ifdef INSTANCE_EMISSIVE
+    @location(14) instance_emissive: vec4<f32>,
+#endif
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+    @location(15) instance_metallic_roughness: vec2<f32>,
+#endif
+#endif
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     #import bevy_pbr::mesh_vertex_output
+    #import bevy_instanced_mesh_material_pipeline::instance_vertex_output
 };
 
 @vertex
-fn vertex(vertex: Vertex) -> VertexOutput {
+fn vertex(vertex_input: Vertex) -> VertexOutput {
     var out: VertexOutput;
-    var position = vertex.instance_position;
+    // A copy, so vertex animations can replace the position and normal.
+    var vertex = vertex_input;
+
+#ifdef INSTANCE_VERTEX_ANIMATION
+#ifdef INSTANCE_STORAGE
+    let vertex_animation = vertex_animation_frames(
+        instance_storage_vertex_animation(vertex.instance_index)
+    );
+#ifdef VERTEX_POSITIONS
+    vertex.position = vertex_animation_position(vertex_animation, vertex.vertex_index, vertex.position);
+#endif
+#ifdef VERTEX_NORMALS
+    vertex.normal = vertex_animation_normal(vertex_animation, vertex.vertex_index, vertex.normal);
+#endif
+#endif
+#endif
+
+#ifdef INSTANCE_TRANSFORM
+#ifdef INSTANCE_STORAGE
+    let instance = instance_storage_transform(vertex.instance_index);
+#else
+    let instance = instance_transform(
+        vertex.instance_transform_0,
+        vertex.instance_transform_1,
+        vertex.instance_transform_2
+    );
+#endif
+#else
+    let instance = instance_identity();
+#endif
 
 #ifdef SKINNED
-    var model = skin_model(vertex.joint_indices, vertex.joint_weights);
+#ifdef INSTANCE_ANIMATION
+#ifdef INSTANCE_STORAGE
+    let animation = instance_storage_animation(vertex.instance_index);
+    var model = mesh.model * instance * animation_skin_model(
+        animation.x,
+        bitcast<f32>(animation.y),
+        vertex.joint_indices,
+        vertex.joint_weights
+    );
 #else
-    var model = mesh.model;
+    var model = instance_skin_model(
+        mesh.model,
+        mesh.inverse_transpose_model,
+        instance,
+        skin_model(vertex.joint_indices, vertex.joint_weights)
+    );
+#endif
+#else
+    var model = instance_skin_model(
+        mesh.model,
+        mesh.inverse_transpose_model,
+        instance,
+        skin_model(vertex.joint_indices, vertex.joint_weights)
+    );
+#endif
+#else
+    var model = mesh.model * instance;
 #endif
 
 #ifdef VERTEX_NORMALS
 #ifdef SKINNED
     out.world_normal = skin_normals(model, vertex.normal);
 #else
-    out.world_normal = mesh_normal_local_to_world(vertex.normal);
+    out.world_normal = mesh_normal_local_to_world(
+        instance_normal_local_to_mesh(instance, vertex.normal)
+    );
 #endif
 #endif
 
 #ifdef VERTEX_POSITIONS
-    position += vertex.position;
-#endif
-
-    out.world_position = mesh_position_local_to_world(model, vec4<f32>(position, 1.0));
+    out.world_position = mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0));
     out.clip_position = mesh_position_world_to_clip(out.world_position);
+#endif
 
 #ifdef VERTEX_UVS
     out.uv = vertex.uv;
 #endif
 
+#ifdef INSTANCE_IMPOSTOR
+    // Impostor quads face the camera instead of following the model.
+    let impostor = impostor_vertex(model, vertex.position);
+    out.world_position = impostor.world_position;
+    out.clip_position = mesh_position_world_to_clip(out.world_position);
+#ifdef VERTEX_NORMALS
+    out.world_normal = impostor.world_normal;
+#endif
+    out.impostor_layer = impostor.layer;
+#endif
+
 #ifdef VERTEX_TANGENTS
     out.world_tangent = mesh_tangent_local_to_world(model, vertex.tangent);
+    out.world_tangent.w = out.world_tangent.w * instance_sign_determinant(instance);
 #endif
 
 #ifdef VERTEX_COLORS
     out.color = vertex.color;
 #endif
 
+#ifdef INSTANCE_COLOR
+#ifdef INSTANCE_STORAGE
+    out.instance_color = instance_storage_color(vertex.instance_index);
+#else
+    out.instance_color = vertex.instance_color;
+#endif
+#endif
+
+#ifdef INSTANCE_EMISSIVE
+#ifdef INSTANCE_STORAGE
+    out.instance_emissive = instance_storage_emissive(vertex.instance_index);
+#else
+    out.instance_emissive = vertex.instance_emissive;
+#endif
+#endif
+
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+#ifdef INSTANCE_STORAGE
+    out.instance_metallic_roughness = instance_storage_metallic_roughness(vertex.instance_index);
+#else
+    out.instance_metallic_roughness = vertex.instance_metallic_roughness;
+#endif
+#endif
+
+#ifdef INSTANCE_LAYER
+#ifdef INSTANCE_STORAGE
+    out.instance_layer = instance_storage_layer(vertex.instance_index);
+#else
+    out.instance_layer = vertex.instance_layer;
+#endif
+#endif
+
+#ifdef INSTANCE_STORAGE
+    out.instance_lod_fade = instance_storage_lod_fade(vertex.instance_index);
+#endif
+
     return out;
 }
 
@@ -0,0 +1,159 @@
+#import bevy_sprite::mesh2d_view_bindings
+#import bevy_sprite::mesh2d_bindings
+
+// NOTE: Bindings must come before functions that use them!
+#import bevy_sprite::mesh2d_functions
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+#import bevy_instanced_mesh_material_pipeline::instance_storage
+
+#ifdef TONEMAP_IN_SHADER
+#import bevy_core_pipeline::tonemapping
+#endif
+
+struct Vertex {
+#ifdef VERTEX_POSITIONS
+    @location(0) position: vec3<f32>,
+#endif
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
+#ifdef INSTANCE_STORAGE
+    @builtin(instance_index) instance_index: u32,
+#else
+#ifdef INSTANCE_LAYER
+    @location(9) instance_layer: u32,
+#endif
+#ifdef INSTANCE_TRANSFORM
+    @location(10) instance_transform_0: vec4<f32>,
+    @location(11) instance_transform_1: vec4<f32>,
+    @location(12) instance_transform_2: vec4<f32>,
+#endif
+#ifdef INSTANCE_COLOR
+    @location(13) instance_color: vec4<f32>,
+#endif
+#ifdef INSTANCE_EMISSIVE
+    @location(14) instance_emissive: vec4<f32>,
+#endif
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+    @location(15) instance_metallic_roughness: vec2<f32>,
+#endif
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    #import bevy_sprite::mesh2d_vertex_output
+    #import bevy_instanced_mesh_material_pipeline::instance_vertex_output
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+#ifdef INSTANCE_TRANSFORM
+#ifdef INSTANCE_STORAGE
+    let instance = instance_storage_transform(vertex.instance_index);
+#else
+    let instance = instance_transform(
+        vertex.instance_transform_0,
+        vertex.instance_transform_1,
+        vertex.instance_transform_2
+    );
+#endif
+#else
+    let instance = instance_identity();
+#endif
+    let model = mesh.model * instance;
+
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif
+
+#ifdef VERTEX_POSITIONS
+    out.world_position = mesh2d_position_local_to_world(model, vec4<f32>(vertex.position, 1.0));
+    out.clip_position = mesh2d_position_world_to_clip(out.world_position);
+#endif
+
+#ifdef VERTEX_NORMALS
+    out.world_normal = normalize(mesh2d_normal_local_to_world(
+        instance_normal_local_to_mesh(instance, vertex.normal)
+    ));
+#endif
+
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh2d_tangent_local_to_world(model, vertex.tangent);
+    out.world_tangent.w = out.world_tangent.w * instance_sign_determinant(instance);
+#endif
+
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+
+#ifdef INSTANCE_COLOR
+#ifdef INSTANCE_STORAGE
+    out.instance_color = instance_storage_color(vertex.instance_index);
+#else
+    out.instance_color = vertex.instance_color;
+#endif
+#endif
+
+#ifdef INSTANCE_EMISSIVE
+#ifdef INSTANCE_STORAGE
+    out.instance_emissive = instance_storage_emissive(vertex.instance_index);
+#else
+    out.instance_emissive = vertex.instance_emissive;
+#endif
+#endif
+
+#ifdef INSTANCE_METALLIC_ROUGHNESS
+#ifdef INSTANCE_STORAGE
+    out.instance_metallic_roughness = instance_storage_metallic_roughness(vertex.instance_index);
+#else
+    out.instance_metallic_roughness = vertex.instance_metallic_roughness;
+#endif
+#endif
+
+#ifdef INSTANCE_LAYER
+#ifdef INSTANCE_STORAGE
+    out.instance_layer = instance_storage_layer(vertex.instance_index);
+#else
+    out.instance_layer = vertex.instance_layer;
+#endif
+#endif
+
+#ifdef INSTANCE_STORAGE
+    out.instance_lod_fade = instance_storage_lod_fade(vertex.instance_index);
+#endif
+
+    return out;
+}
+
+struct FragmentInput {
+    #import bevy_sprite::mesh2d_vertex_output
+    #import bevy_instanced_mesh_material_pipeline::instance_vertex_output
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+#ifdef VERTEX_COLORS
+    var color = in.color;
+#else
+    var color = vec4<f32>(1.0, 0.0, 1.0, 1.0);
+#endif
+#ifdef INSTANCE_COLOR
+    color = color * in.instance_color;
+#endif
+#ifdef TONEMAP_IN_SHADER
+    color = tone_mapping(color);
+#endif
+    return color;
+}
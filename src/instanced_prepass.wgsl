@@ -0,0 +1,171 @@
+#import bevy_pbr::prepass_bindings
+#import bevy_pbr::mesh_functions
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+#import bevy_instanced_mesh_material_pipeline::instance_storage
+#import bevy_instanced_mesh_material_pipeline::instance_animation
+#import bevy_instanced_mesh_material_pipeline::instance_vertex_animation
+#import bevy_instanced_mesh_material_pipeline::instance_impostor
+
+// Mirrors bevy's prepass vertex shader, with the instance transform applied in mesh space.
+struct Vertex {
+    @location(0) position: vec3<f32>,
+
+#ifdef VERTEX_UVS
+    @location(1) uv: vec2<f32>,
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+    @location(2) normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+
+#ifdef SKINNED
+    @location(4) joint_indices: vec4<u32>,
+    @location(5) joint_weights: vec4<f32>,
+#endif // SKINNED
+
+#ifdef INSTANCE_STORAGE
+    @builtin(instance_index) instance_index: u32,
+    @builtin(vertex_index) vertex_index: u32,
+#else // INSTANCE_STORAGE
+#ifdef INSTANCE_PICKING
+    @builtin(instance_index) instance_index: u32,
+#endif // INSTANCE_PICKING
+#ifdef INSTANCE_TRANSFORM
+    @location(10) instance_transform_0: vec4<f32>,
+    @location(11) instance_transform_1: vec4<f32>,
+    @location(12) instance_transform_2: vec4<f32>,
+#endif // INSTANCE_TRANSFORM
+#endif // INSTANCE_STORAGE
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(2) world_tangent: vec4<f32>,
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+
+#ifdef INSTANCE_PICKING
+    @location(3) @interpolate(flat) instance_index: u32,
+#endif // INSTANCE_PICKING
+
+#ifdef INSTANCE_IMPOSTOR
+    @location(4) @interpolate(flat) impostor_layer: u32,
+#endif // INSTANCE_IMPOSTOR
+}
+
+@vertex
+fn vertex(vertex_input: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    // A copy, so vertex animations can replace the position and normal.
+    var vertex = vertex_input;
+
+#ifdef INSTANCE_VERTEX_ANIMATION
+#ifdef INSTANCE_STORAGE
+    let vertex_animation = vertex_animation_frames(
+        instance_storage_vertex_animation(vertex.instance_index)
+    );
+    vertex.position = vertex_animation_position(vertex_animation, vertex.vertex_index, vertex.position);
+#ifdef NORMAL_PREPASS
+    vertex.normal = vertex_animation_normal(vertex_animation, vertex.vertex_index, vertex.normal);
+#endif // NORMAL_PREPASS
+#endif // INSTANCE_STORAGE
+#endif // INSTANCE_VERTEX_ANIMATION
+
+#ifdef INSTANCE_TRANSFORM
+#ifdef INSTANCE_STORAGE
+    let instance = instance_storage_transform(vertex.instance_index);
+#else // INSTANCE_STORAGE
+    let instance = instance_transform(
+        vertex.instance_transform_0,
+        vertex.instance_transform_1,
+        vertex.instance_transform_2
+    );
+#endif // INSTANCE_STORAGE
+#else // INSTANCE_TRANSFORM
+    let instance = instance_identity();
+#endif // INSTANCE_TRANSFORM
+
+#ifdef SKINNED
+#ifdef INSTANCE_ANIMATION
+#ifdef INSTANCE_STORAGE
+    let animation = instance_storage_animation(vertex.instance_index);
+    var model = mesh.model * instance * animation_skin_model(
+        animation.x,
+        bitcast<f32>(animation.y),
+        vertex.joint_indices,
+        vertex.joint_weights
+    );
+#else // INSTANCE_STORAGE
+    var model = instance_skin_model(
+        mesh.model,
+        mesh.inverse_transpose_model,
+        instance,
+        skin_model(vertex.joint_indices, vertex.joint_weights)
+    );
+#endif // INSTANCE_STORAGE
+#else // INSTANCE_ANIMATION
+    var model = instance_skin_model(
+        mesh.model,
+        mesh.inverse_transpose_model,
+        instance,
+        skin_model(vertex.joint_indices, vertex.joint_weights)
+    );
+#endif // INSTANCE_ANIMATION
+#else // SKINNED
+    var model = mesh.model * instance;
+#endif // SKINNED
+
+    out.clip_position = mesh_position_local_to_clip(model, vec4(vertex.position, 1.0));
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif // DEPTH_CLAMP_ORTHO
+
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+#ifdef SKINNED
+    out.world_normal = skin_normals(model, vertex.normal);
+#else // SKINNED
+    out.world_normal = mesh_normal_local_to_world(
+        instance_normal_local_to_mesh(instance, vertex.normal)
+    );
+#endif // SKINNED
+
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_tangent_local_to_world(model, vertex.tangent);
+    out.world_tangent.w = out.world_tangent.w * instance_sign_determinant(instance);
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+
+#ifdef INSTANCE_IMPOSTOR
+    // Impostor quads face the camera instead of following the model.
+    let impostor = impostor_vertex(model, vertex.position);
+    out.clip_position = mesh_position_world_to_clip(impostor.world_position);
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif // DEPTH_CLAMP_ORTHO
+#ifdef NORMAL_PREPASS
+    out.world_normal = impostor.world_normal;
+#endif // NORMAL_PREPASS
+    out.impostor_layer = impostor.layer;
+#endif // INSTANCE_IMPOSTOR
+
+#ifdef INSTANCE_PICKING
+    out.instance_index = vertex.instance_index;
+#endif // INSTANCE_PICKING
+
+    return out;
+}
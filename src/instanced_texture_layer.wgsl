@@ -0,0 +1,73 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_core_pipeline::tonemapping
+#import bevy_instanced_mesh_material_pipeline::instance_functions
+
+// Unlit fragment shader of `InstancedTextureArrayMaterial` and `InstancedTextureAtlasMaterial`.
+struct TextureLayerMaterial {
+    color: vec4<f32>,
+    // Columns and rows of the atlas.
+    atlas_grid: vec2<u32>,
+    // Alpha below which fragments are discarded, or negative if none are.
+    alpha_cutoff: f32,
+};
+
+@group(1) @binding(0)
+var<uniform> material: TextureLayerMaterial;
+#ifdef TEXTURE_ATLAS
+@group(1) @binding(1)
+var base_color_texture: texture_2d<f32>;
+#else
+@group(1) @binding(1)
+var base_color_texture: texture_2d_array<f32>;
+#endif
+@group(1) @binding(2)
+var base_color_sampler: sampler;
+
+struct FragmentInput {
+    @builtin(front_facing) is_front: bool,
+    @builtin(position) frag_coord: vec4<f32>,
+    #import bevy_pbr::mesh_vertex_output
+    #import bevy_instanced_mesh_material_pipeline::instance_vertex_output
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    var layer = 0u;
+#ifdef INSTANCE_LAYER
+    layer = in.instance_layer;
+#endif
+
+    var output_color = material.color;
+#ifdef VERTEX_UVS
+#ifdef TEXTURE_ATLAS
+    let cell = vec2<u32>(layer % material.atlas_grid.x, layer / material.atlas_grid.x);
+    let uv = (vec2<f32>(cell) + in.uv) / vec2<f32>(material.atlas_grid);
+    output_color = output_color * textureSample(base_color_texture, base_color_sampler, uv);
+#else
+    output_color = output_color * textureSample(base_color_texture, base_color_sampler, in.uv, i32(layer));
+#endif
+#endif
+#ifdef VERTEX_COLORS
+    output_color = output_color * in.color;
+#endif
+#ifdef INSTANCE_COLOR
+    output_color = output_color * in.instance_color;
+#endif
+
+    if output_color.a < material.alpha_cutoff {
+        discard;
+    }
+#ifdef INSTANCE_STORAGE
+    if instance_lod_dithered(in.frag_coord.xy, in.instance_lod_fade) {
+        discard;
+    }
+#endif
+
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color);
+#endif
+#ifdef PREMULTIPLY_ALPHA
+    output_color = vec4<f32>(output_color.rgb * output_color.a, output_color.a);
+#endif
+    return output_color;
+}
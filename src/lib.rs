@@ -1,56 +1,236 @@
-use std::{hash::Hash, marker::PhantomData};
+use std::{hash::Hash, marker::PhantomData, ops::Range};
 
+use animation::{warn_without_animation_poses, InstanceAnimationPlugin};
+use auto_instance::AutoInstancedEntities;
 use bevy::{
     asset::load_internal_asset,
-    core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
-    ecs::query::QueryItem,
-    pbr::{MaterialPipelineKey, MeshPipelineKey, MeshUniform, RenderMaterials},
+    core_pipeline::{
+        core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+        prepass::{AlphaMask3dPrepass, Opaque3dPrepass},
+    },
+    pbr::{
+        MaterialPipelineKey, MeshPipelineKey, MeshUniform, RenderLightSystems, RenderMaterials,
+        Shadow,
+    },
     prelude::*,
     render::{
-        extract_component::{ExtractComponent, ExtractComponentPlugin},
         render_asset::RenderAssets,
         render_phase::{AddRenderCommand, DrawFunctions, RenderPhase},
         render_resource::*,
-        renderer::RenderDevice,
-        view::ExtractedView,
-        RenderApp, RenderSet,
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        view::{ExtractedView, VisibilitySystems, VisibleEntities},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
     },
+    utils::{HashMap, HashSet},
+};
+use bounds::update_instanced_aabbs;
+use cpu_culling::add_cpu_instance_culling;
+use culling::add_instance_culling;
+use impostor::{bake_impostors, InstancedImpostorPlugin};
+use indirect::{IndirectArgs, IndirectInstancesPlugin};
+use lod::{add_instanced_lods, InstancedLodEntities, InstancedLodPlugin};
+use pipeline::{
+    DrawMeshInstancedPrepass, DrawMeshInstancedWithMaterial, DrawMeshStorageInstancedPrepass,
+    DrawMeshStorageInstancedWithMaterial, InstanceBindings, InstancedMeshMaterialPipeline,
+    InstancedPrepassPipeline,
+};
+use prepass::queue_instanced_prepass_meshes;
+use shadow::queue_instanced_shadows;
+use sorting::add_instance_sorting;
+use specialization::{
+    add_specialization_error_asset, SpecializationErrorPlugin, SpecializationErrors,
+};
+use vertex_animation::{warn_without_vertex_animations, VertexAnimationPlugin};
+use wgpu::DownlevelFlags;
+
+use crate::pipeline::{
+    INSTANCED_IMPOSTOR_PREPASS_SHADER_HANDLE, INSTANCED_IMPOSTOR_SHADER_HANDLE,
+    INSTANCED_MESH_SHADER_HANDLE, INSTANCED_PBR_SHADER_HANDLE, INSTANCED_PREPASS_SHADER_HANDLE,
+    INSTANCED_TEXTURE_LAYER_SHADER_HANDLE, INSTANCE_FUNCTIONS_SHADER_HANDLE,
+    INSTANCE_IMPOSTOR_SHADER_HANDLE, INSTANCE_STORAGE_SHADER_HANDLE,
+    INSTANCE_VERTEX_OUTPUT_SHADER_HANDLE,
 };
-use bytemuck::{Pod, Zeroable};
-use pipeline::{DrawMeshInstancedWithMaterial, InstancedMeshMaterialPipeline};
 
-use crate::pipeline::INSTANCED_MESH_SHADER_HANDLE;
+// Lets `#[derive(InstanceData)]` refer to this crate by name from within it.
+extern crate self as bevy_instanced_mesh_material_pipeline;
 
-pub mod pipeline;
+/// Declares a `ShaderType` uniform in a `uniform` module of its own and imports it with the
+/// given visibility. The derive checks each field in a function that is never called, so
+/// `dead_code` can only be allowed for it around the struct.
+macro_rules! shader_uniform {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_attr:meta])* $field_vis:vis $field:ident: $field_ty:ty),* $(,)?
+        }
+    ) => {
+        $vis use uniform::$name;
 
-#[derive(Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct Instance {
-    pub position: Vec3,
+        #[allow(dead_code)]
+        mod uniform {
+            use bevy::{prelude::*, render::render_resource::ShaderType};
+
+            $(#[$attr])*
+            #[derive(ShaderType)]
+            pub struct $name {
+                $($(#[$field_attr])* $field_vis $field: $field_ty),*
+            }
+        }
+    };
 }
 
+pub mod animation;
+pub mod auto_instance;
+pub mod bounds;
+pub mod bvh;
+pub mod cpu_culling;
+pub mod culling;
+pub mod impostor;
+pub mod indirect;
+pub mod instance;
+pub mod lod;
+pub mod mesh2d;
+pub mod picking;
+pub mod pipeline;
+pub mod prepass;
+pub mod raycast;
+pub mod shadow;
+pub mod sorting;
+pub mod specialization;
+pub mod standard_material;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod texture_layer;
+pub mod vertex_animation;
+
+pub use animation::AnimationPoses;
+pub use auto_instance::{AutoInstance, AutoInstancePlugin, AutoInstancedMaterial};
+pub use bevy_instanced_mesh_material_pipeline_macros::InstanceData;
+pub use bvh::{InstanceBvh, InstanceBvhPlugin, MeshVersions};
+pub use cpu_culling::CpuInstanceCulling;
+pub use culling::GpuInstanceCulling;
+pub use impostor::{InstancedImpostor, InstancedImpostorMaterial};
+pub use indirect::IndirectInstances;
+pub use instance::*;
+pub use lod::InstancedLods;
+pub use mesh2d::InstancedMesh2dMaterialPipelinePlugin;
+pub use picking::{
+    InstanceHit, InstancePicked, InstancePicking, InstancePickingPlugin, PickInstance,
+};
+pub use raycast::{raycast_instances, InstanceRayHit, InstanceRaycast, InstanceRaycastPlugin};
+pub use sorting::{GpuFrontToBackSorting, GpuInstanceSorting};
+pub use specialization::InstancedSpecializationError;
+pub use standard_material::InstancedStandardMaterial;
+pub use texture_layer::{InstancedTextureArrayMaterial, InstancedTextureAtlasMaterial};
+pub use vertex_animation::VertexAnimationTexture;
+
 #[derive(Component, Deref)]
-pub struct Instances(pub Vec<Instance>);
+pub struct Instances<I: InstanceData = Instance>(pub Vec<I>);
 
-impl ExtractComponent for Instances {
-    type Query = &'static Self;
-    type Filter = ();
-    type Out = Self;
+/// Instances that changed or were removed in the main world since the last extraction.
+///
+/// Unchanged instances are not extracted again; they stay resident in [`RenderInstances`].
+#[derive(Resource)]
+pub struct ExtractedInstances<I: InstanceData = Instance> {
+    changed: Vec<(Entity, Vec<I>)>,
+    removed: Vec<Entity>,
+}
 
-    fn extract_component(instances: QueryItem<'_, Self::Query>) -> Option<Self> {
-        Some(Instances(instances.0.clone()))
+impl<I: InstanceData> Default for ExtractedInstances<I> {
+    fn default() -> Self {
+        Self {
+            changed: Vec::new(),
+            removed: Vec::new(),
+        }
     }
 }
 
-#[derive(Default)]
-pub struct InstancedMeshMaterialPipelinePlugin<M> {
-    marker: PhantomData<M>,
+#[allow(clippy::type_complexity)]
+fn extract_instances<I: InstanceData>(
+    mut extracted_instances: ResMut<ExtractedInstances<I>>,
+    changed_instances: Extract<Query<(Entity, &Instances<I>), Changed<Instances<I>>>>,
+    mut removed_instances: Extract<RemovedComponents<Instances<I>>>,
+) {
+    // Removals are applied first, so instances removed and re-added in the same frame survive.
+    extracted_instances.removed.extend(removed_instances.iter());
+    extracted_instances.changed.extend(
+        changed_instances
+            .iter()
+            .map(|(entity, instances)| (entity, instances.0.clone())),
+    );
 }
 
-impl<M> Plugin for InstancedMeshMaterialPipelinePlugin<M>
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InstanceSystems {
+    /// Uploads changed instances to their [`InstanceBuffers`].
+    PrepareBuffers,
+    /// Binds the [`InstanceBuffers`] for [`InstanceStorage::StorageBuffer`].
+    PrepareBindGroups,
+}
+
+/// How instances are bound to the instanced shaders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum InstanceStorage {
+    /// A per-instance vertex buffer at slot 1, with one attribute per field.
+    #[default]
+    VertexBuffer,
+    /// A storage buffer at bind group 3, indexed by `instance_index`. Not limited to vertex
+    /// formats or vertex attribute locations.
+    ///
+    /// Falls back to [`InstanceStorage::VertexBuffer`] where vertex shaders cannot read storage
+    /// buffers, such as on WebGL2, or if the size of an instance is not a multiple of 4 bytes.
+    StorageBuffer,
+}
+
+impl InstanceStorage {
+    /// The storage actually used for instances of `I` on `render_device` and `render_adapter`.
+    pub fn resolve<I: InstanceData>(
+        self,
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+    ) -> Self {
+        match self {
+            InstanceStorage::StorageBuffer
+                if storage_instances_supported(render_device, render_adapter)
+                    && std::mem::size_of::<I>().is_multiple_of(4) =>
+            {
+                InstanceStorage::StorageBuffer
+            }
+            _ => InstanceStorage::VertexBuffer,
+        }
+    }
+}
+
+/// Draws entities with [`Instances`] of `I` using the material `M`.
+///
+/// Instances with an [`InstanceAnimation`] or an [`InstanceVertexAnimation`] are only animated
+/// with [`InstanceStorage::StorageBuffer`], not with the default vertex buffers:
+///
+/// ```ignore
+/// app.add_plugin(InstancedMeshMaterialPipelinePlugin::<StandardMaterial, AnimatedInstance> {
+///     instance_storage: InstanceStorage::StorageBuffer,
+///     ..default()
+/// });
+/// ```
+pub struct InstancedMeshMaterialPipelinePlugin<M, I = Instance> {
+    pub instance_storage: InstanceStorage,
+    marker: PhantomData<(M, I)>,
+}
+
+impl<M, I> Default for InstancedMeshMaterialPipelinePlugin<M, I> {
+    fn default() -> Self {
+        Self {
+            instance_storage: InstanceStorage::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M, I> Plugin for InstancedMeshMaterialPipelinePlugin<M, I>
 where
     M: Material + Sync + Send + 'static,
     M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
 {
     fn build(&self, app: &mut App) {
         load_internal_asset!(
@@ -59,60 +239,368 @@ where
             "instanced_mesh.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            INSTANCED_PREPASS_SHADER_HANDLE,
+            "instanced_prepass.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_PBR_SHADER_HANDLE,
+            "instanced_pbr.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_TEXTURE_LAYER_SHADER_HANDLE,
+            "instanced_texture_layer.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCE_IMPOSTOR_SHADER_HANDLE,
+            "instance_impostor.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_IMPOSTOR_SHADER_HANDLE,
+            "instanced_impostor.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_IMPOSTOR_PREPASS_SHADER_HANDLE,
+            "instanced_impostor_prepass.wgsl",
+            Shader::from_wgsl
+        );
 
-        app.add_plugin(ExtractComponentPlugin::<Instances>::default());
-        app.sub_app_mut(RenderApp)
+        if !app.is_plugin_added::<InstancesPlugin<I>>() {
+            app.add_plugin(InstancesPlugin::<I>::default());
+        }
+        app.add_system(bake_impostors::<M>);
+        add_specialization_error_asset::<M>(app);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        let instance_storage = self.instance_storage.resolve::<I>(
+            render_app.world.resource::<RenderDevice>(),
+            render_app.world.resource::<RenderAdapter>(),
+        );
+        warn_without_animation_poses::<I>(instance_storage);
+        warn_without_vertex_animations::<I>(instance_storage);
+        let instanced_mesh_material_pipeline =
+            InstancedMeshMaterialPipeline::<M, I>::new(&mut render_app.world, instance_storage);
+        let instanced_prepass_pipeline =
+            InstancedPrepassPipeline::<M, I>::new(&mut render_app.world, instance_storage);
+
+        render_app
             .add_render_command::<Opaque3d, DrawMeshInstancedWithMaterial<M>>()
             .add_render_command::<AlphaMask3d, DrawMeshInstancedWithMaterial<M>>()
             .add_render_command::<Transparent3d, DrawMeshInstancedWithMaterial<M>>()
-            .init_resource::<InstancedMeshMaterialPipeline<M>>()
-            .init_resource::<SpecializedMeshPipelines<InstancedMeshMaterialPipeline<M>>>()
-            .add_system(queue_instanced_meshes_with_material::<M>.in_set(RenderSet::Queue))
-            .add_system(prepare_instance_buffers.in_set(RenderSet::Prepare));
+            .add_render_command::<Shadow, DrawMeshInstancedPrepass<M>>()
+            .add_render_command::<Opaque3d, DrawMeshStorageInstancedWithMaterial<M>>()
+            .add_render_command::<AlphaMask3d, DrawMeshStorageInstancedWithMaterial<M>>()
+            .add_render_command::<Transparent3d, DrawMeshStorageInstancedWithMaterial<M>>()
+            .add_render_command::<Shadow, DrawMeshStorageInstancedPrepass<M>>()
+            // Only materials with their prepass enabled set up the prepass draw functions.
+            .init_resource::<DrawFunctions<Opaque3dPrepass>>()
+            .init_resource::<DrawFunctions<AlphaMask3dPrepass>>()
+            .add_render_command::<Opaque3dPrepass, DrawMeshInstancedPrepass<M>>()
+            .add_render_command::<AlphaMask3dPrepass, DrawMeshInstancedPrepass<M>>()
+            .add_render_command::<Opaque3dPrepass, DrawMeshStorageInstancedPrepass<M>>()
+            .add_render_command::<AlphaMask3dPrepass, DrawMeshStorageInstancedPrepass<M>>()
+            .insert_resource(instanced_mesh_material_pipeline)
+            .init_resource::<SpecializedMeshPipelines<InstancedMeshMaterialPipeline<M, I>>>()
+            .insert_resource(instanced_prepass_pipeline)
+            .init_resource::<SpecializedMeshPipelines<InstancedPrepassPipeline<M, I>>>()
+            .add_system(queue_instanced_meshes_with_material::<M, I>.in_set(RenderSet::Queue))
+            .add_system(queue_instanced_prepass_meshes::<M, I>.in_set(RenderSet::Queue))
+            .add_system(queue_instanced_shadows::<M, I>.in_set(RenderLightSystems::QueueShadows));
+
+        add_instanced_lods::<M, I>(app);
+        if !app.is_plugin_added::<InstancedImpostorPlugin>() {
+            app.add_plugin(InstancedImpostorPlugin);
+        }
+    }
+}
+
+/// Extracts and uploads the [`Instances`] of `I`, and adds what every pipeline drawing them
+/// shares. Added by [`InstancedMeshMaterialPipelinePlugin`] and
+/// [`InstancedMesh2dMaterialPipelinePlugin`].
+pub struct InstancesPlugin<I>(PhantomData<I>);
+
+impl<I> Default for InstancesPlugin<I> {
+    fn default() -> Self {
+        Self(PhantomData)
     }
 }
 
-#[derive(Component)]
+impl<I: InstanceData> Plugin for InstancesPlugin<I> {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_FUNCTIONS_SHADER_HANDLE,
+            "instance_functions.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCE_STORAGE_SHADER_HANDLE,
+            "instance_storage.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCE_VERTEX_OUTPUT_SHADER_HANDLE,
+            "instance_vertex_output.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_system(
+            update_instanced_aabbs::<I>
+                .in_base_set(CoreSet::PostUpdate)
+                .after(VisibilitySystems::CalculateBoundsFlush)
+                .before(VisibilitySystems::CheckVisibility),
+        );
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedInstances<I>>()
+            .init_resource::<InstanceBuffers>()
+            .init_resource::<RenderInstances<I>>()
+            .init_resource::<AutoInstancedEntities>()
+            .add_system(extract_instances::<I>.in_schedule(ExtractSchedule))
+            .add_system(
+                prepare_instance_buffers::<I>
+                    .in_set(RenderSet::Prepare)
+                    .in_set(InstanceSystems::PrepareBuffers),
+            );
+
+        add_instance_culling::<I>(app);
+        add_cpu_instance_culling::<I>(app);
+        add_instance_sorting::<I>(app);
+        if !app.is_plugin_added::<IndirectInstancesPlugin>() {
+            app.add_plugin(IndirectInstancesPlugin);
+        }
+        if !app.is_plugin_added::<InstanceAnimationPlugin>() {
+            app.add_plugin(InstanceAnimationPlugin);
+        }
+        if !app.is_plugin_added::<VertexAnimationPlugin>() {
+            app.add_plugin(VertexAnimationPlugin);
+        }
+        if !app.is_plugin_added::<InstancedLodPlugin>() {
+            app.add_plugin(InstancedLodPlugin);
+        }
+        if !app.is_plugin_added::<SpecializationErrorPlugin>() {
+            app.add_plugin(SpecializationErrorPlugin);
+        }
+    }
+}
+
+/// GPU buffer holding the instances of one entity. Lives across frames and is only rewritten
+/// where its instances changed.
 pub struct InstanceBuffer {
     buffer: Buffer,
+    capacity: u64,
     length: usize,
+    /// Number of writes, to tell when the instances changed.
+    generation: u64,
+    indirect: Option<IndirectArgs>,
+    bind_group: Option<BindGroup>,
+    /// The resources bound with the buffer.
+    bindings: Option<InstanceBindings>,
 }
 
-fn prepare_instance_buffers(
-    mut commands: Commands,
-    query: Query<(Entity, &Instances)>,
-    render_device: Res<RenderDevice>,
-) {
-    for (entity, instances) in &query {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+impl InstanceBuffer {
+    fn new<I: InstanceData>(
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+        instances: &[I],
+    ) -> Self {
+        // Grow geometrically so instance sets that keep growing are not reallocated every frame.
+        let capacity = (std::mem::size_of_val(instances) as u64)
+            .max(std::mem::size_of::<I>() as u64)
+            .next_power_of_two();
+        let buffer = render_device.create_buffer(&BufferDescriptor {
             label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(instances.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            size: capacity,
+            usage: if storage_instances_supported(render_device, render_adapter) {
+                // Read and written by compute shaders, such as the culling pass.
+                BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::STORAGE
+            } else {
+                BufferUsages::VERTEX | BufferUsages::COPY_DST
+            },
+            mapped_at_creation: false,
         });
-        commands.entity(entity).insert(InstanceBuffer {
+
+        Self {
             buffer,
-            length: instances.len(),
-        });
+            capacity,
+            length: 0,
+            generation: 0,
+            indirect: None,
+            bind_group: None,
+            bindings: None,
+        }
+    }
+
+    /// Uploads `instances`, writing only the ranges that differ from the `previous` upload.
+    fn write<I: InstanceData>(
+        &mut self,
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+        render_queue: &RenderQueue,
+        previous: &[I],
+        instances: &[I],
+    ) {
+        let generation = self.generation + 1;
+        if std::mem::size_of_val(instances) as u64 > self.capacity {
+            *self = Self::new(render_device, render_adapter, instances);
+            render_queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(instances));
+        } else {
+            let stride = std::mem::size_of::<I>();
+            for range in changed_ranges(previous, instances) {
+                render_queue.write_buffer(
+                    &self.buffer,
+                    (range.start * stride) as u64,
+                    bytemuck::cast_slice(&instances[range]),
+                );
+            }
+        }
+        self.length = instances.len();
+        self.generation = generation;
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Size of the buffer in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Changes whenever the instances are written.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Binds the buffer for [`InstanceStorage::StorageBuffer`].
+    pub fn bind_group(&self) -> Option<&BindGroup> {
+        self.bind_group.as_ref()
+    }
+
+    /// Arguments of the indirect draw of an entity with [`IndirectInstances`].
+    pub fn indirect(&self) -> Option<&Buffer> {
+        self.indirect.as_ref().map(IndirectArgs::buffer)
+    }
+}
+
+/// Whether vertex shaders can bind instance buffers as storage buffers, which rules out WebGL2.
+pub(crate) fn storage_instances_supported(
+    render_device: &RenderDevice,
+    render_adapter: &RenderAdapter,
+) -> bool {
+    // The instances, animation poses, vertex animations and level of detail fades.
+    render_device.limits().max_storage_buffers_per_shader_stage >= 4
+        && render_adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(DownlevelFlags::VERTEX_STORAGE)
+}
+
+/// Runs of consecutive instances in `instances` that differ from `previous`.
+fn changed_ranges<'a, I: InstanceData>(
+    previous: &'a [I],
+    instances: &'a [I],
+) -> impl Iterator<Item = Range<usize>> + 'a {
+    let mut index = 0;
+    std::iter::from_fn(move || {
+        let changed = |index: usize| {
+            previous.get(index).map(bytemuck::bytes_of)
+                != Some(bytemuck::bytes_of(&instances[index]))
+        };
+        let start = (index..instances.len()).find(|&index| changed(index))?;
+        let end = (start..instances.len())
+            .find(|&index| !changed(index))
+            .unwrap_or(instances.len());
+        index = end;
+        Some(start..end)
+    })
+}
+
+/// Instance buffers of all instanced entities in the render world.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct InstanceBuffers(HashMap<Entity, InstanceBuffer>);
+
+/// The instances last uploaded to each entity's [`InstanceBuffer`].
+#[derive(Resource, Deref, DerefMut)]
+pub struct RenderInstances<I: InstanceData = Instance>(HashMap<Entity, Vec<I>>);
+
+impl<I: InstanceData> Default for RenderInstances<I> {
+    fn default() -> Self {
+        Self(HashMap::default())
+    }
+}
+
+fn prepare_instance_buffers<I: InstanceData>(
+    mut extracted_instances: ResMut<ExtractedInstances<I>>,
+    mut render_instances: ResMut<RenderInstances<I>>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+) {
+    let ExtractedInstances { changed, removed } = &mut *extracted_instances;
+
+    for entity in removed.drain(..) {
+        render_instances.remove(&entity);
+        instance_buffers.remove(&entity);
+    }
+
+    for (entity, instances) in changed.drain(..) {
+        let previous = render_instances.entry(entity).or_default();
+        let instance_buffer = instance_buffers
+            .entry(entity)
+            .or_insert_with(|| InstanceBuffer::new(&render_device, &render_adapter, &instances));
+        instance_buffer.write(
+            &render_device,
+            &render_adapter,
+            &render_queue,
+            previous,
+            &instances,
+        );
+        *previous = instances;
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn queue_instanced_meshes_with_material<M>(
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_instanced_meshes_with_material<M, I>(
     opaque_draw_functions: Res<DrawFunctions<Opaque3d>>,
     alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_draw_functions: Res<DrawFunctions<Transparent3d>>,
-    instanced_mesh_material_pipeline: Res<InstancedMeshMaterialPipeline<M>>,
+    instanced_mesh_material_pipeline: Res<InstancedMeshMaterialPipeline<M, I>>,
     msaa: Res<Msaa>,
-    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMeshMaterialPipeline<M>>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMeshMaterialPipeline<M, I>>>,
     pipeline_cache: Res<PipelineCache>,
     render_meshes: Res<RenderAssets<Mesh>>,
     render_materials: Res<RenderMaterials<M>>,
-    instanced_meshes_with_material: Query<
-        (Entity, &MeshUniform, &Handle<Mesh>, &Handle<M>),
-        With<Instances>,
-    >,
+    render_instances: Res<RenderInstances<I>>,
+    lod_entities: Res<InstancedLodEntities>,
+    auto_instanced: Res<AutoInstancedEntities>,
+    mut queued_batches: Local<HashSet<Entity>>,
+    specialization_errors: Res<SpecializationErrors>,
+    instanced_meshes_with_material: Query<(&MeshUniform, &Handle<Mesh>, &Handle<M>)>,
     mut views: Query<(
         &ExtractedView,
+        &VisibleEntities,
         &mut RenderPhase<Opaque3d>,
         &mut RenderPhase<AlphaMask3d>,
         &mut RenderPhase<Transparent3d>,
@@ -120,24 +608,36 @@ fn queue_instanced_meshes_with_material<M>(
 ) where
     M: Material,
     M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
 {
-    let draw_instanced_mesh_with_opaque_material = opaque_draw_functions
-        .read()
-        .id::<DrawMeshInstancedWithMaterial<M>>();
-    let draw_instanced_mesh_with_alpha_mask_material = alpha_mask_draw_functions
-        .read()
-        .id::<DrawMeshInstancedWithMaterial<M>>();
-    let draw_instanced_mesh_with_transparent_material = transparent_draw_functions
-        .read()
-        .id::<DrawMeshInstancedWithMaterial<M>>();
+    let draw_instanced_mesh_with_opaque_material =
+        instanced_mesh_material_pipeline.draw_function(&opaque_draw_functions);
+    let draw_instanced_mesh_with_alpha_mask_material =
+        instanced_mesh_material_pipeline.draw_function(&alpha_mask_draw_functions);
+    let draw_instanced_mesh_with_transparent_material =
+        instanced_mesh_material_pipeline.draw_function(&transparent_draw_functions);
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
 
-    for (view, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in &mut views {
+    for (view, visible_entities, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in
+        &mut views
+    {
         let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
         let rangefinder = view.rangefinder3d();
-        for (entity, mesh_uniform, mesh_handle, material_handle) in &instanced_meshes_with_material
+        for entity in auto_instanced
+            .drawn_entities(&visible_entities.entities, &mut queued_batches)
+            .flat_map(|entity| lod_entities.drawn_entities(entity))
         {
+            let Ok((mesh_uniform, mesh_handle, material_handle)) =
+                instanced_meshes_with_material.get(entity)
+            else {
+                continue;
+            };
+            let source = match lod_entities.instances_of(entity) {
+                Some(source) if render_instances.contains_key(&source) => source,
+                _ => continue,
+            };
+
             if let (Some(mesh), Some(material)) = (
                 render_meshes.get(mesh_handle),
                 render_materials.get(material_handle),
@@ -151,17 +651,26 @@ fn queue_instanced_meshes_with_material<M>(
                     mesh_key |= MeshPipelineKey::BLEND_MULTIPLY;
                 }
 
-                let pipeline = pipelines
-                    .specialize(
-                        &pipeline_cache,
-                        &instanced_mesh_material_pipeline,
-                        MaterialPipelineKey {
-                            mesh_key,
-                            bind_group_data: material.key.clone(),
-                        },
-                        &mesh.layout,
-                    )
-                    .unwrap();
+                let pipeline = match pipelines.specialize(
+                    &pipeline_cache,
+                    &instanced_mesh_material_pipeline,
+                    MaterialPipelineKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(error) => {
+                        specialization_errors.report(
+                            source,
+                            mesh_handle,
+                            material_handle.id(),
+                            error,
+                        );
+                        continue;
+                    }
+                };
 
                 let distance =
                     rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
@@ -201,3 +710,42 @@ fn queue_instanced_meshes_with_material<M>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(xs: &[f32]) -> Vec<Instance> {
+        xs.iter()
+            .map(|&x| Instance::from_translation(Vec3::new(x, 0.0, 0.0)))
+            .collect()
+    }
+
+    fn ranges(previous: &[f32], current: &[f32]) -> Vec<(usize, usize)> {
+        changed_ranges(&instances(previous), &instances(current))
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    #[test]
+    fn changed_ranges_of_unchanged_instances_are_empty() {
+        assert!(ranges(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]).is_empty());
+        assert!(ranges(&[], &[]).is_empty());
+        // Removed instances leave nothing to upload.
+        assert!(ranges(&[1.0, 2.0, 3.0], &[1.0, 2.0]).is_empty());
+    }
+
+    #[test]
+    fn changed_ranges_merge_consecutive_changes() {
+        assert_eq!(ranges(&[], &[1.0, 2.0, 3.0]), [(0, 3)]);
+        assert_eq!(
+            ranges(
+                &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+                &[0.0, 2.0, 0.0, 0.0, 5.0, 0.0]
+            ),
+            [(0, 1), (2, 4), (5, 6)]
+        );
+        assert_eq!(ranges(&[1.0, 2.0], &[1.0, 0.0, 3.0, 4.0]), [(1, 4)]);
+        assert_eq!(ranges(&[1.0, 2.0], &[1.0, 2.0, 3.0]), [(2, 3)]);
+    }
+}
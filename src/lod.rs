@@ -0,0 +1,593 @@
+use bevy::{
+    pbr::{MeshUniform, NotShadowCaster, ViewLightEntities},
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewSet},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::{FloatOrd, HashMap},
+};
+
+use crate::{
+    impostor::{BakedImpostor, InstancedImpostor},
+    pipeline::{
+        instance_bind_group, InstanceBindGroupLayout, InstanceBindings, InstanceBindingsKey,
+    },
+    sorting::{GpuFrontToBackSorting, GpuInstanceSorting},
+    storage_instances_supported, InstanceBuffers, InstanceData, InstanceSystems, Instances,
+    RenderInstances,
+};
+
+/// Levels of detail of an entity with [`Instances`]: each instance is drawn with the mesh of the
+/// first level whose `max_distance` from the camera is beyond the instance, instead of with the
+/// entity's `Handle<Mesh>`. Instances beyond the last level are drawn as its `impostor`, or not
+/// at all.
+///
+/// Instances are selected per camera, and shadows use the levels selected for the camera they
+/// are rendered for. Levels are drawn without the entity's `SkinnedMesh`, and entities with
+/// levels are not culled with [`GpuInstanceCulling`](crate::GpuInstanceCulling).
+#[derive(Component, Clone, Debug, Default)]
+pub struct InstancedLods {
+    /// Ordered from the nearest level to the farthest.
+    pub levels: Vec<InstancedLod>,
+    /// Width of the distance range around each switch distance in which instances are dithered
+    /// from one level to the next, or zero to switch at once.
+    ///
+    /// Only applies with [`InstanceStorage::StorageBuffer`](crate::InstanceStorage), to the
+    /// fragment shaders of this crate. The prepass and shadows draw both levels undithered.
+    pub cross_fade: f32,
+    /// Camera-facing quads baked from the entity's mesh, drawn beyond the last level.
+    pub impostor: Option<InstancedImpostor>,
+}
+
+/// A mesh of [`InstancedLods`] and the distance up to which it is used.
+#[derive(Clone, Debug, Default)]
+pub struct InstancedLod {
+    pub mesh: Handle<Mesh>,
+    pub max_distance: f32,
+}
+
+impl InstancedLods {
+    /// Levels from pairs of a mesh and its maximum distance, nearest first.
+    pub fn new(levels: impl IntoIterator<Item = (Handle<Mesh>, f32)>) -> Self {
+        Self {
+            levels: levels
+                .into_iter()
+                .map(|(mesh, max_distance)| InstancedLod { mesh, max_distance })
+                .collect(),
+            cross_fade: 0.0,
+            impostor: None,
+        }
+    }
+
+    pub fn with_cross_fade(mut self, cross_fade: f32) -> Self {
+        self.cross_fade = cross_fade;
+        self
+    }
+
+    pub fn with_impostor(mut self, impostor: InstancedImpostor) -> Self {
+        self.impostor = Some(impostor);
+        self
+    }
+}
+
+/// The render entities drawing the levels of an entity with [`InstancedLods`] this frame.
+pub struct ExtractedLods {
+    /// The entity's transform.
+    pub transform: Mat4,
+    /// One render entity per level, with the level's mesh and the entity's material, and one
+    /// for the impostor once it is baked.
+    pub levels: Vec<Entity>,
+    pub max_distances: Vec<f32>,
+    pub cross_fade: f32,
+}
+
+/// Levels of detail of every visible entity with [`InstancedLods`] this frame.
+#[derive(Resource, Default)]
+pub struct InstancedLodEntities {
+    lods: HashMap<Entity, ExtractedLods>,
+    /// The entity and level drawn by each level entity.
+    sources: HashMap<Entity, (Entity, usize)>,
+}
+
+impl InstancedLodEntities {
+    pub fn get(&self, entity: Entity) -> Option<&ExtractedLods> {
+        self.lods.get(&entity)
+    }
+
+    /// The entity whose instances `entity` draws: its source for a level entity, itself for
+    /// entities without levels, and `None` for entities drawn by their levels.
+    pub fn instances_of(&self, entity: Entity) -> Option<Entity> {
+        match self.sources.get(&entity) {
+            Some((source, _)) => Some(*source),
+            None if self.lods.contains_key(&entity) => None,
+            None => Some(entity),
+        }
+    }
+
+    /// The entities drawing the instances of `entity`: its levels, or itself.
+    pub fn drawn_entities(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        let levels = self
+            .lods
+            .get(&entity)
+            .map(|lods| lods.levels.iter().copied());
+        let itself = levels.is_none().then_some(entity);
+        levels.into_iter().flatten().chain(itself)
+    }
+}
+
+/// Adds the resources behind [`InstancedLods`]. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct InstancedLodPlugin;
+
+impl Plugin for InstancedLodPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<InstancedLodEntities>()
+            .init_resource::<LodInstanceBuffers>()
+            .add_system(
+                copy_lod_mesh_uniforms
+                    .after(RenderSet::ExtractCommands)
+                    .before(RenderSet::Prepare),
+            )
+            .add_system(cleanup_instanced_lods.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Adds [`InstancedLods`] of entities with [`Instances`] of `I` and the material `M` to
+/// [`InstancedLodPlugin`].
+pub(crate) fn add_instanced_lods<M: Material, I: InstanceData>(app: &mut App) {
+    app.sub_app_mut(RenderApp)
+        .add_system(extract_instanced_lods::<M, I>.in_schedule(ExtractSchedule))
+        .add_system(
+            prepare_instanced_lods::<M, I>
+                .in_set(RenderSet::Prepare)
+                .after(InstanceSystems::PrepareBindGroups)
+                // Shadow views are spawned before the view uniforms are prepared.
+                .after(ViewSet::PrepareUniforms),
+        );
+}
+
+/// Spawns a render entity for each level of every visible entity with [`InstancedLods`]. Their
+/// `MeshUniform` is replaced by the entity's own in [`copy_lod_mesh_uniforms`].
+#[allow(clippy::type_complexity)]
+fn extract_instanced_lods<M: Material, I: InstanceData>(
+    mut commands: Commands,
+    mut lod_entities: ResMut<InstancedLodEntities>,
+    lods: Extract<
+        Query<
+            (
+                Entity,
+                &ComputedVisibility,
+                &GlobalTransform,
+                &InstancedLods,
+                &Handle<M>,
+                Option<With<NotShadowCaster>>,
+                Option<&BakedImpostor>,
+            ),
+            With<Instances<I>>,
+        >,
+    >,
+) {
+    let InstancedLodEntities {
+        lods: extracted_lods,
+        sources,
+    } = &mut *lod_entities;
+    for (entity, visibility, transform, lods, material, not_caster, baked_impostor) in &lods {
+        let impostor = lods.impostor.as_ref().zip(baked_impostor);
+        if !visibility.is_visible() || (lods.levels.is_empty() && impostor.is_none()) {
+            continue;
+        }
+
+        let transform = transform.compute_matrix();
+        let mesh_uniform = MeshUniform {
+            transform,
+            inverse_transpose_model: transform.inverse().transpose(),
+            flags: 0,
+        };
+
+        let mut levels = Vec::with_capacity(lods.levels.len() + 1);
+        let mut max_distances = Vec::with_capacity(lods.levels.len() + 1);
+        for lod in &lods.levels {
+            let mut level_entity = commands.spawn((
+                lod.mesh.clone_weak(),
+                mesh_uniform.clone(),
+                material.clone_weak(),
+            ));
+            if not_caster.is_some() {
+                level_entity.insert(NotShadowCaster);
+            }
+            sources.insert(level_entity.id(), (entity, levels.len()));
+            levels.push(level_entity.id());
+            max_distances.push(lod.max_distance);
+        }
+        if let Some((impostor, baked_impostor)) = impostor {
+            let mut level_entity = commands.spawn((
+                baked_impostor.mesh.clone_weak(),
+                mesh_uniform,
+                baked_impostor.material.clone_weak(),
+            ));
+            if not_caster.is_some() {
+                level_entity.insert(NotShadowCaster);
+            }
+            sources.insert(level_entity.id(), (entity, levels.len()));
+            levels.push(level_entity.id());
+            max_distances.push(impostor.max_distance);
+        }
+
+        extracted_lods.insert(
+            entity,
+            ExtractedLods {
+                transform,
+                levels,
+                max_distances,
+                cross_fade: lods.cross_fade.max(0.0),
+            },
+        );
+    }
+}
+
+/// Gives each level entity the `MeshUniform` bevy extracted for its entity, whose flags follow
+/// the entity's `NotShadowReceiver` and the handedness of its transform.
+fn copy_lod_mesh_uniforms(
+    lod_entities: Res<InstancedLodEntities>,
+    mut mesh_uniforms: Query<&mut MeshUniform>,
+) {
+    for (level_entity, (entity, _)) in &lod_entities.sources {
+        let Ok(mesh_uniform) = mesh_uniforms.get(*entity).cloned() else {
+            continue;
+        };
+        if let Ok(mut level_uniform) = mesh_uniforms.get_mut(*level_entity) {
+            *level_uniform = mesh_uniform;
+        }
+    }
+}
+
+/// Instances of one level of an entity selected for one camera, and how far each is faded
+/// into the level.
+pub struct LodInstances {
+    instances: Buffer,
+    /// Read by the fragment shaders to dither instances cross-fading between levels.
+    lod_fades: Option<Buffer>,
+    bind_group: Option<(InstanceBindingsKey, BindGroup)>,
+    capacity: usize,
+    len: u32,
+}
+
+impl LodInstances {
+    fn new(
+        render_device: &RenderDevice,
+        render_adapter: &RenderAdapter,
+        stride: u64,
+        capacity: usize,
+    ) -> Self {
+        let storage = storage_instances_supported(render_device, render_adapter);
+        Self {
+            instances: render_device.create_buffer(&BufferDescriptor {
+                label: Some("lod instance data buffer"),
+                size: capacity as u64 * stride,
+                usage: if storage {
+                    BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::STORAGE
+                } else {
+                    BufferUsages::VERTEX | BufferUsages::COPY_DST
+                },
+                mapped_at_creation: false,
+            }),
+            lod_fades: storage.then(|| {
+                render_device.create_buffer(&BufferDescriptor {
+                    label: Some("lod fade buffer"),
+                    size: (capacity * std::mem::size_of::<f32>()) as u64,
+                    usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            }),
+            bind_group: None,
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn instances(&self) -> &Buffer {
+        &self.instances
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Binds the instances for [`InstanceStorage::StorageBuffer`](crate::InstanceStorage).
+    pub fn bind_group(&self) -> Option<&BindGroup> {
+        self.bind_group.as_ref().map(|(_, bind_group)| bind_group)
+    }
+}
+
+#[derive(Default)]
+struct LodInstancesPool {
+    lod_instances: Vec<LodInstances>,
+    used: usize,
+}
+
+/// Instances of every level entity selected for every view this frame.
+///
+/// Buffers are pooled per entity and level and reused across frames, since level entities and
+/// shadow views are spawned anew every frame.
+#[derive(Resource, Default)]
+pub struct LodInstanceBuffers {
+    pools: HashMap<(Entity, usize), LodInstancesPool>,
+    views: HashMap<(Entity, Entity), ((Entity, usize), usize)>,
+}
+
+impl LodInstanceBuffers {
+    /// The instances the level entity `entity` draws in `view`, if it is a level entity.
+    pub fn get(&self, view: Entity, entity: Entity) -> Option<&LodInstances> {
+        let (pool, index) = self.views.get(&(view, entity))?;
+        self.pools.get(pool)?.lod_instances.get(*index)
+    }
+}
+
+/// Instances of one level and their fades, gathered on the CPU.
+#[derive(Default)]
+struct LodBucket {
+    instances: Vec<u8>,
+    lod_fades: Vec<f32>,
+}
+
+impl LodBucket {
+    /// Adds an instance faded in to `fade`, see [`lod_fades`].
+    fn push<I: InstanceData>(&mut self, instance: &I, fade: f32) {
+        self.instances
+            .extend_from_slice(bytemuck::bytes_of(instance));
+        self.lod_fades.push(fade);
+    }
+}
+
+/// The levels an instance at `distance` is drawn with and their fades.
+///
+/// A fade of `f >= 0` keeps the fragments whose dither threshold is below `f`, and `f < 0` the
+/// fragments whose threshold is at least `1 + f`, so both levels of an instance cross-fading from
+/// `t = 0` to `t = 1` cover complementary fragments with fades `1 - t` and `-t`.
+fn lod_fades(
+    distance: f32,
+    max_distances: &[f32],
+    cross_fade: f32,
+) -> impl Iterator<Item = (usize, f32)> {
+    let levels = max_distances.len();
+    // The switch distance the instance is cross-faded around, if any.
+    let switch = max_distances
+        .iter()
+        .position(|switch| (distance - switch).abs() < 0.5 * cross_fade);
+    let (near, t) = match switch {
+        Some(level) => (level, fade_t(distance, max_distances[level], cross_fade)),
+        None => (
+            max_distances
+                .iter()
+                .position(|max_distance| distance < *max_distance)
+                .unwrap_or(levels),
+            0.0,
+        ),
+    };
+    // Past the last level, instances fade out without a next level.
+    let far = switch
+        .map(|level| (level + 1, -t))
+        .filter(|(far, _)| *far < levels);
+    std::iter::once((near, 1.0 - t))
+        .filter(move |(near, _)| *near < levels)
+        .chain(far)
+}
+
+/// How far an instance at `distance` is through the cross-fade around `switch`.
+fn fade_t(distance: f32, switch: f32, cross_fade: f32) -> f32 {
+    ((distance - switch) / cross_fade + 0.5).clamp(0.0, 1.0)
+}
+
+/// Sorts the instances of every entity with [`InstancedLods`] and the material `M` into its
+/// levels by their distance to each camera, and uploads them for the camera and its shadow
+/// views.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prepare_instanced_lods<M: Material, I: InstanceData>(
+    mut lod_instance_buffers: ResMut<LodInstanceBuffers>,
+    mut buckets: Local<Vec<LodBucket>>,
+    mut order: Local<Vec<usize>>,
+    lod_entities: Res<InstancedLodEntities>,
+    instance_bind_group_layout: Res<InstanceBindGroupLayout>,
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+    render_instances: Res<RenderInstances<I>>,
+    instance_buffers: Res<InstanceBuffers>,
+    materials: Query<(), With<Handle<M>>>,
+    sorted_sources: Query<(Option<&GpuInstanceSorting>, Option<&GpuFrontToBackSorting>)>,
+    views: Query<(Entity, &ExtractedView, &ViewLightEntities)>,
+) {
+    let stride = std::mem::size_of::<I>() as u64;
+    let LodInstanceBuffers {
+        pools,
+        views: lod_views,
+    } = &mut *lod_instance_buffers;
+
+    for (source, lods) in &lod_entities.lods {
+        if !materials.contains(*source) {
+            continue;
+        }
+        let Some(instances) = render_instances.get(source) else {
+            continue;
+        };
+        let bindings = instance_buffers
+            .get(source)
+            .and_then(|instance_buffer| instance_buffer.bindings.as_ref());
+
+        let position = |instance: &I| {
+            lods.transform
+                .transform_point3(instance.transform().translation.into())
+        };
+
+        for (view_entity, view, view_lights) in &views {
+            let origin = view.transform.translation();
+            // Sorted like `GpuInstanceSorting` and `GpuFrontToBackSorting` sort instances without
+            // levels, though every frame.
+            order.clear();
+            order.extend(0..instances.len());
+            match sorted_sources.get(*source) {
+                Ok((Some(_), _)) => {
+                    let view_depth = view.transform.compute_matrix().inverse().row(2);
+                    order.sort_by_cached_key(|index| {
+                        FloatOrd(view_depth.dot(position(&instances[*index]).extend(1.0)))
+                    });
+                }
+                Ok((None, Some(front_to_back))) => {
+                    let bucket_size = front_to_back.bucket_size.max(f32::EPSILON);
+                    order.sort_by_cached_key(|index| {
+                        (position(&instances[*index]).distance(origin) / bucket_size) as u32
+                    });
+                }
+                _ => {}
+            }
+
+            buckets.resize_with(lods.levels.len(), LodBucket::default);
+            for instance in order.iter().map(|index| &instances[*index]) {
+                let position = position(instance);
+                let distance = position.distance(origin);
+                for (level, fade) in lod_fades(distance, &lods.max_distances, lods.cross_fade) {
+                    buckets[level].push(instance, fade);
+                }
+            }
+
+            for (level, bucket) in buckets.iter_mut().enumerate() {
+                let key = (*source, level);
+                let pool = pools.entry(key).or_default();
+                let len = bucket.lod_fades.len();
+                let capacity = len.max(1).next_power_of_two();
+                if pool.used == pool.lod_instances.len() {
+                    pool.lod_instances.push(LodInstances::new(
+                        &render_device,
+                        &render_adapter,
+                        stride,
+                        capacity,
+                    ));
+                }
+                let lod_instances = &mut pool.lod_instances[pool.used];
+                if lod_instances.capacity < len {
+                    *lod_instances =
+                        LodInstances::new(&render_device, &render_adapter, stride, capacity);
+                }
+                let level_entity = lods.levels[level];
+                for view in std::iter::once(view_entity).chain(view_lights.lights.iter().copied()) {
+                    lod_views.insert((view, level_entity), (key, pool.used));
+                }
+                pool.used += 1;
+
+                lod_instances.len = len as u32;
+                if len > 0 {
+                    render_queue.write_buffer(&lod_instances.instances, 0, &bucket.instances);
+                    if let Some(lod_fades) = &lod_instances.lod_fades {
+                        render_queue.write_buffer(
+                            lod_fades,
+                            0,
+                            bytemuck::cast_slice(&bucket.lod_fades),
+                        );
+                    }
+                }
+                bucket.instances.clear();
+                bucket.lod_fades.clear();
+
+                if let (Some(layout), Some(bindings), Some(lod_fades)) = (
+                    &**instance_bind_group_layout,
+                    bindings,
+                    &lod_instances.lod_fades,
+                ) {
+                    let bindings = InstanceBindings {
+                        lod_fades: lod_fades.clone(),
+                        ..bindings.clone()
+                    };
+                    let key = bindings.key();
+                    if !matches!(&lod_instances.bind_group, Some((bound, _)) if *bound == key) {
+                        let bind_group = instance_bind_group(
+                            &render_device,
+                            layout,
+                            &lod_instances.instances,
+                            &bindings,
+                        );
+                        lod_instances.bind_group = Some((key, bind_group));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cleanup_instanced_lods(
+    mut lod_entities: ResMut<InstancedLodEntities>,
+    mut lod_instance_buffers: ResMut<LodInstanceBuffers>,
+) {
+    lod_entities.lods.clear();
+    lod_entities.sources.clear();
+    lod_instance_buffers.views.clear();
+    lod_instance_buffers.pools.retain(|_, pool| {
+        pool.lod_instances.truncate(pool.used);
+        pool.used = 0;
+        !pool.lod_instances.is_empty()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fades(distance: f32, max_distances: &[f32], cross_fade: f32) -> Vec<(usize, f32)> {
+        lod_fades(distance, max_distances, cross_fade).collect()
+    }
+
+    #[test]
+    fn lod_fades_without_cross_fade_pick_one_level() {
+        let max_distances = [10.0, 20.0, 40.0];
+        assert_eq!(fades(0.0, &max_distances, 0.0), [(0, 1.0)]);
+        assert_eq!(fades(10.0, &max_distances, 0.0), [(1, 1.0)]);
+        assert_eq!(fades(39.9, &max_distances, 0.0), [(2, 1.0)]);
+        assert_eq!(fades(40.0, &max_distances, 0.0), []);
+    }
+
+    #[test]
+    fn lod_fades_cross_fade_around_switches() {
+        let max_distances = [10.0, 20.0, 40.0];
+        // Outside the cross-fades, which exclude their ends.
+        assert_eq!(fades(8.0, &max_distances, 4.0), [(0, 1.0)]);
+        assert_eq!(fades(15.0, &max_distances, 4.0), [(1, 1.0)]);
+        // Into the cross-fade between the first two levels.
+        assert_eq!(fades(9.0, &max_distances, 4.0), [(0, 0.75), (1, -0.25)]);
+        assert_eq!(fades(10.0, &max_distances, 4.0), [(0, 0.5), (1, -0.5)]);
+        assert_eq!(fades(11.0, &max_distances, 4.0), [(0, 0.25), (1, -0.75)]);
+        assert_eq!(fades(12.0, &max_distances, 4.0), [(1, 1.0)]);
+    }
+
+    #[test]
+    fn lod_fades_fade_out_past_the_last_level() {
+        let max_distances = [10.0, 20.0];
+        assert_eq!(fades(19.0, &max_distances, 4.0), [(1, 0.75)]);
+        assert_eq!(fades(20.0, &max_distances, 4.0), [(1, 0.5)]);
+        assert_eq!(fades(21.0, &max_distances, 4.0), [(1, 0.25)]);
+        assert_eq!(fades(22.0, &max_distances, 4.0), []);
+        assert_eq!(fades(0.0, &[], 4.0), []);
+    }
+
+    #[test]
+    fn lod_fades_of_cross_fading_levels_are_complementary() {
+        let max_distances = [10.0, 20.0];
+        for step in 1..40 {
+            let distance = 8.0 + step as f32 * 0.1;
+            let fades = fades(distance, &max_distances, 4.0);
+            let [(near, near_fade), (far, far_fade)] = fades[..] else {
+                panic!("{distance} is drawn with {fades:?}");
+            };
+            assert_eq!(far, near + 1);
+            assert!(near_fade > 0.0 && far_fade < 0.0);
+            // The near level keeps thresholds below `near_fade`, the far one from `1 + far_fade`.
+            assert!((near_fade - (1.0 + far_fade)).abs() < 1e-6);
+        }
+    }
+}
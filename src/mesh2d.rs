@@ -0,0 +1,363 @@
+use std::{hash::Hash, marker::PhantomData};
+
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::{
+        core_2d::Transparent2d,
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, DrawFunctionId, DrawFunctions, PhaseItem, RenderCommand,
+            RenderCommandResult, RenderPhase, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::{RenderAdapter, RenderDevice},
+        view::{ExtractedView, VisibleEntities},
+        RenderApp, RenderSet,
+    },
+    sprite::{
+        Material2d, Material2dKey, Material2dPipeline, Mesh2dHandle, Mesh2dPipelineKey,
+        Mesh2dUniform, RenderMaterials2d, SetMaterial2dBindGroup, SetMesh2dBindGroup,
+        SetMesh2dViewBindGroup,
+    },
+    utils::FloatOrd,
+};
+
+use crate::{
+    cpu_culling::CpuCulledInstanceBuffers,
+    culling::CulledInstanceBuffers,
+    lod::LodInstanceBuffers,
+    pipeline::{
+        draw_instances, push_instance_layout, view_instances, InstanceBindGroupLayout,
+        SetInstanceBindGroup, INSTANCED_MESH2D_SHADER_HANDLE,
+    },
+    sorting::SortedInstanceBuffers,
+    specialization::{add_specialization_error_asset, SpecializationErrors},
+    Instance, InstanceBuffers, InstanceData, InstanceStorage, InstancesPlugin, RenderInstances,
+};
+
+/// Draws 2D meshes with [`Instances`](crate::Instances) of `I` using the 2D material `M`.
+///
+/// Instanced entities are queued in the [`Transparent2d`] phase by the z of their transform,
+/// like bevy's `Material2dPlugin`, and specialized with the [`Mesh2dPipelineKey`] of the view.
+/// Instance animations, culling, indirect instances, levels of detail and sorting do not apply.
+///
+/// The per-instance inputs reach the fragment shader of `M` in `instance_vertex_output`, and
+/// only the built-in fragment shader used by materials without one applies the instance color.
+pub struct InstancedMesh2dMaterialPipelinePlugin<M, I = Instance> {
+    pub instance_storage: InstanceStorage,
+    marker: PhantomData<(M, I)>,
+}
+
+impl<M, I> Default for InstancedMesh2dMaterialPipelinePlugin<M, I> {
+    fn default() -> Self {
+        Self {
+            instance_storage: InstanceStorage::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M, I> Plugin for InstancedMesh2dMaterialPipelinePlugin<M, I>
+where
+    M: Material2d,
+    M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
+{
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCED_MESH2D_SHADER_HANDLE,
+            "instanced_mesh2d.wgsl",
+            Shader::from_wgsl
+        );
+
+        if !app.is_plugin_added::<InstancesPlugin<I>>() {
+            app.add_plugin(InstancesPlugin::<I>::default());
+        }
+        add_specialization_error_asset::<M>(app);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        let instance_storage = self.instance_storage.resolve::<I>(
+            render_app.world.resource::<RenderDevice>(),
+            render_app.world.resource::<RenderAdapter>(),
+        );
+        let instanced_mesh2d_material_pipeline =
+            InstancedMesh2dMaterialPipeline::<M, I>::new(&mut render_app.world, instance_storage);
+
+        render_app
+            .add_render_command::<Transparent2d, DrawMesh2dInstancedWithMaterial<M>>()
+            .add_render_command::<Transparent2d, DrawMesh2dStorageInstancedWithMaterial<M>>()
+            .insert_resource(instanced_mesh2d_material_pipeline)
+            .init_resource::<SpecializedMeshPipelines<InstancedMesh2dMaterialPipeline<M, I>>>()
+            .add_system(queue_instanced_mesh2d_with_material::<M, I>.in_set(RenderSet::Queue));
+    }
+}
+
+#[derive(Resource)]
+pub struct InstancedMesh2dMaterialPipeline<M: Material2d, I: InstanceData = Instance> {
+    pub material2d_pipeline: Material2dPipeline<M>,
+    pub instance_storage: InstanceStorage,
+    pub instance_layout: Option<BindGroupLayout>,
+    marker: PhantomData<I>,
+}
+
+impl<M, I> InstancedMesh2dMaterialPipeline<M, I>
+where
+    M: Material2d,
+    I: InstanceData,
+{
+    /// Expects `instance_storage` to be resolved for the render device already.
+    pub fn new(world: &mut World, instance_storage: InstanceStorage) -> Self {
+        world.init_resource::<InstanceBindGroupLayout>();
+        let instance_layout = world.resource::<InstanceBindGroupLayout>().0.clone();
+
+        let asset_server = world.resource::<AssetServer>();
+        let vertex_shader = match I::mesh2d_vertex_shader() {
+            ShaderRef::Default => INSTANCED_MESH2D_SHADER_HANDLE.typed(),
+            ShaderRef::Handle(handle) => handle,
+            ShaderRef::Path(path) => asset_server.load(path),
+        };
+
+        let mut material2d_pipeline = Material2dPipeline::<M>::from_world(world);
+        material2d_pipeline.vertex_shader = Some(vertex_shader);
+        // Materials without a fragment shader of their own draw the vertex colors tinted by the
+        // instance colors, which other fragment shaders read themselves.
+        if material2d_pipeline.fragment_shader.is_none() {
+            material2d_pipeline.fragment_shader = Some(INSTANCED_MESH2D_SHADER_HANDLE.typed());
+        }
+
+        Self {
+            material2d_pipeline,
+            instance_storage,
+            instance_layout,
+            marker: PhantomData,
+        }
+    }
+
+    /// The draw function of the pipeline's [`InstanceStorage`] in `draw_functions`.
+    pub fn draw_function<P: PhaseItem>(&self, draw_functions: &DrawFunctions<P>) -> DrawFunctionId {
+        let draw_functions = draw_functions.read();
+        match self.instance_storage {
+            InstanceStorage::VertexBuffer => {
+                draw_functions.id::<DrawMesh2dInstancedWithMaterial<M>>()
+            }
+            InstanceStorage::StorageBuffer => {
+                draw_functions.id::<DrawMesh2dStorageInstancedWithMaterial<M>>()
+            }
+        }
+    }
+}
+
+impl<M, I> FromWorld for InstancedMesh2dMaterialPipeline<M, I>
+where
+    M: Material2d,
+    I: InstanceData,
+{
+    fn from_world(world: &mut World) -> Self {
+        Self::new(world, InstanceStorage::VertexBuffer)
+    }
+}
+
+impl<M: Material2d, I: InstanceData> SpecializedMeshPipeline
+    for InstancedMesh2dMaterialPipeline<M, I>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = Material2dKey<M>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.material2d_pipeline.specialize(key, layout)?;
+        push_instance_layout::<I>(
+            &mut descriptor,
+            self.instance_storage,
+            self.instance_layout.as_ref(),
+        );
+
+        Ok(descriptor)
+    }
+}
+
+pub type DrawMesh2dInstancedWithMaterial<M> = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    SetMaterial2dBindGroup<M, 1>,
+    SetMesh2dBindGroup<2>,
+    DrawMesh2dInstanced,
+);
+
+pub type DrawMesh2dStorageInstancedWithMaterial<M> = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    SetMaterial2dBindGroup<M, 1>,
+    SetMesh2dBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    DrawMesh2dInstanced,
+);
+
+/// Draws the instances of a 2D mesh, like
+/// [`DrawMeshInstanced`](crate::pipeline::DrawMeshInstanced).
+pub struct DrawMesh2dInstanced;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawMesh2dInstanced {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<InstanceBuffers>,
+        SRes<CulledInstanceBuffers>,
+        SRes<CpuCulledInstanceBuffers>,
+        SRes<SortedInstanceBuffers>,
+        SRes<LodInstanceBuffers>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = Read<Mesh2dHandle>;
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view: Entity,
+        mesh2d_handle: &'w Mesh2dHandle,
+        (
+            meshes,
+            instance_buffers,
+            culled_instance_buffers,
+            cpu_culled_instance_buffers,
+            sorted_instance_buffers,
+            lod_instance_buffers,
+        ): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(gpu_mesh) = meshes.into_inner().get(&mesh2d_handle.0) else {
+            return RenderCommandResult::Failure;
+        };
+        let Some(view_instances) = view_instances(
+            view,
+            item.entity(),
+            instance_buffers.into_inner(),
+            culled_instance_buffers.into_inner(),
+            cpu_culled_instance_buffers.into_inner(),
+            sorted_instance_buffers.into_inner(),
+            lod_instance_buffers.into_inner(),
+        ) else {
+            return RenderCommandResult::Failure;
+        };
+
+        draw_instances(gpu_mesh, view_instances, pass);
+        RenderCommandResult::Success
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_instanced_mesh2d_with_material<M, I>(
+    transparent_draw_functions: Res<DrawFunctions<Transparent2d>>,
+    instanced_mesh2d_material_pipeline: Res<InstancedMesh2dMaterialPipeline<M, I>>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedMesh2dMaterialPipeline<M, I>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials2d<M>>,
+    render_instances: Res<RenderInstances<I>>,
+    specialization_errors: Res<SpecializationErrors>,
+    instanced_mesh2d_with_material: Query<(&Mesh2dUniform, &Mesh2dHandle, &Handle<M>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        &mut RenderPhase<Transparent2d>,
+    )>,
+) where
+    M: Material2d,
+    M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
+{
+    let draw_instanced_mesh2d_with_material =
+        instanced_mesh2d_material_pipeline.draw_function(&transparent_draw_functions);
+
+    let msaa_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (view, visible_entities, tonemapping, dither, mut transparent_phase) in &mut views {
+        let mut view_key = msaa_key | Mesh2dPipelineKey::from_hdr(view.hdr);
+        // Mirrors bevy's `queue_material2d_meshes`.
+        if !view.hdr {
+            if let Some(tonemapping) = tonemapping {
+                view_key |= Mesh2dPipelineKey::TONEMAP_IN_SHADER;
+                view_key |= match tonemapping {
+                    Tonemapping::None => Mesh2dPipelineKey::TONEMAP_METHOD_NONE,
+                    Tonemapping::Reinhard => Mesh2dPipelineKey::TONEMAP_METHOD_REINHARD,
+                    Tonemapping::ReinhardLuminance => {
+                        Mesh2dPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE
+                    }
+                    Tonemapping::AcesFitted => Mesh2dPipelineKey::TONEMAP_METHOD_ACES_FITTED,
+                    Tonemapping::AgX => Mesh2dPipelineKey::TONEMAP_METHOD_AGX,
+                    Tonemapping::SomewhatBoringDisplayTransform => {
+                        Mesh2dPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM
+                    }
+                    Tonemapping::TonyMcMapface => Mesh2dPipelineKey::TONEMAP_METHOD_TONY_MC_MAPFACE,
+                    Tonemapping::BlenderFilmic => Mesh2dPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC,
+                };
+            }
+            if let Some(DebandDither::Enabled) = dither {
+                view_key |= Mesh2dPipelineKey::DEBAND_DITHER;
+            }
+        }
+
+        for &entity in &visible_entities.entities {
+            let Ok((mesh2d_uniform, mesh2d_handle, material_handle)) =
+                instanced_mesh2d_with_material.get(entity)
+            else {
+                continue;
+            };
+            if !render_instances.contains_key(&entity) {
+                continue;
+            }
+
+            if let (Some(mesh), Some(material)) = (
+                render_meshes.get(&mesh2d_handle.0),
+                render_materials.get(material_handle),
+            ) {
+                let mesh_key =
+                    view_key | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology);
+
+                let pipeline = match pipelines.specialize(
+                    &pipeline_cache,
+                    &instanced_mesh2d_material_pipeline,
+                    Material2dKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(error) => {
+                        specialization_errors.report(
+                            entity,
+                            &mesh2d_handle.0,
+                            material_handle.id(),
+                            error,
+                        );
+                        continue;
+                    }
+                };
+
+                // Views look down -z, so the z of the mesh orders it back to front.
+                let mesh_z = mesh2d_uniform.transform.w_axis.z;
+                transparent_phase.add(Transparent2d {
+                    entity,
+                    draw_function: draw_instanced_mesh2d_with_material,
+                    pipeline,
+                    sort_key: FloatOrd(mesh_z),
+                    batch_range: None,
+                });
+            }
+        }
+    }
+}
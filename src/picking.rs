@@ -0,0 +1,991 @@
+use std::{
+    cmp::Reverse,
+    marker::PhantomData,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::core_3d,
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    pbr::{
+        MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, MAX_CASCADES_PER_LIGHT,
+        MAX_DIRECTIONAL_LIGHTS,
+    },
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        mesh::{GpuMesh, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::{
+            sort_phase_system, AddRenderCommand, CachedRenderPipelinePhaseItem, DrawFunctionId,
+            DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::{RenderAdapter, RenderContext, RenderDevice, RenderQueue},
+        texture::{CachedTexture, TextureCache},
+        view::{ExtractedView, ViewUniform, ViewUniformOffset, ViewUniforms, VisibleEntities},
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::{FloatOrd, HashMap, HashSet},
+};
+
+use crate::{
+    auto_instance::AutoInstancedEntities,
+    pipeline::{
+        draw_instances, push_instance_layout, InstanceBindGroupLayout,
+        INSTANCED_PREPASS_SHADER_HANDLE, INSTANCE_PICKING_SHADER_HANDLE,
+    },
+    specialization::SpecializationErrors,
+    Instance, InstanceBuffer, InstanceBuffers, InstanceData, InstanceStorage, InstancesPlugin,
+    RenderInstances,
+};
+
+pub const INSTANCE_PICKING: &str = "instance_picking";
+
+const PICKING_FORMAT: TextureFormat = TextureFormat::Rgba32Uint;
+const PICKING_DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+/// Size of one texel of [`PICKING_FORMAT`].
+const PICKING_TEXEL_SIZE: u64 = 16;
+
+/// Renders the instanced entities seen by a 3D camera into an ID texture, which [`PickInstance`]
+/// reads back from.
+///
+/// Every instance is drawn with the entity's own mesh and the vertex shader of its prepass, at
+/// the full resolution of the camera's viewport, so picking costs about as much as a depth
+/// prepass. Levels of detail are picked against the entity's mesh, and culling and sorting do
+/// not apply. The pass only renders in frames with a [`PickInstance`] for the camera.
+///
+/// Materials with their own prepass vertex shader must output the instance index as
+/// `@location(3) @interpolate(flat) instance_index: u32` when the `INSTANCE_PICKING` shader def
+/// is set, like the instanced prepass shader does, or their instances cannot be picked.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct InstancePicking;
+
+/// Requests the instance under `position` in the viewport of `camera`, a camera with
+/// [`InstancePicking`]. Answered by an [`InstancePicked`] a few frames later, once the ID
+/// texture is read back from the GPU.
+#[derive(Clone, Copy, Debug)]
+pub struct PickInstance {
+    pub camera: Entity,
+    /// In physical pixels from the top left corner of the camera's viewport.
+    pub position: UVec2,
+}
+
+/// The answer to a [`PickInstance`].
+#[derive(Clone, Copy, Debug)]
+pub struct InstancePicked {
+    pub camera: Entity,
+    pub position: UVec2,
+    /// The nearest instance at `position`, or `None` if none was drawn there.
+    pub hit: Option<InstanceHit>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstanceHit {
+    pub entity: Entity,
+    /// Index in the entity's [`Instances`](crate::Instances). Always 0 for entities batched
+    /// with [`AutoInstance`](crate::AutoInstance), which are reported themselves.
+    pub instance_index: u32,
+}
+
+#[derive(Default)]
+struct PickingRequests {
+    requested: Vec<PickInstance>,
+    picked: Vec<InstancePicked>,
+}
+
+/// Picking requests and their answers, shared by the main and render worlds.
+#[derive(Resource, Clone, Default)]
+struct InstancePickingRequests(Arc<Mutex<PickingRequests>>);
+
+impl InstancePickingRequests {
+    fn answer(&self, picked: InstancePicked) {
+        if let Ok(mut requests) = self.0.lock() {
+            requests.picked.push(picked);
+        }
+    }
+}
+
+/// Picks instances of `I` with [`InstancePicking`].
+pub struct InstancePickingPlugin<I = Instance> {
+    pub instance_storage: InstanceStorage,
+    marker: PhantomData<I>,
+}
+
+impl<I> Default for InstancePickingPlugin<I> {
+    fn default() -> Self {
+        Self {
+            instance_storage: InstanceStorage::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<I: InstanceData> Plugin for InstancePickingPlugin<I> {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<InstancesPlugin<I>>() {
+            app.add_plugin(InstancesPlugin::<I>::default());
+        }
+        if !app.is_plugin_added::<InstancePickingPassPlugin>() {
+            app.add_plugin(InstancePickingPassPlugin);
+        }
+
+        let render_app = app.sub_app_mut(RenderApp);
+        let instance_storage = self.instance_storage.resolve::<I>(
+            render_app.world.resource::<RenderDevice>(),
+            render_app.world.resource::<RenderAdapter>(),
+        );
+        let instance_picking_pipeline =
+            InstancePickingPipeline::<I>::new(&mut render_app.world, instance_storage);
+
+        render_app
+            .insert_resource(instance_picking_pipeline)
+            .init_resource::<SpecializedMeshPipelines<InstancePickingPipeline<I>>>()
+            .add_system(
+                queue_instance_picking::<I>
+                    .in_set(RenderSet::Queue)
+                    .in_set(QueueInstancePicking),
+            );
+    }
+}
+
+/// Adds the picking pass shared by every [`InstancePickingPlugin`]. Added by
+/// [`InstancePickingPlugin`].
+pub struct InstancePickingPassPlugin;
+
+impl Plugin for InstancePickingPassPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_PICKING_SHADER_HANDLE,
+            "instance_picking.wgsl",
+            Shader::from_wgsl
+        );
+
+        let requests = InstancePickingRequests::default();
+        app.add_event::<PickInstance>()
+            .add_event::<InstancePicked>()
+            .insert_resource(requests.clone())
+            .add_system(exchange_instance_picks);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(requests)
+            .init_resource::<InstancePickingLayouts>()
+            .init_resource::<InstancePickingBindGroups>()
+            .init_resource::<ExtractedInstancePicks>()
+            .init_resource::<DrawFunctions<InstancePicking3d>>()
+            .add_render_command::<InstancePicking3d, DrawInstancePicking>()
+            .add_render_command::<InstancePicking3d, DrawStorageInstancePicking>()
+            .add_system(extract_instance_picking.in_schedule(ExtractSchedule))
+            .add_system(prepare_instance_picking.in_set(RenderSet::Prepare))
+            .add_system(
+                queue_instance_picking_bind_groups
+                    .in_set(RenderSet::Queue)
+                    .after(QueueInstancePicking),
+            )
+            .add_system(sort_phase_system::<InstancePicking3d>.in_set(RenderSet::PhaseSort))
+            .add_system(read_back_instance_picks.in_set(RenderSet::Cleanup));
+
+        let instance_picking_node = InstancePickingNode::new(&mut render_app.world);
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        let Some(draw_3d_graph) = render_graph.get_sub_graph_mut(core_3d::graph::NAME) else {
+            warn!("Instance picking needs the 3D render graph of bevy's core pipeline");
+            return;
+        };
+        draw_3d_graph.add_node(INSTANCE_PICKING, instance_picking_node);
+        let input_node_id = draw_3d_graph.input_node().id;
+        draw_3d_graph.add_slot_edge(
+            input_node_id,
+            core_3d::graph::input::VIEW_ENTITY,
+            INSTANCE_PICKING,
+            InstancePickingNode::IN_VIEW,
+        );
+        draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, INSTANCE_PICKING);
+    }
+}
+
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct QueueInstancePicking;
+
+/// Passes the [`PickInstance`]s of the frame to the render world and sends the
+/// [`InstancePicked`]s read back since.
+fn exchange_instance_picks(
+    requests: Res<InstancePickingRequests>,
+    mut pick_instances: EventReader<PickInstance>,
+    mut instances_picked: EventWriter<InstancePicked>,
+) {
+    if let Ok(mut requests) = requests.0.lock() {
+        requests.requested.extend(pick_instances.iter().copied());
+        instances_picked.send_batch(requests.picked.drain(..));
+    }
+}
+
+/// A phase of the picking pass, sorted front to back.
+pub struct InstancePicking3d {
+    pub distance: f32,
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+}
+
+impl PhaseItem for InstancePicking3d {
+    type SortKey = Reverse<FloatOrd>;
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        Reverse(FloatOrd(self.distance))
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for InstancePicking3d {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+/// The ID and depth textures of a camera with [`InstancePicking`], and the picks read back from
+/// them this frame.
+#[derive(Component)]
+pub struct ViewInstancePicking {
+    pub texture: CachedTexture,
+    pub depth: CachedTexture,
+    pub size: UVec2,
+    readbacks: Vec<InstancePickReadback>,
+}
+
+/// A pick and the buffer its texel is copied to.
+struct InstancePickReadback {
+    pick: PickInstance,
+    buffer: Buffer,
+    /// The entities of the batches drawn this frame, to report batched entities themselves.
+    batch_members: Arc<HashMap<Entity, Vec<Entity>>>,
+}
+
+#[allow(clippy::type_complexity)]
+fn extract_instance_picking(
+    mut commands: Commands,
+    requests: Res<InstancePickingRequests>,
+    mut extracted_picks: ResMut<ExtractedInstancePicks>,
+    cameras: Extract<Query<(Entity, &Camera), (With<Camera3d>, With<InstancePicking>)>>,
+) {
+    if let Ok(mut requests) = requests.0.lock() {
+        extracted_picks.0.append(&mut requests.requested);
+    }
+    // Only cameras with picks to answer render the picking pass.
+    for (entity, camera) in &cameras {
+        if camera.is_active && extracted_picks.0.iter().any(|pick| pick.camera == entity) {
+            commands
+                .get_or_spawn(entity)
+                .insert((InstancePicking, RenderPhase::<InstancePicking3d>::default()));
+        }
+    }
+}
+
+/// The [`PickInstance`]s to answer this frame.
+#[derive(Resource, Default)]
+struct ExtractedInstancePicks(Vec<PickInstance>);
+
+/// Allocates the picking textures of each camera with [`InstancePicking`] and picks to answer,
+/// and a buffer to read each pick back into.
+fn prepare_instance_picking(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    mut extracted_picks: ResMut<ExtractedInstancePicks>,
+    requests: Res<InstancePickingRequests>,
+    auto_instanced: Res<AutoInstancedEntities>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera), With<RenderPhase<InstancePicking3d>>>,
+) {
+    let mut picks = std::mem::take(&mut extracted_picks.0);
+    let batch_members = if picks.is_empty() {
+        Arc::default()
+    } else {
+        Arc::new(auto_instanced.batch_members())
+    };
+
+    for (entity, camera) in &views {
+        let Some(size) = camera.physical_viewport_size else {
+            continue;
+        };
+        let mut readbacks = Vec::new();
+        picks.retain(|pick| {
+            if pick.camera != entity {
+                return true;
+            }
+            if pick.position.cmplt(size).all() {
+                readbacks.push(InstancePickReadback {
+                    pick: *pick,
+                    buffer: render_device.create_buffer(&BufferDescriptor {
+                        label: Some("instance_picking_readback_buffer"),
+                        size: PICKING_TEXEL_SIZE,
+                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                        mapped_at_creation: false,
+                    }),
+                    batch_members: batch_members.clone(),
+                });
+            } else {
+                requests.answer(InstancePicked {
+                    camera: pick.camera,
+                    position: pick.position,
+                    hit: None,
+                });
+            }
+            false
+        });
+
+        if readbacks.is_empty() {
+            continue;
+        }
+
+        let extent = Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        };
+        let mut texture_descriptor = |label, format, usage| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: extent,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format,
+                    usage,
+                    view_formats: &[],
+                },
+            )
+        };
+        let texture = texture_descriptor(
+            "instance_picking_texture",
+            PICKING_FORMAT,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        );
+        let depth = texture_descriptor(
+            "instance_picking_depth_texture",
+            PICKING_DEPTH_FORMAT,
+            TextureUsages::RENDER_ATTACHMENT,
+        );
+
+        commands.entity(entity).insert(ViewInstancePicking {
+            texture,
+            depth,
+            size,
+            readbacks,
+        });
+    }
+
+    // Picks of cameras without picking, or not rendering this frame, hit nothing.
+    for pick in picks {
+        requests.answer(InstancePicked {
+            camera: pick.camera,
+            position: pick.position,
+            hit: None,
+        });
+    }
+}
+
+/// Maps the picks copied this frame, answering them once the GPU is done with them.
+fn read_back_instance_picks(
+    requests: Res<InstancePickingRequests>,
+    mut views: Query<&mut ViewInstancePicking>,
+) {
+    for mut view_instance_picking in &mut views {
+        for readback in view_instance_picking.readbacks.drain(..) {
+            let requests = requests.clone();
+            let buffer = readback.buffer.clone();
+            readback
+                .buffer
+                .slice(..)
+                .map_async(MapMode::Read, move |result| {
+                    let hit = match result {
+                        Ok(()) => {
+                            let texel: [u32; 4] =
+                                bytemuck::pod_read_unaligned(&buffer.slice(..).get_mapped_range());
+                            buffer.unmap();
+                            instance_hit(texel, &readback.batch_members)
+                        }
+                        Err(_) => None,
+                    };
+                    requests.answer(InstancePicked {
+                        camera: readback.pick.camera,
+                        position: readback.pick.position,
+                        hit,
+                    });
+                });
+        }
+    }
+}
+
+/// The instance written to a texel of the ID texture.
+fn instance_hit(
+    [entity_low, entity_high, instance_index, drawn]: [u32; 4],
+    batch_members: &HashMap<Entity, Vec<Entity>>,
+) -> Option<InstanceHit> {
+    if drawn == 0 {
+        return None;
+    }
+    let entity = Entity::from_bits(u64::from(entity_low) | (u64::from(entity_high) << 32));
+    match batch_members.get(&entity) {
+        Some(members) => Some(InstanceHit {
+            entity: *members.get(instance_index as usize)?,
+            instance_index: 0,
+        }),
+        None => Some(InstanceHit {
+            entity,
+            instance_index,
+        }),
+    }
+}
+
+/// Layouts of the view and entity bind groups of the picking pass.
+#[derive(Resource)]
+pub struct InstancePickingLayouts {
+    pub view_layout: BindGroupLayout,
+    pub entity_layout: BindGroupLayout,
+}
+
+impl FromWorld for InstancePickingLayouts {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_picking_view_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(ViewUniform::min_size()),
+                },
+                count: None,
+            }],
+        });
+        let entity_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_picking_entity_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: NonZeroU64::new(8),
+                },
+                count: None,
+            }],
+        });
+
+        Self {
+            view_layout,
+            entity_layout,
+        }
+    }
+}
+
+/// Draws instances of `I` into the ID texture of the picking pass, with the prepass vertex
+/// shader of `I`.
+#[derive(Resource)]
+pub struct InstancePickingPipeline<I: InstanceData = Instance> {
+    pub view_layout: BindGroupLayout,
+    pub entity_layout: BindGroupLayout,
+    pub mesh_layout: BindGroupLayout,
+    pub skinned_mesh_layout: BindGroupLayout,
+    pub vertex_shader: Handle<Shader>,
+    pub instance_storage: InstanceStorage,
+    pub instance_layout: Option<BindGroupLayout>,
+    marker: PhantomData<I>,
+}
+
+impl<I: InstanceData> InstancePickingPipeline<I> {
+    /// Expects `instance_storage` to be resolved for the render device already.
+    pub fn new(world: &mut World, instance_storage: InstanceStorage) -> Self {
+        world.init_resource::<InstanceBindGroupLayout>();
+        world.init_resource::<InstancePickingLayouts>();
+        let instance_layout = world.resource::<InstanceBindGroupLayout>().0.clone();
+        let layouts = world.resource::<InstancePickingLayouts>();
+        let mesh_pipeline = world.resource::<MeshPipeline>();
+
+        let asset_server = world.resource::<AssetServer>();
+        let vertex_shader = match I::prepass_vertex_shader() {
+            ShaderRef::Default => INSTANCED_PREPASS_SHADER_HANDLE.typed(),
+            ShaderRef::Handle(handle) => handle,
+            ShaderRef::Path(path) => asset_server.load(path),
+        };
+
+        Self {
+            view_layout: layouts.view_layout.clone(),
+            entity_layout: layouts.entity_layout.clone(),
+            mesh_layout: mesh_pipeline.mesh_layout.clone(),
+            skinned_mesh_layout: mesh_pipeline.skinned_mesh_layout.clone(),
+            vertex_shader,
+            instance_storage,
+            instance_layout,
+            marker: PhantomData,
+        }
+    }
+
+    /// The draw function of the pipeline's [`InstanceStorage`] in `draw_functions`.
+    pub fn draw_function(
+        &self,
+        draw_functions: &DrawFunctions<InstancePicking3d>,
+    ) -> DrawFunctionId {
+        let draw_functions = draw_functions.read();
+        match self.instance_storage {
+            InstanceStorage::VertexBuffer => draw_functions.id::<DrawInstancePicking>(),
+            InstanceStorage::StorageBuffer => draw_functions.id::<DrawStorageInstancePicking>(),
+        }
+    }
+}
+
+impl<I: InstanceData> FromWorld for InstancePickingPipeline<I> {
+    fn from_world(world: &mut World) -> Self {
+        Self::new(world, InstanceStorage::VertexBuffer)
+    }
+}
+
+impl<I: InstanceData> SpecializedMeshPipeline for InstancePickingPipeline<I> {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut shader_defs = vec![
+            "INSTANCE_PICKING".into(),
+            "VERTEX_POSITIONS".into(),
+            ShaderDefVal::Int(
+                "MAX_DIRECTIONAL_LIGHTS".to_string(),
+                MAX_DIRECTIONAL_LIGHTS as i32,
+            ),
+            ShaderDefVal::Int(
+                "MAX_CASCADES_PER_LIGHT".to_string(),
+                MAX_CASCADES_PER_LIGHT as i32,
+            ),
+        ];
+        let mut vertex_attributes = vec![Mesh::ATTRIBUTE_POSITION.at_shader_location(0)];
+        let mesh_layout = if layout.contains(Mesh::ATTRIBUTE_JOINT_INDEX)
+            && layout.contains(Mesh::ATTRIBUTE_JOINT_WEIGHT)
+        {
+            shader_defs.push("SKINNED".into());
+            vertex_attributes.push(Mesh::ATTRIBUTE_JOINT_INDEX.at_shader_location(4));
+            vertex_attributes.push(Mesh::ATTRIBUTE_JOINT_WEIGHT.at_shader_location(5));
+            self.skinned_mesh_layout.clone()
+        } else {
+            self.mesh_layout.clone()
+        };
+
+        let mut descriptor = RenderPipelineDescriptor {
+            label: Some("instance_picking_pipeline".into()),
+            layout: vec![
+                self.view_layout.clone(),
+                self.entity_layout.clone(),
+                mesh_layout,
+            ],
+            push_constant_ranges: Vec::new(),
+            vertex: VertexState {
+                shader: self.vertex_shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "vertex".into(),
+                buffers: vec![layout.get_layout(&vertex_attributes)?],
+            },
+            fragment: Some(FragmentState {
+                shader: INSTANCE_PICKING_SHADER_HANDLE.typed(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: PICKING_FORMAT,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: key.primitive_topology(),
+                cull_mode: Some(Face::Back),
+                ..default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: PICKING_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+        };
+        push_instance_layout::<I>(
+            &mut descriptor,
+            self.instance_storage,
+            self.instance_layout.as_ref(),
+        );
+
+        Ok(descriptor)
+    }
+}
+
+/// Queues the visible entities with instances of `I` into the picking phase of each camera with
+/// [`InstancePicking`].
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_instance_picking<I: InstanceData>(
+    draw_functions: Res<DrawFunctions<InstancePicking3d>>,
+    instance_picking_pipeline: Res<InstancePickingPipeline<I>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancePickingPipeline<I>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_instances: Res<RenderInstances<I>>,
+    auto_instanced: Res<AutoInstancedEntities>,
+    mut queued_batches: Local<HashSet<Entity>>,
+    specialization_errors: Res<SpecializationErrors>,
+    instanced_meshes: Query<(&MeshUniform, &Handle<Mesh>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<InstancePicking3d>,
+    )>,
+) {
+    let draw_instance_picking = instance_picking_pipeline.draw_function(&draw_functions);
+
+    for (view, visible_entities, mut picking_phase) in &mut views {
+        let rangefinder = view.rangefinder3d();
+        // Entities with levels of detail are picked against their own mesh and instances.
+        for entity in auto_instanced.drawn_entities(&visible_entities.entities, &mut queued_batches)
+        {
+            if !render_instances.contains_key(&entity) {
+                continue;
+            }
+            let Ok((mesh_uniform, mesh_handle)) = instanced_meshes.get(entity) else {
+                continue;
+            };
+            let Some(mesh) = render_meshes.get(mesh_handle) else {
+                continue;
+            };
+
+            let pipeline = match pipelines.specialize(
+                &pipeline_cache,
+                &instance_picking_pipeline,
+                MeshPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                &mesh.layout,
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(error) => {
+                    specialization_errors.report(
+                        entity,
+                        mesh_handle,
+                        instance_picking_pipeline.vertex_shader.id(),
+                        error,
+                    );
+                    continue;
+                }
+            };
+
+            picking_phase.add(InstancePicking3d {
+                distance: rangefinder.distance(&mesh_uniform.transform),
+                entity,
+                pipeline,
+                draw_function: draw_instance_picking,
+            });
+        }
+    }
+}
+
+shader_uniform! {
+    struct InstancePickingUniform {
+        pub(super) entity: UVec2,
+    }
+}
+
+/// The view bind group of the picking pass, and the entity bind group with the offset of each
+/// entity queued for picking.
+#[derive(Resource, Default)]
+pub struct InstancePickingBindGroups {
+    view: Option<BindGroup>,
+    entity: Option<BindGroup>,
+    entity_uniforms: DynamicUniformBuffer<InstancePickingUniform>,
+    entity_offsets: HashMap<Entity, u32>,
+}
+
+/// Writes the entity of every item in the picking phases, and binds the view uniforms.
+fn queue_instance_picking_bind_groups(
+    mut bind_groups: ResMut<InstancePickingBindGroups>,
+    layouts: Res<InstancePickingLayouts>,
+    view_uniforms: Res<ViewUniforms>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    picking_phases: Query<&RenderPhase<InstancePicking3d>>,
+) {
+    let InstancePickingBindGroups {
+        view,
+        entity,
+        entity_uniforms,
+        entity_offsets,
+    } = &mut *bind_groups;
+    entity_uniforms.clear();
+    entity_offsets.clear();
+    for item in picking_phases.iter().flat_map(|phase| &phase.items) {
+        entity_offsets.entry(item.entity).or_insert_with(|| {
+            let bits = item.entity.to_bits();
+            entity_uniforms.push(InstancePickingUniform {
+                entity: UVec2::new(bits as u32, (bits >> 32) as u32),
+            })
+        });
+    }
+    if entity_offsets.is_empty() {
+        return;
+    }
+    entity_uniforms.write_buffer(&render_device, &render_queue);
+
+    *view = view_uniforms.uniforms.binding().map(|view_binding| {
+        render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("instance_picking_view_bind_group"),
+            layout: &layouts.view_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: view_binding,
+            }],
+        })
+    });
+    *entity = entity_uniforms.binding().map(|entity_binding| {
+        render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("instance_picking_entity_bind_group"),
+            layout: &layouts.entity_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: entity_binding,
+            }],
+        })
+    });
+}
+
+pub type DrawInstancePicking = (
+    SetItemPipeline,
+    SetInstancePickingBindGroups<0, 1>,
+    SetMeshBindGroup<2>,
+    DrawPickedInstances,
+);
+
+pub type DrawStorageInstancePicking = (
+    SetItemPipeline,
+    SetInstancePickingBindGroups<0, 1>,
+    SetMeshBindGroup<2>,
+    SetPickedInstanceBindGroup<3>,
+    DrawPickedInstances,
+);
+
+/// Binds the view and the entity drawn in the picking pass.
+pub struct SetInstancePickingBindGroups<const V: usize, const E: usize>;
+
+impl<P: PhaseItem, const V: usize, const E: usize> RenderCommand<P>
+    for SetInstancePickingBindGroups<V, E>
+{
+    type Param = SRes<InstancePickingBindGroups>;
+    type ViewWorldQuery = Read<ViewUniformOffset>;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view_uniform_offset: &ViewUniformOffset,
+        _item_query: (),
+        bind_groups: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let bind_groups = bind_groups.into_inner();
+        let (Some(view), Some(entity), Some(entity_offset)) = (
+            &bind_groups.view,
+            &bind_groups.entity,
+            bind_groups.entity_offsets.get(&item.entity()),
+        ) else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(V, view, &[view_uniform_offset.offset]);
+        pass.set_bind_group(E, entity, &[*entity_offset]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Binds all the instances of an entity for [`InstanceStorage::StorageBuffer`], whatever the
+/// view culled or sorted.
+pub struct SetPickedInstanceBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetPickedInstanceBindGroup<I> {
+    type Param = SRes<InstanceBuffers>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        _item_query: (),
+        instance_buffers: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(bind_group) = instance_buffers
+            .into_inner()
+            .get(&item.entity())
+            .and_then(InstanceBuffer::bind_group)
+        else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Draws all the instances of an entity, so the instance index written to the ID texture is
+/// their index in the entity's [`Instances`](crate::Instances).
+pub struct DrawPickedInstances;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawPickedInstances {
+    type Param = (SRes<RenderAssets<Mesh>>, SRes<InstanceBuffers>);
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<Handle<Mesh>>;
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        mesh_handle: &'w Handle<Mesh>,
+        (meshes, instance_buffers): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let (Some(gpu_mesh), Some(instance_buffer)) = (
+            meshes.into_inner().get(mesh_handle),
+            instance_buffers.into_inner().get(&item.entity()),
+        ) else {
+            return RenderCommandResult::Failure;
+        };
+
+        draw_picked_instances(gpu_mesh, instance_buffer, pass);
+        RenderCommandResult::Success
+    }
+}
+
+fn draw_picked_instances<'w>(
+    gpu_mesh: &'w GpuMesh,
+    instance_buffer: &'w InstanceBuffer,
+    pass: &mut TrackedRenderPass<'w>,
+) {
+    draw_instances(
+        gpu_mesh,
+        (
+            instance_buffer.buffer(),
+            instance_buffer.len() as u32,
+            instance_buffer.indirect(),
+        ),
+        pass,
+    );
+}
+
+/// Renders the picking phase of a camera with [`InstancePicking`] and copies the texels of its
+/// picks to their readback buffers.
+pub struct InstancePickingNode {
+    view_query: QueryState<
+        (
+            &'static RenderPhase<InstancePicking3d>,
+            &'static ViewInstancePicking,
+        ),
+        With<ExtractedView>,
+    >,
+}
+
+impl InstancePickingNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for InstancePickingNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((picking_phase, view_instance_picking)) =
+            self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if view_instance_picking.readbacks.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("instance_picking_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view_instance_picking.texture.default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::NONE.into()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &view_instance_picking.depth.default_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(0.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            picking_phase.render(&mut render_pass, world, view_entity);
+        }
+
+        for readback in &view_instance_picking.readbacks {
+            render_context.command_encoder().copy_texture_to_buffer(
+                ImageCopyTexture {
+                    texture: &view_instance_picking.texture.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: readback.pick.position.x,
+                        y: readback.pick.position.y,
+                        z: 0,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                ImageCopyBuffer {
+                    buffer: &readback.buffer,
+                    layout: ImageDataLayout::default(),
+                },
+                Extent3d::default(),
+            );
+        }
+
+        Ok(())
+    }
+}
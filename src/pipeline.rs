@@ -1,46 +1,133 @@
-use std::hash::Hash;
+use std::{hash::Hash, marker::PhantomData};
 
 use bevy::{
     ecs::system::{lifetimeless::*, SystemParamItem},
     pbr::{
-        MaterialPipeline, MaterialPipelineKey, SetMaterialBindGroup, SetMeshBindGroup,
-        SetMeshViewBindGroup,
+        MaterialPipeline, MaterialPipelineKey, PrepassPipeline, SetMaterialBindGroup,
+        SetMeshBindGroup, SetMeshViewBindGroup, SetPrepassViewBindGroup, PBR_SHADER_HANDLE,
     },
     prelude::*,
     reflect::TypeUuid,
     render::{
-        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        mesh::{GpuBufferInfo, GpuMesh, MeshVertexBufferLayout},
         render_asset::RenderAssets,
         render_phase::{
-            PhaseItem, RenderCommand, RenderCommandResult, SetItemPipeline, TrackedRenderPass,
+            DrawFunctionId, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
+            SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
+        renderer::{RenderAdapter, RenderDevice},
+        texture::FallbackImage,
     },
 };
 
-use crate::InstanceBuffer;
+use crate::{
+    cpu_culling::CpuCulledInstanceBuffers, culling::CulledInstanceBuffers, lod::LodInstanceBuffers,
+    sorting::SortedInstanceBuffers, storage_instances_supported, AnimationPoses, Instance,
+    InstanceBuffer, InstanceBuffers, InstanceData, InstanceStorage, VertexAnimationTexture,
+};
 
+pub const INSTANCE_FUNCTIONS_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 11938290153874307427);
 pub const INSTANCED_MESH_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 17287871048485609451);
+pub const INSTANCED_PREPASS_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 5863162932867107296);
+pub const INSTANCE_STORAGE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 9407512733081264619);
+pub const INSTANCE_VERTEX_OUTPUT_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 14513021906717485361);
+pub const INSTANCED_PBR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3259486092153278114);
+pub const INSTANCED_TEXTURE_LAYER_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12085733192466015627);
+pub const INSTANCE_ANIMATION_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6642918730515487211);
+pub const INSTANCE_VERTEX_ANIMATION_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 15320974188266145003);
+pub const INSTANCE_IMPOSTOR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 8154029367712904391);
+pub const INSTANCED_IMPOSTOR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 13574820964310528417);
+pub const INSTANCED_IMPOSTOR_PREPASS_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 4719385620173948263);
+pub const INSTANCE_CULLING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 2716394081524667389);
+pub const INSTANCE_SORTING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 9361027485316470523);
+pub const INSTANCED_MESH2D_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 16470935218874302659);
+pub const INSTANCE_PICKING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7391520846193027565);
 
 #[derive(Resource)]
-pub struct InstancedMeshMaterialPipeline<M: Material> {
+pub struct InstancedMeshMaterialPipeline<M: Material, I: InstanceData = Instance> {
     pub material_pipeline: MaterialPipeline<M>,
+    pub instance_storage: InstanceStorage,
+    pub instance_layout: Option<BindGroupLayout>,
+    marker: PhantomData<I>,
 }
 
-impl<M> FromWorld for InstancedMeshMaterialPipeline<M>
+impl<M, I> InstancedMeshMaterialPipeline<M, I>
 where
     M: Material,
+    I: InstanceData,
 {
-    fn from_world(world: &mut World) -> Self {
+    /// Expects `instance_storage` to be resolved for the render device already.
+    pub fn new(world: &mut World, instance_storage: InstanceStorage) -> Self {
+        world.init_resource::<InstanceBindGroupLayout>();
+        let instance_layout = world.resource::<InstanceBindGroupLayout>().0.clone();
+
+        let asset_server = world.resource::<AssetServer>();
+        let vertex_shader = match I::vertex_shader() {
+            ShaderRef::Default => INSTANCED_MESH_SHADER_HANDLE.typed(),
+            ShaderRef::Handle(handle) => handle,
+            ShaderRef::Path(path) => asset_server.load(path),
+        };
+
         let mut material_pipeline = MaterialPipeline::<M>::from_world(world);
-        material_pipeline.vertex_shader = Some(INSTANCED_MESH_SHADER_HANDLE.typed());
+        material_pipeline.vertex_shader = Some(vertex_shader);
+        // `StandardMaterial` and its like use a copy of bevy's PBR shader that applies the
+        // per-instance overrides.
+        if material_pipeline.fragment_shader == Some(PBR_SHADER_HANDLE.typed()) {
+            material_pipeline.fragment_shader = Some(INSTANCED_PBR_SHADER_HANDLE.typed());
+        }
+
+        Self {
+            material_pipeline,
+            instance_storage,
+            instance_layout,
+            marker: PhantomData,
+        }
+    }
+}
 
-        Self { material_pipeline }
+impl<M: Material, I: InstanceData> InstancedMeshMaterialPipeline<M, I> {
+    /// The draw function of the pipeline's [`InstanceStorage`] in `draw_functions`.
+    pub fn draw_function<P: PhaseItem>(&self, draw_functions: &DrawFunctions<P>) -> DrawFunctionId {
+        let draw_functions = draw_functions.read();
+        match self.instance_storage {
+            InstanceStorage::VertexBuffer => {
+                draw_functions.id::<DrawMeshInstancedWithMaterial<M>>()
+            }
+            InstanceStorage::StorageBuffer => {
+                draw_functions.id::<DrawMeshStorageInstancedWithMaterial<M>>()
+            }
+        }
+    }
+}
+
+impl<M, I> FromWorld for InstancedMeshMaterialPipeline<M, I>
+where
+    M: Material,
+    I: InstanceData,
+{
+    fn from_world(world: &mut World) -> Self {
+        Self::new(world, InstanceStorage::VertexBuffer)
     }
 }
 
-impl<M: Material> SpecializedMeshPipeline for InstancedMeshMaterialPipeline<M>
+impl<M: Material, I: InstanceData> SpecializedMeshPipeline for InstancedMeshMaterialPipeline<M, I>
 where
     M::Data: PartialEq + Eq + Hash + Clone,
 {
@@ -52,21 +139,351 @@ where
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
         let mut descriptor = self.material_pipeline.specialize(key, layout)?;
+        push_instance_layout::<I>(
+            &mut descriptor,
+            self.instance_storage,
+            self.instance_layout.as_ref(),
+        );
+
+        Ok(descriptor)
+    }
+}
 
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: VertexFormat::Float32x3.size(),
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![VertexAttribute {
-                format: VertexFormat::Float32x3,
-                offset: 0,
-                shader_location: 10,
-            }],
-        });
+/// Specializes bevy's prepass pipeline for instanced meshes. Used for the depth and normal
+/// prepass and for the shadow pass.
+#[derive(Resource)]
+pub struct InstancedPrepassPipeline<M: Material, I: InstanceData = Instance> {
+    pub prepass_pipeline: PrepassPipeline<M>,
+    pub instance_storage: InstanceStorage,
+    pub instance_layout: Option<BindGroupLayout>,
+    marker: PhantomData<I>,
+}
+
+impl<M, I> InstancedPrepassPipeline<M, I>
+where
+    M: Material,
+    I: InstanceData,
+{
+    /// Expects `instance_storage` to be resolved for the render device already.
+    pub fn new(world: &mut World, instance_storage: InstanceStorage) -> Self {
+        world.init_resource::<InstanceBindGroupLayout>();
+        let instance_layout = world.resource::<InstanceBindGroupLayout>().0.clone();
+
+        let asset_server = world.resource::<AssetServer>();
+        let vertex_shader = match I::prepass_vertex_shader() {
+            ShaderRef::Default => INSTANCED_PREPASS_SHADER_HANDLE.typed(),
+            ShaderRef::Handle(handle) => handle,
+            ShaderRef::Path(path) => asset_server.load(path),
+        };
+
+        let mut prepass_pipeline = PrepassPipeline::<M>::from_world(world);
+        prepass_pipeline.material_vertex_shader = Some(vertex_shader);
+
+        Self {
+            prepass_pipeline,
+            instance_storage,
+            instance_layout,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: Material, I: InstanceData> InstancedPrepassPipeline<M, I> {
+    /// The draw function of the pipeline's [`InstanceStorage`] in `draw_functions`.
+    pub fn draw_function<P: PhaseItem>(&self, draw_functions: &DrawFunctions<P>) -> DrawFunctionId {
+        let draw_functions = draw_functions.read();
+        match self.instance_storage {
+            InstanceStorage::VertexBuffer => draw_functions.id::<DrawMeshInstancedPrepass<M>>(),
+            InstanceStorage::StorageBuffer => {
+                draw_functions.id::<DrawMeshStorageInstancedPrepass<M>>()
+            }
+        }
+    }
+}
+
+impl<M, I> FromWorld for InstancedPrepassPipeline<M, I>
+where
+    M: Material,
+    I: InstanceData,
+{
+    fn from_world(world: &mut World) -> Self {
+        Self::new(world, InstanceStorage::VertexBuffer)
+    }
+}
+
+impl<M: Material, I: InstanceData> SpecializedMeshPipeline for InstancedPrepassPipeline<M, I>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = MaterialPipelineKey<M>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.prepass_pipeline.specialize(key, layout)?;
+        push_instance_layout::<I>(
+            &mut descriptor,
+            self.instance_storage,
+            self.instance_layout.as_ref(),
+        );
 
         Ok(descriptor)
     }
 }
 
+/// Adds the instance buffer of `I` and its shader defs to a mesh pipeline.
+pub(crate) fn push_instance_layout<I: InstanceData>(
+    descriptor: &mut RenderPipelineDescriptor,
+    instance_storage: InstanceStorage,
+    instance_bind_group_layout: Option<&BindGroupLayout>,
+) {
+    let instance_layout = I::layout();
+    let shader_defs = match (instance_storage, instance_bind_group_layout) {
+        (InstanceStorage::StorageBuffer, Some(instance_bind_group_layout)) => {
+            descriptor.layout.push(instance_bind_group_layout.clone());
+            instance_layout.storage_shader_defs()
+        }
+        _ => {
+            descriptor
+                .vertex
+                .buffers
+                .push(instance_layout.vertex_buffer_layout());
+            instance_layout.shader_defs
+        }
+    };
+
+    descriptor
+        .vertex
+        .shader_defs
+        .extend_from_slice(&shader_defs);
+    if let Some(fragment) = &mut descriptor.fragment {
+        fragment.shader_defs.extend_from_slice(&shader_defs);
+    }
+}
+
+/// Layout of the bind group of [`InstanceStorage::StorageBuffer`]: the instances of an entity
+/// and its [`InstanceBindings`]. `None` where vertex shaders cannot read storage buffers.
+#[derive(Resource, Clone, Deref)]
+pub struct InstanceBindGroupLayout(pub Option<BindGroupLayout>);
+
+impl FromWorld for InstanceBindGroupLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        if !storage_instances_supported(render_device, world.resource::<RenderAdapter>()) {
+            return Self(None);
+        }
+
+        let storage_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        // Read with `textureLoad`, so any float format can be bound.
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        Self(Some(render_device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor {
+                label: Some("instance_layout"),
+                entries: &[
+                    storage_entry(0),
+                    storage_entry(1),
+                    storage_entry(2),
+                    texture_entry(3),
+                    texture_entry(4),
+                    storage_entry(5),
+                ],
+            },
+        )))
+    }
+}
+
+/// Resources of an entity bound next to its instances, or empty stand-ins where it has none.
+#[derive(Clone)]
+pub struct InstanceBindings {
+    /// The [`AnimationPoses`] of the entity.
+    pub animation_poses: Buffer,
+    /// The [`VertexAnimationTexture`] of the entity and its textures.
+    pub vertex_animation: Buffer,
+    pub vertex_animation_positions: TextureView,
+    pub vertex_animation_normals: TextureView,
+    /// How far each instance is faded into its level of detail, see
+    /// [`InstancedLods`](crate::InstancedLods).
+    pub lod_fades: Buffer,
+}
+
+impl InstanceBindings {
+    /// Identifies the bound resources, so bind groups are only created again when they change.
+    pub fn key(&self) -> InstanceBindingsKey {
+        (
+            self.animation_poses.id(),
+            self.vertex_animation.id(),
+            self.vertex_animation_positions.id(),
+            self.vertex_animation_normals.id(),
+            self.lod_fades.id(),
+        )
+    }
+}
+
+pub type InstanceBindingsKey = (BufferId, BufferId, TextureViewId, TextureViewId, BufferId);
+
+pub fn instance_bind_group(
+    render_device: &RenderDevice,
+    layout: &BindGroupLayout,
+    instances: &Buffer,
+    bindings: &InstanceBindings,
+) -> BindGroup {
+    render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("instance_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: instances.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: bindings.animation_poses.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: bindings.vertex_animation.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(&bindings.vertex_animation_positions),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&bindings.vertex_animation_normals),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: bindings.lod_fades.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+/// Buffers standing in for the [`InstanceBindings`] of entities without them.
+pub(crate) struct EmptyInstanceBindings {
+    animation_poses: Buffer,
+    vertex_animation: Buffer,
+    lod_fades: Buffer,
+}
+
+impl EmptyInstanceBindings {
+    fn new(render_device: &RenderDevice) -> Self {
+        let create_buffer = |label, words: Vec<[u32; 4]>| {
+            render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(&words),
+                usage: BufferUsages::STORAGE,
+            })
+        };
+
+        Self {
+            animation_poses: create_buffer(
+                "empty animation poses buffer",
+                AnimationPoses::new(0).storage_words(),
+            ),
+            vertex_animation: create_buffer(
+                "empty vertex animation buffer",
+                VertexAnimationTexture::default().storage_words(),
+            ),
+            // Instances past the end of the fades are not faded.
+            lod_fades: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("empty lod fade buffer"),
+                contents: bytemuck::bytes_of(&1.0f32),
+                usage: BufferUsages::STORAGE,
+            }),
+        }
+    }
+}
+
+/// Binds the instance buffer of every entity together with its [`InstanceBindings`].
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(crate) fn prepare_instance_bind_groups(
+    mut empty_bindings: Local<Option<EmptyInstanceBindings>>,
+    render_device: Res<RenderDevice>,
+    instance_bind_group_layout: Res<InstanceBindGroupLayout>,
+    fallback_image: Res<FallbackImage>,
+    render_images: Res<RenderAssets<Image>>,
+    render_poses: Res<RenderAssets<AnimationPoses>>,
+    render_vertex_animations: Res<RenderAssets<VertexAnimationTexture>>,
+    mut instance_buffers: ResMut<InstanceBuffers>,
+    animated_meshes: Query<(
+        Option<&Handle<AnimationPoses>>,
+        Option<&Handle<VertexAnimationTexture>>,
+    )>,
+) {
+    let Some(layout) = &**instance_bind_group_layout else {
+        return;
+    };
+    let empty_bindings =
+        empty_bindings.get_or_insert_with(|| EmptyInstanceBindings::new(&render_device));
+
+    for (entity, instance_buffer) in instance_buffers.iter_mut() {
+        let (poses, vertex_animation) = animated_meshes.get(*entity).unwrap_or((None, None));
+        let poses = poses.and_then(|handle| render_poses.get(handle));
+        let vertex_animation =
+            vertex_animation.and_then(|handle| render_vertex_animations.get(handle));
+        let texture_view = |handle: Option<&Handle<Image>>| {
+            handle
+                .and_then(|handle| render_images.get(handle))
+                .map_or(&fallback_image.texture_view, |image| &image.texture_view)
+                .clone()
+        };
+
+        let bindings = InstanceBindings {
+            animation_poses: poses
+                .map_or(&empty_bindings.animation_poses, |poses| &poses.buffer)
+                .clone(),
+            vertex_animation: vertex_animation
+                .map_or(&empty_bindings.vertex_animation, |vertex_animation| {
+                    &vertex_animation.buffer
+                })
+                .clone(),
+            vertex_animation_positions: texture_view(
+                vertex_animation.map(|vertex_animation| &vertex_animation.positions),
+            ),
+            vertex_animation_normals: texture_view(
+                vertex_animation.and_then(|vertex_animation| vertex_animation.normals.as_ref()),
+            ),
+            lod_fades: empty_bindings.lod_fades.clone(),
+        };
+
+        // New and reallocated buffers, and entities whose bindings changed, are bound again.
+        if instance_buffer.bind_group.is_some()
+            && matches!(&instance_buffer.bindings, Some(bound) if bound.key() == bindings.key())
+        {
+            continue;
+        }
+        instance_buffer.bind_group = Some(instance_bind_group(
+            &render_device,
+            layout,
+            &instance_buffer.buffer,
+            &bindings,
+        ));
+        instance_buffer.bindings = Some(bindings);
+    }
+}
+
 pub type DrawMeshInstancedWithMaterial<M> = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
@@ -75,42 +492,222 @@ pub type DrawMeshInstancedWithMaterial<M> = (
     DrawMeshInstanced,
 );
 
+pub type DrawMeshInstancedPrepass<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    DrawMeshInstanced,
+);
+
+pub type DrawMeshStorageInstancedWithMaterial<M> = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    DrawMeshInstanced,
+);
+
+pub type DrawMeshStorageInstancedPrepass<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    DrawMeshInstanced,
+);
+
+/// Binds the instances of [`InstanceStorage::StorageBuffer`].
+pub struct SetInstanceBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetInstanceBindGroup<I> {
+    type Param = (
+        SRes<InstanceBuffers>,
+        SRes<CulledInstanceBuffers>,
+        SRes<CpuCulledInstanceBuffers>,
+        SRes<SortedInstanceBuffers>,
+        SRes<LodInstanceBuffers>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view: Entity,
+        _item_query: (),
+        (
+            instance_buffers,
+            culled_instance_buffers,
+            cpu_culled_instance_buffers,
+            sorted_instance_buffers,
+            lod_instance_buffers,
+        ): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let lod_instances = lod_instance_buffers.into_inner().get(view, item.entity());
+        let culled_instances = culled_instance_buffers
+            .into_inner()
+            .get(view, item.entity());
+        let cpu_culled_instances = cpu_culled_instance_buffers
+            .into_inner()
+            .get(view, item.entity());
+        let sorted_instances = sorted_instance_buffers
+            .into_inner()
+            .get(view, item.entity());
+        let bind_group = if let Some(lod_instances) = lod_instances {
+            lod_instances.bind_group()
+        } else if let Some(culled_instances) = culled_instances {
+            culled_instances.bind_group()
+        } else if let Some(cpu_culled_instances) = cpu_culled_instances {
+            cpu_culled_instances.bind_group()
+        } else if let Some(sorted_instances) = sorted_instances {
+            sorted_instances.bind_group()
+        } else {
+            instance_buffers
+                .into_inner()
+                .get(&item.entity())
+                .and_then(InstanceBuffer::bind_group)
+        };
+        let Some(bind_group) = bind_group else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
 pub struct DrawMeshInstanced;
 
 impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
-    type Param = SRes<RenderAssets<Mesh>>;
-    type ViewWorldQuery = ();
-    type ItemWorldQuery = (Read<Handle<Mesh>>, Read<InstanceBuffer>);
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<InstanceBuffers>,
+        SRes<CulledInstanceBuffers>,
+        SRes<CpuCulledInstanceBuffers>,
+        SRes<SortedInstanceBuffers>,
+        SRes<LodInstanceBuffers>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = Read<Handle<Mesh>>;
 
     #[inline]
     fn render<'w>(
-        _item: &P,
-        _view: (),
-        (mesh_handle, instance_buffer): (&'w Handle<Mesh>, &'w InstanceBuffer),
-        meshes: SystemParamItem<'w, '_, Self::Param>,
+        item: &P,
+        view: Entity,
+        mesh_handle: &'w Handle<Mesh>,
+        (
+            meshes,
+            instance_buffers,
+            culled_instance_buffers,
+            cpu_culled_instance_buffers,
+            sorted_instance_buffers,
+            lod_instance_buffers,
+        ): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
-            Some(gpu_mesh) => gpu_mesh,
-            None => return RenderCommandResult::Failure,
+        let Some(gpu_mesh) = meshes.into_inner().get(mesh_handle) else {
+            return RenderCommandResult::Failure;
+        };
+        let Some(view_instances) = view_instances(
+            view,
+            item.entity(),
+            instance_buffers.into_inner(),
+            culled_instance_buffers.into_inner(),
+            cpu_culled_instance_buffers.into_inner(),
+            sorted_instance_buffers.into_inner(),
+            lod_instance_buffers.into_inner(),
+        ) else {
+            return RenderCommandResult::Failure;
         };
 
-        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
-
-        match &gpu_mesh.buffer_info {
-            GpuBufferInfo::Indexed {
-                buffer,
-                index_format,
-                count,
-            } => {
-                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
-            }
-            GpuBufferInfo::NonIndexed { vertex_count } => {
-                pass.draw(0..*vertex_count, 0..instance_buffer.length as u32);
+        draw_instances(gpu_mesh, view_instances, pass);
+        RenderCommandResult::Success
+    }
+}
+
+/// Instances to draw: their buffer, their count, and the indirect draw arguments if the count is
+/// on the GPU.
+pub(crate) type ViewInstances<'w> = (&'w Buffer, u32, Option<&'w Buffer>);
+
+/// The instances `entity` draws in `view`.
+///
+/// Levels of detail and instances culled on the CPU draw the instances selected for the view,
+/// sorted instances their copy sorted for the view, instances culled on the GPU or drawn
+/// indirectly are drawn with the count on the GPU.
+pub(crate) fn view_instances<'w>(
+    view: Entity,
+    entity: Entity,
+    instance_buffers: &'w InstanceBuffers,
+    culled_instance_buffers: &'w CulledInstanceBuffers,
+    cpu_culled_instance_buffers: &'w CpuCulledInstanceBuffers,
+    sorted_instance_buffers: &'w SortedInstanceBuffers,
+    lod_instance_buffers: &'w LodInstanceBuffers,
+) -> Option<ViewInstances<'w>> {
+    if let Some(lod_instances) = lod_instance_buffers.get(view, entity) {
+        return Some((lod_instances.instances(), lod_instances.len(), None));
+    }
+    if let Some(cpu_culled_instances) = cpu_culled_instance_buffers.get(view, entity) {
+        return Some((
+            cpu_culled_instances.instances(),
+            cpu_culled_instances.len(),
+            None,
+        ));
+    }
+
+    let instance_buffer = instance_buffers.get(&entity)?;
+    let culled_instances = culled_instance_buffers.get(view, entity);
+    let sorted_instances = sorted_instance_buffers.get(view, entity);
+    Some(match (culled_instances, sorted_instances) {
+        (Some(culled_instances), _) => (
+            culled_instances.instances(),
+            instance_buffer.len() as u32,
+            Some(culled_instances.indirect()),
+        ),
+        (None, Some(sorted_instances)) => (
+            sorted_instances.instances(),
+            instance_buffer.len() as u32,
+            None,
+        ),
+        (None, None) => (
+            instance_buffer.buffer(),
+            instance_buffer.len() as u32,
+            instance_buffer.indirect(),
+        ),
+    })
+}
+
+/// Draws `gpu_mesh` once for each of the `view_instances`.
+pub(crate) fn draw_instances<'w>(
+    gpu_mesh: &'w GpuMesh,
+    (instances, instance_count, indirect): ViewInstances<'w>,
+    pass: &mut TrackedRenderPass<'w>,
+) {
+    // Indirect draws read their instance count from the GPU.
+    if indirect.is_none() && instance_count == 0 {
+        return;
+    }
+
+    pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+    pass.set_vertex_buffer(1, instances.slice(..));
+
+    match &gpu_mesh.buffer_info {
+        GpuBufferInfo::Indexed {
+            buffer,
+            index_format,
+            count,
+        } => {
+            pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+            match indirect {
+                Some(indirect) => pass.draw_indexed_indirect(indirect, 0),
+                None => pass.draw_indexed(0..*count, 0, 0..instance_count),
             }
         }
-        RenderCommandResult::Success
+        GpuBufferInfo::NonIndexed { vertex_count } => match indirect {
+            Some(indirect) => pass.draw_indirect(indirect, 0),
+            None => pass.draw(0..*vertex_count, 0..instance_count),
+        },
     }
 }
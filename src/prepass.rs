@@ -0,0 +1,155 @@
+use std::hash::Hash;
+
+use bevy::{
+    core_pipeline::prepass::{AlphaMask3dPrepass, DepthPrepass, NormalPrepass, Opaque3dPrepass},
+    pbr::{MaterialPipelineKey, MeshPipelineKey, MeshUniform, RenderMaterials},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_phase::{DrawFunctions, RenderPhase},
+        render_resource::{PipelineCache, SpecializedMeshPipelines},
+        view::{ExtractedView, VisibleEntities},
+    },
+    utils::HashSet,
+};
+
+use crate::{
+    auto_instance::AutoInstancedEntities, lod::InstancedLodEntities,
+    pipeline::InstancedPrepassPipeline, specialization::SpecializationErrors, InstanceData,
+    RenderInstances,
+};
+
+/// Queues instanced meshes into the depth and normal prepass of views with a
+/// [`DepthPrepass`] or [`NormalPrepass`].
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn queue_instanced_prepass_meshes<M, I>(
+    opaque_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3dPrepass>>,
+    instanced_prepass_pipeline: Res<InstancedPrepassPipeline<M, I>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedPrepassPipeline<M, I>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<M>>,
+    render_instances: Res<RenderInstances<I>>,
+    lod_entities: Res<InstancedLodEntities>,
+    auto_instanced: Res<AutoInstancedEntities>,
+    mut queued_batches: Local<HashSet<Entity>>,
+    specialization_errors: Res<SpecializationErrors>,
+    instanced_meshes_with_material: Query<(&MeshUniform, &Handle<Mesh>, &Handle<M>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<Opaque3dPrepass>,
+        &mut RenderPhase<AlphaMask3dPrepass>,
+        Option<&DepthPrepass>,
+        Option<&NormalPrepass>,
+    )>,
+) where
+    M: Material,
+    M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
+{
+    let draw_instanced_opaque_prepass =
+        instanced_prepass_pipeline.draw_function(&opaque_draw_functions);
+    let draw_instanced_alpha_mask_prepass =
+        instanced_prepass_pipeline.draw_function(&alpha_mask_draw_functions);
+
+    for (
+        view,
+        visible_entities,
+        mut opaque_phase,
+        mut alpha_mask_phase,
+        depth_prepass,
+        normal_prepass,
+    ) in &mut views
+    {
+        let mut view_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+        if depth_prepass.is_some() {
+            view_key |= MeshPipelineKey::DEPTH_PREPASS;
+        }
+        if normal_prepass.is_some() {
+            view_key |= MeshPipelineKey::NORMAL_PREPASS;
+        }
+
+        let rangefinder = view.rangefinder3d();
+        for entity in auto_instanced
+            .drawn_entities(&visible_entities.entities, &mut queued_batches)
+            .flat_map(|entity| lod_entities.drawn_entities(entity))
+        {
+            let Ok((mesh_uniform, mesh_handle, material_handle)) =
+                instanced_meshes_with_material.get(entity)
+            else {
+                continue;
+            };
+            let source = match lod_entities.instances_of(entity) {
+                Some(source) if render_instances.contains_key(&source) => source,
+                _ => continue,
+            };
+
+            if let (Some(mesh), Some(material)) = (
+                render_meshes.get(mesh_handle),
+                render_materials.get(material_handle),
+            ) {
+                let mut mesh_key =
+                    MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
+                let alpha_mode = material.properties.alpha_mode;
+                match alpha_mode {
+                    AlphaMode::Opaque => {}
+                    AlphaMode::Mask(_) => mesh_key |= MeshPipelineKey::ALPHA_MASK,
+                    AlphaMode::Blend
+                    | AlphaMode::Premultiplied
+                    | AlphaMode::Add
+                    | AlphaMode::Multiply => continue,
+                }
+
+                let pipeline_id = match pipelines.specialize(
+                    &pipeline_cache,
+                    &instanced_prepass_pipeline,
+                    MaterialPipelineKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline_id) => pipeline_id,
+                    Err(error) => {
+                        specialization_errors.report(
+                            source,
+                            mesh_handle,
+                            material_handle.id(),
+                            error,
+                        );
+                        continue;
+                    }
+                };
+
+                let distance =
+                    rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
+
+                match alpha_mode {
+                    AlphaMode::Opaque => {
+                        opaque_phase.add(Opaque3dPrepass {
+                            entity,
+                            draw_function: draw_instanced_opaque_prepass,
+                            pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Mask(_) => {
+                        alpha_mask_phase.add(AlphaMask3dPrepass {
+                            entity,
+                            draw_function: draw_instanced_alpha_mask_prepass,
+                            pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Blend
+                    | AlphaMode::Premultiplied
+                    | AlphaMode::Add
+                    | AlphaMode::Multiply => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    ecs::system::SystemParam,
+    math::{Affine3A, Vec3A},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology, VertexAttributeValues},
+        primitives::Aabb,
+    },
+};
+
+use crate::{
+    bvh::{ray_bounds_distance, InstanceBvh, InstanceBvhPlugin, MeshVersions},
+    Instance, InstanceData, Instances,
+};
+
+/// Keeps the [`InstanceBvh`] of entities with [`Instances`] of `I` up to date for
+/// [`InstanceRaycast`], adding an [`InstanceBvhPlugin`] if needed.
+///
+/// Only needs the main world, so it also works in headless apps with `Assets<Mesh>`.
+pub struct InstanceRaycastPlugin<I = Instance>(PhantomData<I>);
+
+impl<I> Default for InstanceRaycastPlugin<I> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<I: InstanceData> Plugin for InstanceRaycastPlugin<I> {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<InstanceBvhPlugin<I>>() {
+            app.add_plugin(InstanceBvhPlugin::<I>::default());
+        }
+    }
+}
+
+/// The nearest instance hit by a ray.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceRayHit {
+    pub instance_index: usize,
+    /// How far along the ray the instance is hit, in multiples of its direction.
+    pub distance: f32,
+    /// Where the instance is hit, in the space of the ray.
+    pub position: Vec3,
+}
+
+/// The nearest of `instances` of `mesh` hit by `ray`, given in the space of their entity.
+///
+/// Each instance is tested against the bounds of the mesh first, then against its triangles,
+/// from both sides. Only meshes with a `TriangleList` topology can be hit.
+pub fn raycast_instances<I: InstanceData>(
+    ray: Ray,
+    instances: &Instances<I>,
+    mesh: &Mesh,
+) -> Option<InstanceRayHit> {
+    let triangles = MeshTriangles::new(mesh)?;
+    let mut nearest: Option<(usize, f32)> = None;
+    for (index, instance) in instances.iter().enumerate() {
+        let Some(distance) = triangles.cast_instance(ray, instance) else {
+            continue;
+        };
+        if !matches!(nearest, Some((_, nearest_distance)) if nearest_distance <= distance) {
+            nearest = Some((index, distance));
+        }
+    }
+    nearest.map(|(index, distance)| ray_hit(ray, index, distance))
+}
+
+/// Same as [`raycast_instances`], but only tests the instances whose bounds in `bvh` the ray
+/// crosses. `bvh` must have been built from `instances` and `mesh`.
+pub fn raycast_instances_in_bvh<I: InstanceData>(
+    ray: Ray,
+    instances: &Instances<I>,
+    mesh: &Mesh,
+    bvh: &InstanceBvh,
+) -> Option<InstanceRayHit> {
+    let triangles = MeshTriangles::new(mesh)?;
+    bvh.nearest_ray_hit(ray, |index| {
+        triangles.cast_instance(ray, instances.get(index)?)
+    })
+    .map(|(index, distance)| ray_hit(ray, index, distance))
+}
+
+fn ray_hit(ray: Ray, instance_index: usize, distance: f32) -> InstanceRayHit {
+    InstanceRayHit {
+        instance_index,
+        distance,
+        position: ray.get_point(distance),
+    }
+}
+
+/// `ray` in the space `transform` maps from, or `None` if it cannot be inverted.
+///
+/// Affine transforms keep distances along the ray in multiples of its direction, so hits in
+/// either space are equally far.
+fn inverse_transform_ray(ray: Ray, transform: &Affine3A) -> Option<Ray> {
+    if transform.matrix3.determinant() == 0.0 {
+        return None;
+    }
+    let inverse = transform.inverse();
+    Some(Ray {
+        origin: inverse.transform_point3(ray.origin),
+        direction: inverse.transform_vector3(ray.direction),
+    })
+}
+
+/// The positions and triangles of a mesh.
+struct MeshTriangles<'a> {
+    aabb: Aabb,
+    positions: &'a [[f32; 3]],
+    indices: Option<&'a Indices>,
+}
+
+impl<'a> MeshTriangles<'a> {
+    fn new(mesh: &'a Mesh) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return None;
+        };
+
+        Some(Self {
+            aabb: mesh.compute_aabb()?,
+            positions,
+            indices: mesh.indices(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        match self.indices {
+            Some(indices) => indices.len() / 3,
+            None => self.positions.len() / 3,
+        }
+    }
+
+    fn triangle(&self, triangle: usize) -> Option<[Vec3A; 3]> {
+        let vertex = |corner: usize| {
+            let index = 3 * triangle + corner;
+            let index = match self.indices {
+                Some(Indices::U16(indices)) => indices[index] as usize,
+                Some(Indices::U32(indices)) => indices[index] as usize,
+                None => index,
+            };
+            self.positions.get(index).copied().map(Vec3A::from)
+        };
+        Some([vertex(0)?, vertex(1)?, vertex(2)?])
+    }
+
+    /// How far along `ray`, in the space of its entity, it hits `instance`.
+    fn cast_instance<I: InstanceData>(&self, ray: Ray, instance: &I) -> Option<f32> {
+        let ray = inverse_transform_ray(ray, &instance.transform())?;
+        ray_bounds_distance(ray, self.aabb.min(), self.aabb.max())?;
+        self.cast(ray)
+    }
+
+    /// How far along `ray`, in the space of the mesh, it hits the nearest triangle.
+    fn cast(&self, ray: Ray) -> Option<f32> {
+        (0..self.len())
+            .filter_map(|triangle| ray_triangle_distance(ray, self.triangle(triangle)?))
+            .min_by(f32::total_cmp)
+    }
+}
+
+/// How far along `ray` it hits the triangle `[a, b, c]` from either side, with the
+/// Möller-Trumbore algorithm.
+fn ray_triangle_distance(ray: Ray, [a, b, c]: [Vec3A; 3]) -> Option<f32> {
+    let direction = Vec3A::from(ray.direction);
+    let ab = b - a;
+    let ac = c - a;
+    let p = direction.cross(ac);
+    let determinant = ab.dot(p);
+    if determinant.abs() <= f32::EPSILON * ab.length() * ac.length() * direction.length() {
+        return None;
+    }
+
+    let inverse_determinant = determinant.recip();
+    let ao = Vec3A::from(ray.origin) - a;
+    let u = ao.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = ao.cross(ab);
+    let v = direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = ac.dot(q) * inverse_determinant;
+    (distance >= 0.0).then_some(distance)
+}
+
+/// Casts rays against the instances of `I` of every entity, in world space, using their
+/// [`InstanceBvh`] while it is up to date.
+///
+/// Works without rendering, for example on headless servers, with an [`InstanceRaycastPlugin`]
+/// keeping the hierarchies up to date.
+#[derive(SystemParam)]
+pub struct InstanceRaycast<'w, 's, I: InstanceData = Instance> {
+    meshes: Res<'w, Assets<Mesh>>,
+    mesh_versions: Res<'w, MeshVersions>,
+    #[allow(clippy::type_complexity)]
+    instanced_meshes: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static GlobalTransform,
+            Ref<'static, Instances<I>>,
+            &'static Handle<Mesh>,
+            Option<&'static InstanceBvh>,
+        ),
+    >,
+}
+
+impl<'w, 's, I: InstanceData> InstanceRaycast<'w, 's, I> {
+    /// The nearest instance hit by `ray`, and its entity. Of instances hit at the same distance,
+    /// the lowest entity and then the lowest instance index wins.
+    pub fn cast(&self, ray: Ray) -> Option<(Entity, InstanceRayHit)> {
+        let mut nearest: Option<(Entity, InstanceRayHit)> = None;
+        for entity in self.instanced_meshes.iter().map(|(entity, ..)| entity) {
+            let Some(hit) = self.cast_entity(entity, ray) else {
+                continue;
+            };
+            let closer = match nearest {
+                Some((nearest_entity, nearest_hit)) => {
+                    hit.distance < nearest_hit.distance
+                        || (hit.distance == nearest_hit.distance && entity < nearest_entity)
+                }
+                None => true,
+            };
+            if closer {
+                nearest = Some((entity, hit));
+            }
+        }
+        nearest
+    }
+
+    /// The nearest instance of `entity` hit by `ray`.
+    pub fn cast_entity(&self, entity: Entity, ray: Ray) -> Option<InstanceRayHit> {
+        let (_, transform, instances, mesh_handle, bvh) = self.instanced_meshes.get(entity).ok()?;
+        let mesh = self.meshes.get(mesh_handle)?;
+        let local_ray = inverse_transform_ray(ray, &transform.affine())?;
+
+        let hit = match bvh {
+            Some(bvh) if bvh.is_current(&instances, mesh_handle, &self.mesh_versions) => {
+                raycast_instances_in_bvh(local_ray, &instances, mesh, bvh)
+            }
+            // Instances changed since the hierarchy was built are tested one by one.
+            _ => raycast_instances(local_ray, &instances, mesh),
+        }?;
+        Some(ray_hit(ray, hit.instance_index, hit.distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::AssetPlugin, core::TaskPoolPlugin, ecs::system::SystemState, render::mesh::shape,
+    };
+
+    use super::*;
+    use crate::{
+        bvh::MeshVersionsPlugin,
+        test_utils::{random_instances, random_numbers},
+    };
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray { origin, direction }
+    }
+
+    fn cube() -> Mesh {
+        Mesh::from(shape::Cube { size: 2.0 })
+    }
+
+    #[test]
+    fn ray_triangle_distance_hits_from_both_sides() {
+        let triangle = [
+            Vec3A::new(-1.0, -1.0, 0.0),
+            Vec3A::new(1.0, -1.0, 0.0),
+            Vec3A::new(0.0, 1.0, 0.0),
+        ];
+        let distance = |origin, direction| ray_triangle_distance(ray(origin, direction), triangle);
+
+        assert_eq!(distance(Vec3::new(0.0, 0.0, 5.0), Vec3::NEG_Z), Some(5.0));
+        assert_eq!(distance(Vec3::new(0.0, 0.0, -2.0), Vec3::Z), Some(2.0));
+        // In multiples of the direction.
+        assert_eq!(
+            distance(Vec3::new(0.0, 0.0, 5.0), -2.0 * Vec3::Z),
+            Some(2.5)
+        );
+
+        assert_eq!(distance(Vec3::new(2.0, 0.0, 5.0), Vec3::NEG_Z), None);
+        assert_eq!(distance(Vec3::new(0.0, 0.0, 5.0), Vec3::Z), None);
+        assert_eq!(distance(Vec3::new(0.0, 0.0, 5.0), Vec3::X), None);
+    }
+
+    #[test]
+    fn raycast_instances_in_bvh_matches_brute_force() {
+        let mesh = cube();
+        let mut random = random_numbers(0x2545_f491);
+        let instances = Instances(random_instances(500, &mut random));
+        let bvh = InstanceBvh::new(&mesh.compute_aabb().unwrap(), &instances);
+
+        for _ in 0..200 {
+            let origin = Vec3::new(random(), random(), random()) * 160.0 - 80.0;
+            let target = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let ray = ray(origin, target - origin);
+            assert_eq!(
+                raycast_instances_in_bvh(ray, &instances, &mesh, &bvh),
+                raycast_instances(ray, &instances, &mesh),
+            );
+        }
+    }
+
+    #[test]
+    fn cast_breaks_ties_by_entity_then_instance_index() {
+        let mut app = App::new();
+        app.add_plugin(TaskPoolPlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_plugin(MeshVersionsPlugin);
+        let mesh = app.world.resource_mut::<Assets<Mesh>>().add(cube());
+        let instances = || {
+            Instances(vec![
+                Instance::from_translation(Vec3::new(5.0, 0.0, 0.0)),
+                Instance::from_translation(Vec3::ZERO),
+                Instance::from_translation(Vec3::ZERO),
+            ])
+        };
+
+        // The lower entity is moved to a newer archetype, so it is not found first.
+        let lower = app.world.spawn_empty().id();
+        let higher = app
+            .world
+            .spawn((GlobalTransform::IDENTITY, instances(), mesh.clone()))
+            .id();
+        app.world.entity_mut(lower).insert((
+            GlobalTransform::IDENTITY,
+            instances(),
+            mesh,
+            Name::new("lower"),
+        ));
+        assert!(lower < higher);
+
+        let mut raycast = SystemState::<InstanceRaycast>::new(&mut app.world);
+        let raycast = raycast.get(&app.world);
+        let (entity, hit) = raycast
+            .cast(ray(Vec3::new(0.0, 0.0, 5.0), Vec3::NEG_Z))
+            .unwrap();
+        assert_eq!(entity, lower);
+        assert_eq!(hit.instance_index, 1);
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.position, Vec3::new(0.0, 0.0, 1.0));
+    }
+}
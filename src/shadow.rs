@@ -0,0 +1,149 @@
+use std::hash::Hash;
+
+use bevy::{
+    pbr::{
+        CascadesVisibleEntities, CubemapVisibleEntities, ExtractedDirectionalLight,
+        ExtractedPointLight, LightEntity, MaterialPipelineKey, MeshPipelineKey, NotShadowCaster,
+        RenderMaterials, Shadow, ViewLightEntities,
+    },
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_phase::{DrawFunctions, RenderPhase},
+        render_resource::{PipelineCache, SpecializedMeshPipelines},
+        view::VisibleEntities,
+    },
+    utils::HashSet,
+};
+
+use crate::{
+    auto_instance::AutoInstancedEntities, lod::InstancedLodEntities,
+    pipeline::InstancedPrepassPipeline, specialization::SpecializationErrors, InstanceData,
+    RenderInstances,
+};
+
+/// Queues instanced meshes into the shadow phase of every light view they are visible from.
+///
+/// Mirrors bevy's `queue_shadows`, using the instanced prepass pipeline.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn queue_instanced_shadows<M, I>(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    instanced_prepass_pipeline: Res<InstancedPrepassPipeline<M, I>>,
+    casting_meshes: Query<(&Handle<Mesh>, &Handle<M>), Without<NotShadowCaster>>,
+    render_instances: Res<RenderInstances<I>>,
+    (lod_entities, auto_instanced): (Res<InstancedLodEntities>, Res<AutoInstancedEntities>),
+    mut queued_batches: Local<HashSet<Entity>>,
+    specialization_errors: Res<SpecializationErrors>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<M>>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<InstancedPrepassPipeline<M, I>>>,
+    pipeline_cache: Res<PipelineCache>,
+    view_lights: Query<(Entity, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    point_light_entities: Query<&CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities: Query<&CascadesVisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_light_entities: Query<&VisibleEntities, With<ExtractedPointLight>>,
+) where
+    M: Material,
+    M::Data: PartialEq + Eq + Hash + Clone,
+    I: InstanceData,
+{
+    let draw_instanced_shadow_mesh =
+        instanced_prepass_pipeline.draw_function(&shadow_draw_functions);
+
+    for (view_entity, view_lights) in &view_lights {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let Ok((light_entity, mut shadow_phase)) =
+                view_light_shadow_phases.get_mut(view_light_entity)
+            else {
+                continue;
+            };
+            let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
+            let visible_entities = match light_entity {
+                LightEntity::Directional {
+                    light_entity,
+                    cascade_index,
+                } => directional_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .and_then(|entities| entities.entities.get(&view_entity))
+                    .and_then(|cascades| cascades.get(*cascade_index)),
+                LightEntity::Point {
+                    light_entity,
+                    face_index,
+                } => point_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .map(|entities| entities.get(*face_index)),
+                LightEntity::Spot { light_entity } => spot_light_entities.get(*light_entity).ok(),
+            };
+            let Some(visible_entities) = visible_entities else {
+                continue;
+            };
+
+            // Batched entities are drawn by their batch, and entities with levels of detail by
+            // their levels.
+            for entity in auto_instanced
+                .drawn_entities(&visible_entities.entities, &mut queued_batches)
+                .flat_map(|entity| lod_entities.drawn_entities(entity))
+            {
+                let source = match lod_entities.instances_of(entity) {
+                    Some(source) if render_instances.contains_key(&source) => source,
+                    _ => continue,
+                };
+                let Ok((mesh_handle, material_handle)) = casting_meshes.get(entity) else {
+                    continue;
+                };
+
+                if let (Some(mesh), Some(material)) = (
+                    render_meshes.get(mesh_handle),
+                    render_materials.get(material_handle),
+                ) {
+                    let mut mesh_key =
+                        MeshPipelineKey::from_primitive_topology(mesh.primitive_topology)
+                            | MeshPipelineKey::DEPTH_PREPASS;
+                    if is_directional_light {
+                        mesh_key |= MeshPipelineKey::DEPTH_CLAMP_ORTHO;
+                    }
+                    match material.properties.alpha_mode {
+                        AlphaMode::Mask(_) => {
+                            mesh_key |= MeshPipelineKey::ALPHA_MASK;
+                        }
+                        AlphaMode::Blend | AlphaMode::Premultiplied | AlphaMode::Add => {
+                            mesh_key |= MeshPipelineKey::BLEND_PREMULTIPLIED_ALPHA;
+                        }
+                        _ => {}
+                    }
+
+                    let pipeline = match pipelines.specialize(
+                        &pipeline_cache,
+                        &instanced_prepass_pipeline,
+                        MaterialPipelineKey {
+                            mesh_key,
+                            bind_group_data: material.key.clone(),
+                        },
+                        &mesh.layout,
+                    ) {
+                        Ok(pipeline) => pipeline,
+                        Err(error) => {
+                            specialization_errors.report(
+                                source,
+                                mesh_handle,
+                                material_handle.id(),
+                                error,
+                            );
+                            continue;
+                        }
+                    };
+
+                    shadow_phase.add(Shadow {
+                        draw_function: draw_instanced_shadow_mesh,
+                        pipeline,
+                        entity,
+                        distance: 0.0,
+                    });
+                }
+            }
+        }
+    }
+}
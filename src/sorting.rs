@@ -0,0 +1,658 @@
+use bevy::{
+    asset::load_internal_asset,
+    core_pipeline::core_3d::Transparent3d,
+    pbr::MeshUniform,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_phase::RenderPhase,
+        render_resource::*,
+        renderer::{RenderAdapter, RenderContext, RenderDevice, RenderQueue},
+        view::ExtractedView,
+        RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    indirect::IndirectInstances,
+    lod::InstancedLodEntities,
+    pipeline::{
+        instance_bind_group, InstanceBindGroupLayout, InstanceBindingsKey,
+        INSTANCE_SORTING_SHADER_HANDLE,
+    },
+    storage_instances_supported, InstanceBuffer, InstanceBuffers, InstanceData, InstanceSemantic,
+    InstanceSystems, RenderInstances,
+};
+
+pub const INSTANCE_SORTING: &str = "instance_sorting";
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Largest power of two of keys the sort has steps for.
+const MAX_KEY_COUNT_LOG2: u32 = 31;
+
+/// Sorts the instances of an entity back to front for every camera on the GPU every frame, so
+/// overlapping instances of a transparent material blend in the right order.
+///
+/// Instances are sorted by the view space depth of their origin. Entities with
+/// [`InstancedLods`](crate::InstancedLods) sort the instances of each level on the CPU instead.
+/// Instances are not culled while they are sorted, see
+/// [`GpuInstanceCulling`](crate::GpuInstanceCulling). Has no effect on entities with
+/// [`IndirectInstances`], nor where compute shaders are not supported, such as on WebGL2.
+#[derive(Component, Clone, Copy, Debug, Default, ExtractComponent)]
+pub struct GpuInstanceSorting;
+
+/// Sorts the instances of an entity roughly front to back for every camera on the GPU, so the
+/// depth test rejects the fragments of opaque instances behind nearer ones before they are
+/// shaded.
+///
+/// Instances are bucketed by the distance of their origin to the camera, and keep their order
+/// within a bucket. They are only sorted again once the camera moved further than
+/// `resort_distance` from where they were last sorted for it, or the instances or the entity's
+/// transform changed, since turning the camera does not change their distances. The same
+/// limitations as for [`GpuInstanceSorting`] apply, which takes precedence.
+#[derive(Component, Clone, Copy, Debug, ExtractComponent)]
+pub struct GpuFrontToBackSorting {
+    /// Width of the distance buckets.
+    pub bucket_size: f32,
+    pub resort_distance: f32,
+}
+
+impl Default for GpuFrontToBackSorting {
+    fn default() -> Self {
+        Self {
+            bucket_size: 1.0,
+            resort_distance: 1.0,
+        }
+    }
+}
+
+/// Adds the compute pass behind [`GpuInstanceSorting`] and [`GpuFrontToBackSorting`]. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct InstanceSortingPlugin;
+
+impl Plugin for InstanceSortingPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_SORTING_SHADER_HANDLE,
+            "instance_sorting.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugin(ExtractComponentPlugin::<GpuInstanceSorting>::default())
+            .add_plugin(ExtractComponentPlugin::<GpuFrontToBackSorting>::default());
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<InstanceSortingPipeline>()
+            .init_resource::<SortedInstanceBuffers>()
+            .add_system(cleanup_sorted_instance_buffers.in_set(RenderSet::Cleanup));
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node(INSTANCE_SORTING, InstanceSortingNode);
+        render_graph.add_node_edge(
+            INSTANCE_SORTING,
+            bevy::render::main_graph::node::CAMERA_DRIVER,
+        );
+    }
+}
+
+/// Adds sorting of the instances of `I` to [`InstanceSortingPlugin`].
+pub(crate) fn add_instance_sorting<I: InstanceData>(app: &mut App) {
+    if !app.is_plugin_added::<InstanceSortingPlugin>() {
+        app.add_plugin(InstanceSortingPlugin);
+    }
+    app.sub_app_mut(RenderApp).add_system(
+        prepare_instance_sorting::<I>
+            .in_set(RenderSet::Prepare)
+            .after(InstanceSystems::PrepareBindGroups),
+    );
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct InstanceSortingUniform {
+    view_depth: Vec4,
+    view_position: Vec4,
+    model: Mat4,
+    instance_count: u32,
+    key_count: u32,
+    stride: u32,
+    transform_offset: u32,
+}
+
+#[derive(Resource)]
+pub struct InstanceSortingPipeline {
+    pub layout: BindGroupLayout,
+    pub step_layout: BindGroupLayout,
+    /// Every step of sorting up to `1 << MAX_KEY_COUNT_LOG2` keys, bound at a dynamic offset.
+    step_bind_group: BindGroup,
+    step_alignment: u32,
+    pub write_keys: CachedComputePipelineId,
+    pub sort_keys: CachedComputePipelineId,
+    pub write_sorted_instances: CachedComputePipelineId,
+}
+
+impl FromWorld for InstanceSortingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_sorting_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(
+                            std::mem::size_of::<InstanceSortingUniform>() as u64,
+                        ),
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+            ],
+        });
+
+        let step_size = std::mem::size_of::<[u32; 2]>() as u64;
+        let step_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_sorting_step_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: BufferSize::new(step_size),
+                },
+                count: None,
+            }],
+        });
+
+        // Steps for fewer keys are a prefix of the steps for more keys.
+        let step_alignment = render_device.limits().min_uniform_buffer_offset_alignment;
+        let mut steps = Vec::new();
+        for k in (1..=MAX_KEY_COUNT_LOG2).map(|log2| 1u32 << log2) {
+            let mut j = k / 2;
+            while j > 0 {
+                let offset = steps.len();
+                steps.resize(offset + step_alignment as usize, 0);
+                steps[offset..offset + step_size as usize]
+                    .copy_from_slice(bytemuck::cast_slice(&[j, k]));
+                j /= 2;
+            }
+        }
+        let step_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("instance sorting step buffer"),
+            contents: &steps,
+            usage: BufferUsages::UNIFORM,
+        });
+        let step_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("instance_sorting_step_bind_group"),
+            layout: &step_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: &step_buffer,
+                    offset: 0,
+                    size: BufferSize::new(step_size),
+                }),
+            }],
+        });
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue_pipeline = |label: &'static str, entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(label.into()),
+                layout: vec![layout.clone(), step_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: INSTANCE_SORTING_SHADER_HANDLE.typed(),
+                shader_defs: Vec::new(),
+                entry_point: entry_point.into(),
+            })
+        };
+        let write_keys = queue_pipeline("instance_sorting_keys_pipeline", "write_keys");
+        let sort_keys = queue_pipeline("instance_sorting_sort_pipeline", "sort_keys");
+        let write_sorted_instances =
+            queue_pipeline("instance_sorting_write_pipeline", "write_sorted_instances");
+
+        Self {
+            layout,
+            step_layout,
+            step_bind_group,
+            step_alignment,
+            write_keys,
+            sort_keys,
+            write_sorted_instances,
+        }
+    }
+}
+
+/// Instances of one entity sorted for one view.
+pub struct SortedInstances {
+    uniform: Buffer,
+    keys: Buffer,
+    instances: Buffer,
+    bind_group: Option<(BufferId, BindGroup)>,
+    /// Keyed by the [`InstanceBindings`](crate::pipeline::InstanceBindings) bound with the instances.
+    instance_bind_group: Option<(InstanceBindingsKey, BindGroup)>,
+    capacity: u64,
+    instance_count: u32,
+    key_count: u32,
+    /// Whether the instances are sorted again this frame.
+    sort: bool,
+}
+
+impl SortedInstances {
+    fn new(render_device: &RenderDevice, capacity: u64, stride: u64) -> Self {
+        let keys = (capacity / stride).max(1).next_power_of_two();
+        Self {
+            uniform: render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance sorting uniform buffer"),
+                size: std::mem::size_of::<InstanceSortingUniform>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            keys: render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance sorting key buffer"),
+                size: keys * std::mem::size_of::<[u32; 2]>() as u64,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+            instances: render_device.create_buffer(&BufferDescriptor {
+                label: Some("sorted instance data buffer"),
+                size: capacity,
+                usage: BufferUsages::VERTEX | BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+            bind_group: None,
+            instance_bind_group: None,
+            capacity,
+            instance_count: 0,
+            key_count: 0,
+            sort: true,
+        }
+    }
+
+    /// Grows the buffers to fit the instances of `instance_buffer` and binds them, returning
+    /// whether the sorted instances were lost.
+    fn prepare(
+        &mut self,
+        render_device: &RenderDevice,
+        instance_sorting_pipeline: &InstanceSortingPipeline,
+        instance_bind_group_layout: &InstanceBindGroupLayout,
+        instance_buffer: &InstanceBuffer,
+        stride: u64,
+    ) -> bool {
+        let grown = self.capacity < instance_buffer.capacity();
+        if grown {
+            *self = Self::new(render_device, instance_buffer.capacity(), stride);
+        }
+
+        let source = instance_buffer.buffer();
+        if !matches!(&self.bind_group, Some((id, _)) if *id == source.id()) {
+            let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("instance_sorting_bind_group"),
+                layout: &instance_sorting_pipeline.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: source.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: self.keys.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: self.instances.as_entire_binding(),
+                    },
+                ],
+            });
+            self.bind_group = Some((source.id(), bind_group));
+        }
+        if let (Some(layout), Some(bindings)) =
+            (&**instance_bind_group_layout, &instance_buffer.bindings)
+        {
+            let key = bindings.key();
+            if !matches!(&self.instance_bind_group, Some((bound, _)) if *bound == key) {
+                let bind_group =
+                    instance_bind_group(render_device, layout, &self.instances, bindings);
+                self.instance_bind_group = Some((key, bind_group));
+            }
+        }
+        grown
+    }
+
+    pub fn instances(&self) -> &Buffer {
+        &self.instances
+    }
+
+    /// Binds the sorted instances for [`InstanceStorage::StorageBuffer`](crate::InstanceStorage).
+    pub fn bind_group(&self) -> Option<&BindGroup> {
+        self.instance_bind_group
+            .as_ref()
+            .map(|(_, bind_group)| bind_group)
+    }
+}
+
+#[derive(Default)]
+struct SortedInstancesPool {
+    sorted_instances: Vec<SortedInstances>,
+    used: usize,
+}
+
+/// Instances of one entity sorted front to back for one camera, and what they were last sorted
+/// for.
+struct FrontToBackInstances {
+    sorted_instances: SortedInstances,
+    /// Camera position, model transform, instance buffer and instance generation.
+    sorted_for: Option<(Vec3, Mat4, BufferId, u64)>,
+    used: bool,
+}
+
+/// Sorted instances of every entity with [`GpuInstanceSorting`] or [`GpuFrontToBackSorting`] for
+/// every camera this frame.
+///
+/// Buffers sorted back to front are pooled per entity and reused across frames. Buffers sorted
+/// front to back are kept per camera and entity while they are used, and only sorted again when
+/// the camera moves away or the instances change.
+#[derive(Resource, Default)]
+pub struct SortedInstanceBuffers {
+    pools: HashMap<Entity, SortedInstancesPool>,
+    views: HashMap<(Entity, Entity), usize>,
+    front_to_back: HashMap<(Entity, Entity), FrontToBackInstances>,
+}
+
+impl SortedInstanceBuffers {
+    /// The instances of `entity` sorted for `view`, if they are sorted for it this frame.
+    pub fn get(&self, view: Entity, entity: Entity) -> Option<&SortedInstances> {
+        if let Some(index) = self.views.get(&(view, entity)) {
+            return self.pools.get(&entity)?.sorted_instances.get(*index);
+        }
+        self.front_to_back
+            .get(&(view, entity))
+            .filter(|front_to_back| front_to_back.used)
+            .map(|front_to_back| &front_to_back.sorted_instances)
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prepare_instance_sorting<I: InstanceData>(
+    mut sorted_instance_buffers: ResMut<SortedInstanceBuffers>,
+    instance_sorting_pipeline: Res<InstanceSortingPipeline>,
+    instance_bind_group_layout: Res<InstanceBindGroupLayout>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_adapter: Res<RenderAdapter>,
+    render_queue: Res<RenderQueue>,
+    render_instances: Res<RenderInstances<I>>,
+    instance_buffers: Res<InstanceBuffers>,
+    lod_entities: Res<InstancedLodEntities>,
+    // Shadow views neither blend nor reject much with early depth tests, so only cameras are
+    // sorted for.
+    views: Query<(Entity, &ExtractedView), With<RenderPhase<Transparent3d>>>,
+    sorted_meshes: Query<
+        (
+            Entity,
+            &MeshUniform,
+            Option<&GpuInstanceSorting>,
+            Option<&GpuFrontToBackSorting>,
+        ),
+        (
+            Or<(With<GpuInstanceSorting>, With<GpuFrontToBackSorting>)>,
+            // Their instance count is decided on the GPU, which the sorting pass does not read.
+            Without<IndirectInstances>,
+        ),
+    >,
+) {
+    // The sorting pass reads the instance buffers as storage buffers, which they only are where
+    // supported.
+    if !storage_instances_supported(&render_device, &render_adapter) {
+        return;
+    }
+    // Until the pipelines are compiled, instances are drawn unsorted.
+    if [
+        instance_sorting_pipeline.write_keys,
+        instance_sorting_pipeline.sort_keys,
+        instance_sorting_pipeline.write_sorted_instances,
+    ]
+    .into_iter()
+    .any(|pipeline| pipeline_cache.get_compute_pipeline(pipeline).is_none())
+    {
+        return;
+    }
+
+    let layout = I::layout();
+    let stride = std::mem::size_of::<I>() as u64;
+    if !stride.is_multiple_of(4) {
+        return;
+    }
+    let transform_offset = layout
+        .semantic_offset(InstanceSemantic::Transform)
+        .map_or(u32::MAX, |offset| (offset / 4) as u32);
+
+    let SortedInstanceBuffers {
+        pools,
+        views: sorted_views,
+        front_to_back: front_to_back_instances,
+    } = &mut *sorted_instance_buffers;
+    for (view_entity, view) in &views {
+        let view_depth = view.transform.compute_matrix().inverse().row(2);
+        let origin = view.transform.translation();
+
+        for (entity, mesh_uniform, back_to_front, front_to_back) in &sorted_meshes {
+            // Levels of detail sort their own selection of the instances.
+            if !render_instances.contains_key(&entity) || lod_entities.get(entity).is_some() {
+                continue;
+            }
+            let Some(instance_buffer) = instance_buffers.get(&entity) else {
+                continue;
+            };
+            let instance_count = instance_buffer.len() as u32;
+            let Some(key_count) = instance_count.checked_next_power_of_two() else {
+                continue;
+            };
+            if instance_count < 2 {
+                continue;
+            }
+
+            // Sorting back to front wins, since blending depends on it.
+            let (sorted_instances, bucket_size) = match (back_to_front, front_to_back) {
+                (None, Some(front_to_back)) => {
+                    let front_to_back_instances = front_to_back_instances
+                        .entry((view_entity, entity))
+                        .or_insert_with(|| FrontToBackInstances {
+                            sorted_instances: SortedInstances::new(
+                                &render_device,
+                                instance_buffer.capacity(),
+                                stride,
+                            ),
+                            sorted_for: None,
+                            used: false,
+                        });
+                    front_to_back_instances.used = true;
+
+                    let instances = (
+                        mesh_uniform.transform,
+                        instance_buffer.buffer().id(),
+                        instance_buffer.generation(),
+                    );
+                    let stale = match front_to_back_instances.sorted_for {
+                        Some((sorted_origin, model, buffer, generation)) => {
+                            sorted_origin.distance(origin) > front_to_back.resort_distance
+                                || (model, buffer, generation) != instances
+                        }
+                        None => true,
+                    };
+                    let sorted_instances = &mut front_to_back_instances.sorted_instances;
+                    let lost = sorted_instances.prepare(
+                        &render_device,
+                        &instance_sorting_pipeline,
+                        &instance_bind_group_layout,
+                        instance_buffer,
+                        stride,
+                    );
+                    sorted_instances.sort = stale || lost;
+                    if sorted_instances.sort {
+                        let (model, buffer, generation) = instances;
+                        front_to_back_instances.sorted_for =
+                            Some((origin, model, buffer, generation));
+                    }
+                    (
+                        sorted_instances,
+                        front_to_back.bucket_size.max(f32::EPSILON),
+                    )
+                }
+                _ => {
+                    let pool = pools.entry(entity).or_default();
+                    if pool.used == pool.sorted_instances.len() {
+                        pool.sorted_instances.push(SortedInstances::new(
+                            &render_device,
+                            instance_buffer.capacity(),
+                            stride,
+                        ));
+                    }
+                    let sorted_instances = &mut pool.sorted_instances[pool.used];
+                    sorted_views.insert((view_entity, entity), pool.used);
+                    pool.used += 1;
+
+                    sorted_instances.prepare(
+                        &render_device,
+                        &instance_sorting_pipeline,
+                        &instance_bind_group_layout,
+                        instance_buffer,
+                        stride,
+                    );
+                    sorted_instances.sort = true;
+                    (sorted_instances, 0.0)
+                }
+            };
+            if !sorted_instances.sort {
+                continue;
+            }
+
+            sorted_instances.instance_count = instance_count;
+            sorted_instances.key_count = key_count;
+            render_queue.write_buffer(
+                &sorted_instances.uniform,
+                0,
+                bytemuck::bytes_of(&InstanceSortingUniform {
+                    view_depth,
+                    view_position: origin.extend(bucket_size),
+                    model: mesh_uniform.transform,
+                    instance_count,
+                    key_count,
+                    stride: (stride / 4) as u32,
+                    transform_offset,
+                }),
+            );
+        }
+    }
+}
+
+fn cleanup_sorted_instance_buffers(mut sorted_instance_buffers: ResMut<SortedInstanceBuffers>) {
+    sorted_instance_buffers.views.clear();
+    sorted_instance_buffers.pools.retain(|_, pool| {
+        pool.sorted_instances.truncate(pool.used);
+        pool.used = 0;
+        !pool.sorted_instances.is_empty()
+    });
+    sorted_instance_buffers
+        .front_to_back
+        .retain(|_, front_to_back| std::mem::take(&mut front_to_back.used));
+}
+
+/// Dispatches the bitonic sort of every entity and view sorted this frame.
+pub struct InstanceSortingNode;
+
+impl Node for InstanceSortingNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let sorted_instance_buffers = world.resource::<SortedInstanceBuffers>();
+        let instance_sorting_pipeline = world.resource::<InstanceSortingPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let (Some(write_keys), Some(sort_keys), Some(write_sorted_instances)) = (
+            pipeline_cache.get_compute_pipeline(instance_sorting_pipeline.write_keys),
+            pipeline_cache.get_compute_pipeline(instance_sorting_pipeline.sort_keys),
+            pipeline_cache.get_compute_pipeline(instance_sorting_pipeline.write_sorted_instances),
+        ) else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("instance_sorting_pass"),
+                });
+        let step_bind_group = &instance_sorting_pipeline.step_bind_group;
+        let back_to_front = sorted_instance_buffers
+            .pools
+            .values()
+            .flat_map(|pool| &pool.sorted_instances[..pool.used]);
+        let front_to_back = sorted_instance_buffers
+            .front_to_back
+            .values()
+            .filter(|front_to_back| front_to_back.used)
+            .map(|front_to_back| &front_to_back.sorted_instances);
+        for sorted_instances in back_to_front.chain(front_to_back) {
+            if !sorted_instances.sort {
+                continue;
+            }
+            let Some((_, bind_group)) = &sorted_instances.bind_group else {
+                continue;
+            };
+            let key_workgroups = sorted_instances.key_count.div_ceil(WORKGROUP_SIZE);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_bind_group(1, step_bind_group, &[0]);
+
+            pass.set_pipeline(write_keys);
+            pass.dispatch_workgroups(key_workgroups, 1, 1);
+
+            let log2 = sorted_instances.key_count.trailing_zeros();
+            pass.set_pipeline(sort_keys);
+            for step in 0..log2 * (log2 + 1) / 2 {
+                pass.set_bind_group(
+                    1,
+                    step_bind_group,
+                    &[step * instance_sorting_pipeline.step_alignment],
+                );
+                pass.dispatch_workgroups(key_workgroups, 1, 1);
+            }
+
+            pass.set_pipeline(write_sorted_instances);
+            pass.dispatch_workgroups(
+                sorted_instances.instance_count.div_ceil(WORKGROUP_SIZE),
+                1,
+                1,
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    asset::{Asset, HandleId},
+    prelude::*,
+    render::{render_resource::SpecializedMeshPipelineError, RenderApp},
+    utils::HashSet,
+};
+
+/// Sent when the pipeline an instanced entity is drawn with could not be specialized, for
+/// example because its mesh lacks a vertex attribute its material needs. The entity is not drawn
+/// while the error lasts.
+///
+/// Sent once per entity, mesh and material, and again if the error happens after the entity's
+/// mesh or material handle, or the mesh or material itself, changed.
+#[derive(Debug)]
+pub struct InstancedSpecializationError {
+    pub entity: Entity,
+    /// Weak handle to the mesh of the entity, or of its level of detail.
+    pub mesh: Handle<Mesh>,
+    pub error: SpecializedMeshPipelineError,
+}
+
+#[derive(Default)]
+struct ReportedErrors {
+    /// Entity, mesh and material triples already logged and sent.
+    reported: HashSet<(Entity, HandleId, HandleId)>,
+    unsent: Vec<InstancedSpecializationError>,
+}
+
+/// Specialization errors of instanced meshes, shared by the main and render worlds.
+#[derive(Resource, Clone, Default)]
+pub struct SpecializationErrors(Arc<Mutex<ReportedErrors>>);
+
+impl SpecializationErrors {
+    /// Logs `error` once per entity, mesh and material, and sends it as an
+    /// [`InstancedSpecializationError`] the next time the main world updates.
+    pub fn report(
+        &self,
+        entity: Entity,
+        mesh: &Handle<Mesh>,
+        material: HandleId,
+        error: SpecializedMeshPipelineError,
+    ) {
+        let Ok(mut reported) = self.0.lock() else {
+            return;
+        };
+        if reported.reported.insert((entity, mesh.id(), material)) {
+            error!(
+                "Could not specialize the pipeline of instanced entity {:?}, which is not drawn: {}",
+                entity, error
+            );
+            reported.unsent.push(InstancedSpecializationError {
+                entity,
+                mesh: mesh.clone_weak(),
+                error,
+            });
+        }
+    }
+}
+
+/// Sends the [`InstancedSpecializationError`]s of the render world in the main world. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct SpecializationErrorPlugin;
+
+impl Plugin for SpecializationErrorPlugin {
+    fn build(&self, app: &mut App) {
+        let specialization_errors = SpecializationErrors::default();
+        app.add_event::<InstancedSpecializationError>()
+            .insert_resource(specialization_errors.clone())
+            .add_system(send_specialization_errors);
+        app.sub_app_mut(RenderApp)
+            .insert_resource(specialization_errors);
+        add_specialization_error_asset::<Mesh>(app);
+    }
+}
+
+/// Forgets the reported [`InstancedSpecializationError`]s of entities whose `Handle<A>` changed
+/// or was removed, and of assets `A` that changed or were removed, so they are reported again if
+/// they happen again. Added by [`SpecializationErrorPlugin`] for meshes, and by the instanced
+/// pipeline plugins for their materials.
+pub struct SpecializationErrorAssetPlugin<A>(PhantomData<A>);
+
+impl<A> Default for SpecializationErrorAssetPlugin<A> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<A: Asset> Plugin for SpecializationErrorAssetPlugin<A> {
+    fn build(&self, app: &mut App) {
+        app.add_system(forget_specialization_errors::<A>);
+    }
+}
+
+/// Adds a [`SpecializationErrorAssetPlugin`] for `A` if needed.
+pub(crate) fn add_specialization_error_asset<A: Asset>(app: &mut App) {
+    if !app.is_plugin_added::<SpecializationErrorAssetPlugin<A>>() {
+        app.add_plugin(SpecializationErrorAssetPlugin::<A>::default());
+    }
+}
+
+fn send_specialization_errors(
+    specialization_errors: Res<SpecializationErrors>,
+    mut events: EventWriter<InstancedSpecializationError>,
+) {
+    if let Ok(mut reported) = specialization_errors.0.lock() {
+        events.send_batch(reported.unsent.drain(..));
+    }
+}
+
+fn forget_specialization_errors<A: Asset>(
+    specialization_errors: Res<SpecializationErrors>,
+    mut asset_events: EventReader<AssetEvent<A>>,
+    mut removed_handles: RemovedComponents<Handle<A>>,
+    changed_handles: Query<Entity, Changed<Handle<A>>>,
+) {
+    let assets: HashSet<HandleId> = asset_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => Some(handle.id()),
+            AssetEvent::Created { .. } => None,
+        })
+        .collect();
+    // Despawned entities lose their handles too.
+    let entities: HashSet<Entity> = removed_handles.iter().chain(&changed_handles).collect();
+    if assets.is_empty() && entities.is_empty() {
+        return;
+    }
+
+    if let Ok(mut reported) = specialization_errors.0.lock() {
+        reported.reported.retain(|(entity, mesh, material)| {
+            !entities.contains(entity) && !assets.contains(mesh) && !assets.contains(material)
+        });
+    }
+}
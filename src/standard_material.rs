@@ -0,0 +1,181 @@
+use std::ops::{Deref, DerefMut};
+
+use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey, PBR_PREPASS_SHADER_HANDLE},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupError, BindGroupLayout, Face, PreparedBindGroup,
+            RenderPipelineDescriptor, ShaderDefVal, ShaderRef, SpecializedMeshPipelineError,
+        },
+        renderer::RenderDevice,
+        texture::FallbackImage,
+    },
+};
+
+use crate::pipeline::INSTANCED_PBR_SHADER_HANDLE;
+
+/// A [`StandardMaterial`] whose fragment shader reads the per-instance inputs of the built-in
+/// instanced shaders, such as [`InstanceSemantic::Color`](crate::InstanceSemantic::Color), and
+/// otherwise supports every lighting feature of `bevy_pbr`.
+///
+/// With a `layer_grid`, its textures are atlases of one cell per
+/// [`InstanceSemantic::Layer`](crate::InstanceSemantic::Layer), so instances of one draw can show
+/// different variants of the material.
+///
+/// Needs a `MaterialPlugin::<InstancedStandardMaterial>` next to the
+/// [`InstancedMeshMaterialPipelinePlugin`](crate::InstancedMeshMaterialPipelinePlugin).
+/// `StandardMaterial` drawn through the plugin uses the same fragment shader, without layers.
+#[derive(Clone, Debug, Default, TypeUuid)]
+#[uuid = "5d6a3b0e-8f9c-4c1e-a2b7-3e41d07c96f2"]
+pub struct InstancedStandardMaterial {
+    pub material: StandardMaterial,
+    /// Columns and rows of the cells of the material's textures, filled row by row from layer
+    /// zero, or zero to sample the whole textures. The prepass ignores it.
+    pub layer_grid: UVec2,
+}
+
+impl InstancedStandardMaterial {
+    /// A material whose textures are split into `columns` by `rows` layers.
+    pub fn with_layers(material: StandardMaterial, columns: u32, rows: u32) -> Self {
+        Self {
+            material,
+            layer_grid: UVec2::new(columns, rows),
+        }
+    }
+}
+
+impl Deref for InstancedStandardMaterial {
+    type Target = StandardMaterial;
+
+    fn deref(&self) -> &StandardMaterial {
+        &self.material
+    }
+}
+
+impl DerefMut for InstancedStandardMaterial {
+    fn deref_mut(&mut self) -> &mut StandardMaterial {
+        &mut self.material
+    }
+}
+
+impl From<StandardMaterial> for InstancedStandardMaterial {
+    fn from(material: StandardMaterial) -> Self {
+        Self {
+            material,
+            layer_grid: UVec2::ZERO,
+        }
+    }
+}
+
+impl From<Color> for InstancedStandardMaterial {
+    fn from(color: Color) -> Self {
+        StandardMaterial::from(color).into()
+    }
+}
+
+impl From<Handle<Image>> for InstancedStandardMaterial {
+    fn from(texture: Handle<Image>) -> Self {
+        StandardMaterial::from(texture).into()
+    }
+}
+
+/// Mirrors bevy's `StandardMaterialKey`, whose fields are private.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct InstancedStandardMaterialKey {
+    normal_map: bool,
+    cull_mode: Option<Face>,
+    depth_bias: i32,
+    layer_grid: UVec2,
+}
+
+impl From<&InstancedStandardMaterial> for InstancedStandardMaterialKey {
+    fn from(material: &InstancedStandardMaterial) -> Self {
+        Self {
+            normal_map: material.normal_map_texture.is_some(),
+            cull_mode: material.cull_mode,
+            depth_bias: material.depth_bias as i32,
+            layer_grid: material.layer_grid,
+        }
+    }
+}
+
+impl AsBindGroup for InstancedStandardMaterial {
+    type Data = InstancedStandardMaterialKey;
+
+    fn as_bind_group(
+        &self,
+        layout: &BindGroupLayout,
+        render_device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        fallback_image: &FallbackImage,
+    ) -> Result<PreparedBindGroup<Self::Data>, AsBindGroupError> {
+        let prepared =
+            self.material
+                .as_bind_group(layout, render_device, images, fallback_image)?;
+
+        Ok(PreparedBindGroup {
+            bindings: prepared.bindings,
+            bind_group: prepared.bind_group,
+            data: self.into(),
+        })
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        StandardMaterial::bind_group_layout(render_device)
+    }
+}
+
+impl Material for InstancedStandardMaterial {
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // Mirrors `StandardMaterial::specialize`.
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.normal_map {
+                fragment
+                    .shader_defs
+                    .push("STANDARDMATERIAL_NORMAL_MAP".into());
+            }
+            let layer_grid = key.bind_group_data.layer_grid;
+            if layer_grid.cmpgt(UVec2::ZERO).all() {
+                fragment.shader_defs.extend([
+                    ShaderDefVal::UInt("INSTANCE_LAYER_COLUMNS".into(), layer_grid.x),
+                    ShaderDefVal::UInt("INSTANCE_LAYER_ROWS".into(), layer_grid.y),
+                ]);
+            }
+        }
+        descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
+        if let Some(label) = &mut descriptor.label {
+            *label = format!("instanced_pbr_{}", *label).into();
+        }
+        if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+            depth_stencil.bias.constant = key.bind_group_data.depth_bias;
+        }
+        Ok(())
+    }
+
+    fn prepass_fragment_shader() -> ShaderRef {
+        PBR_PREPASS_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        INSTANCED_PBR_SHADER_HANDLE.typed().into()
+    }
+
+    #[inline]
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    #[inline]
+    fn depth_bias(&self) -> f32 {
+        self.depth_bias
+    }
+}
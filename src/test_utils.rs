@@ -0,0 +1,28 @@
+use bevy::prelude::*;
+
+use crate::Instance;
+
+/// Pseudo-random numbers in `0..1`, the same on every run.
+pub(crate) fn random_numbers(mut seed: u32) -> impl FnMut() -> f32 {
+    move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// `count` instances scattered in a cube of 100 around the origin, randomly rotated and scaled.
+pub(crate) fn random_instances(count: usize, random: &mut impl FnMut() -> f32) -> Vec<Instance> {
+    (0..count)
+        .map(|_| {
+            let translation = Vec3::new(random(), random(), random()) * 100.0 - 50.0;
+            let rotation = Quat::from_euler(EulerRot::XYZ, random(), random(), random());
+            Instance::from(
+                Transform::from_translation(translation)
+                    .with_rotation(rotation)
+                    .with_scale(Vec3::splat(0.5 + 2.0 * random())),
+            )
+        })
+        .collect()
+}
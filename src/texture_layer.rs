@@ -0,0 +1,151 @@
+use bevy::{
+    pbr::{MaterialPipeline, MaterialPipelineKey},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupShaderType, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipelineError,
+        },
+    },
+};
+
+use crate::pipeline::INSTANCED_TEXTURE_LAYER_SHADER_HANDLE;
+
+/// Unlit material showing the texture array layer selected by the
+/// [`InstanceSemantic::Layer`](crate::InstanceSemantic::Layer) of each instance, so instances
+/// with different textures are drawn in a single draw call.
+///
+/// The texture must be a 2d array texture, for example an image reinterpreted with
+/// `Image::reinterpret_stacked_2d_as_array`. Instances without a layer show the first layer.
+/// Needs a `MaterialPlugin::<InstancedTextureArrayMaterial>`.
+#[derive(AsBindGroup, TypeUuid, Clone, Debug)]
+#[uuid = "b0d7a5c2-61e3-4f0a-9c8e-2a7f4d1e6b35"]
+#[uniform(0, TextureLayerMaterialUniform)]
+pub struct InstancedTextureArrayMaterial {
+    /// Multiplied with the texture.
+    pub color: Color,
+    #[texture(1, dimension = "2d_array")]
+    #[sampler(2)]
+    pub texture: Handle<Image>,
+    pub alpha_mode: AlphaMode,
+}
+
+impl InstancedTextureArrayMaterial {
+    pub fn new(texture: Handle<Image>) -> Self {
+        Self {
+            color: Color::WHITE,
+            texture,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+impl AsBindGroupShaderType<TextureLayerMaterialUniform> for InstancedTextureArrayMaterial {
+    fn as_bind_group_shader_type(
+        &self,
+        _images: &RenderAssets<Image>,
+    ) -> TextureLayerMaterialUniform {
+        TextureLayerMaterialUniform::new(self.color, UVec2::ONE, self.alpha_mode)
+    }
+}
+
+impl Material for InstancedTextureArrayMaterial {
+    fn fragment_shader() -> ShaderRef {
+        INSTANCED_TEXTURE_LAYER_SHADER_HANDLE.typed().into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+}
+
+/// Unlit material showing the cell of a texture atlas selected by the
+/// [`InstanceSemantic::Layer`](crate::InstanceSemantic::Layer) of each instance, so instances
+/// with different textures are drawn in a single draw call.
+///
+/// The atlas is a grid of equally sized cells, indexed row by row from the top left.
+/// Instances without a layer show the first cell.
+/// Needs a `MaterialPlugin::<InstancedTextureAtlasMaterial>`.
+#[derive(AsBindGroup, TypeUuid, Clone, Debug)]
+#[uuid = "4e19c8d3-0a7b-4b62-8f5d-93c2e6a1b7d4"]
+#[uniform(0, TextureLayerMaterialUniform)]
+pub struct InstancedTextureAtlasMaterial {
+    /// Multiplied with the texture.
+    pub color: Color,
+    #[texture(1)]
+    #[sampler(2)]
+    pub texture: Handle<Image>,
+    /// Columns and rows of the atlas.
+    pub grid: UVec2,
+    pub alpha_mode: AlphaMode,
+}
+
+impl InstancedTextureAtlasMaterial {
+    pub fn new(texture: Handle<Image>, columns: u32, rows: u32) -> Self {
+        Self {
+            color: Color::WHITE,
+            texture,
+            grid: UVec2::new(columns, rows),
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+impl AsBindGroupShaderType<TextureLayerMaterialUniform> for InstancedTextureAtlasMaterial {
+    fn as_bind_group_shader_type(
+        &self,
+        _images: &RenderAssets<Image>,
+    ) -> TextureLayerMaterialUniform {
+        TextureLayerMaterialUniform::new(self.color, self.grid.max(UVec2::ONE), self.alpha_mode)
+    }
+}
+
+impl Material for InstancedTextureAtlasMaterial {
+    fn fragment_shader() -> ShaderRef {
+        INSTANCED_TEXTURE_LAYER_SHADER_HANDLE.typed().into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        self.alpha_mode
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = &mut descriptor.fragment {
+            fragment.shader_defs.push("TEXTURE_ATLAS".into());
+        }
+        Ok(())
+    }
+}
+
+shader_uniform! {
+    /// The uniform of [`InstancedTextureArrayMaterial`](super::InstancedTextureArrayMaterial)
+    /// and [`InstancedTextureAtlasMaterial`](super::InstancedTextureAtlasMaterial).
+    #[derive(Clone, Default)]
+    pub struct TextureLayerMaterialUniform {
+        pub color: Vec4,
+        pub atlas_grid: UVec2,
+        /// Alpha below which fragments are discarded, or negative if none are.
+        pub alpha_cutoff: f32,
+    }
+}
+
+impl TextureLayerMaterialUniform {
+    fn new(color: Color, atlas_grid: UVec2, alpha_mode: AlphaMode) -> Self {
+        Self {
+            color: color.as_linear_rgba_f32().into(),
+            atlas_grid,
+            alpha_cutoff: match alpha_mode {
+                AlphaMode::Mask(cutoff) => cutoff,
+                _ => -1.0,
+            },
+        }
+    }
+}
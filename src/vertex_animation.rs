@@ -0,0 +1,396 @@
+use bevy::{
+    asset::{load_internal_asset, AssetLoader, AssetPath, HandleId, LoadContext, LoadedAsset},
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        render_asset::{PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssets},
+        render_resource::{Buffer, BufferInitDescriptor, BufferUsages},
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderSet,
+    },
+    utils::{BoxedFuture, HashMap},
+};
+use serde::Deserialize;
+
+use crate::{
+    pipeline::INSTANCE_VERTEX_ANIMATION_SHADER_HANDLE, InstanceData, InstanceSemantic,
+    InstanceStorage,
+};
+
+/// Animations baked offline into textures of per-vertex position offsets and normals, played
+/// per instance with an [`InstanceVertexAnimation`](crate::InstanceVertexAnimation). A cheaper
+/// alternative to skinning for large crowds.
+///
+/// The texel of vertex `v` in frame `f` of the whole animation is the `f * vertex_count + v`th
+/// texel of the texture, in row-major order. Position texels hold the offset of the vertex from
+/// its rest position, normalized to `bounds_min..bounds_max`. Normal texels hold the normal of
+/// the vertex, mapped from `-1..1` to `0..1`. Both textures are read as linear data: once an
+/// sRGB texture loads, it is replaced with a linear copy, leaving the original image as it is.
+///
+/// Usually loaded from a `.vat.ron` file by [`VertexAnimationLoader`], and only read with
+/// [`InstanceStorage::StorageBuffer`], elsewhere instances are drawn in the rest pose of the mesh
+/// and a warning is logged. Added to entities as a `Handle<VertexAnimationTexture>`.
+#[derive(Clone, Debug, Default, TypeUuid)]
+#[uuid = "c47e2a91-5b3d-4f68-9e0a-d8b16f7c3e25"]
+pub struct VertexAnimationTexture {
+    pub positions: Handle<Image>,
+    /// Without normals, animated vertices keep the normals of the mesh.
+    pub normals: Option<Handle<Image>>,
+    /// Number of vertices of the mesh the animation was baked from.
+    pub vertex_count: u32,
+    pub bounds_min: Vec3,
+    pub bounds_max: Vec3,
+    pub clips: Vec<VertexAnimationClip>,
+}
+
+/// A run of frames of a [`VertexAnimationTexture`] played as one looping animation.
+#[derive(Clone, Debug, Deserialize)]
+pub struct VertexAnimationClip {
+    /// Optional name to look the clip up by.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub start_frame: u32,
+    pub frame_count: u32,
+    pub frames_per_second: f32,
+}
+
+impl VertexAnimationTexture {
+    /// Index of the clip named `name`, for [`InstanceVertexAnimation::clip`](crate::InstanceVertexAnimation::clip).
+    pub fn clip_index(&self, name: &str) -> Option<u32> {
+        self.clips
+            .iter()
+            .position(|clip| clip.name.as_deref() == Some(name))
+            .map(|index| index as u32)
+    }
+
+    /// Contents of the storage buffer read by `instance_vertex_animation.wgsl`: a header with an
+    /// unused word, the vertex count, whether there are normals and the clip count, the bounds, then the first
+    /// frame, frame count, frame rate and time of each clip.
+    pub(crate) fn storage_words(&self) -> Vec<[u32; 4]> {
+        let mut words = Vec::with_capacity(3 + self.clips.len());
+        words.push([
+            0,
+            self.vertex_count,
+            self.normals.is_some() as u32,
+            self.clips.len() as u32,
+        ]);
+        words.push(bytemuck::cast(self.bounds_min.extend(0.0)));
+        words.push(bytemuck::cast(self.bounds_max.extend(0.0)));
+        words.extend(self.clips.iter().map(|clip| {
+            [
+                clip.start_frame,
+                clip.frame_count,
+                clip.frames_per_second.to_bits(),
+                0,
+            ]
+        }));
+        words
+    }
+}
+
+/// The metadata of a [`VertexAnimationTexture`] as written to `.vat.ron` files, with texture
+/// paths relative to the file:
+///
+/// ```ron
+/// (
+///     positions: "soldier_positions.exr",
+///     normals: Some("soldier_normals.png"),
+///     vertex_count: 2436,
+///     bounds_min: (-0.8, -0.1, -0.6),
+///     bounds_max: (0.8, 1.9, 0.6),
+///     clips: [
+///         (name: Some("walk"), start_frame: 0, frame_count: 32, frames_per_second: 30.0),
+///         (name: Some("run"), start_frame: 32, frame_count: 20, frames_per_second: 30.0),
+///     ],
+/// )
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct VertexAnimationMeta {
+    pub positions: String,
+    #[serde(default)]
+    pub normals: Option<String>,
+    pub vertex_count: u32,
+    pub bounds_min: [f32; 3],
+    pub bounds_max: [f32; 3],
+    pub clips: Vec<VertexAnimationClip>,
+}
+
+/// Loads [`VertexAnimationTexture`]s and their textures from `.vat.ron` files of
+/// [`VertexAnimationMeta`].
+#[derive(Default)]
+pub struct VertexAnimationLoader;
+
+impl AssetLoader for VertexAnimationLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let meta: VertexAnimationMeta = ron::de::from_bytes(bytes)?;
+
+            let directory = load_context.path().parent().unwrap_or(load_context.path());
+            let positions = AssetPath::new(directory.join(&meta.positions), None);
+            let normals = meta
+                .normals
+                .as_ref()
+                .map(|normals| AssetPath::new(directory.join(normals), None));
+
+            let vertex_animation = VertexAnimationTexture {
+                positions: load_context.get_handle(positions.clone()),
+                normals: normals
+                    .clone()
+                    .map(|normals| load_context.get_handle(normals)),
+                vertex_count: meta.vertex_count,
+                bounds_min: meta.bounds_min.into(),
+                bounds_max: meta.bounds_max.into(),
+                clips: meta.clips,
+            };
+            load_context.set_default_asset(
+                LoadedAsset::new(vertex_animation)
+                    .with_dependencies(std::iter::once(positions).chain(normals).collect()),
+            );
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vat.ron"]
+    }
+}
+
+/// The storage buffer of a [`VertexAnimationTexture`] and its textures.
+pub struct GpuVertexAnimationTexture {
+    pub buffer: Buffer,
+    pub positions: Handle<Image>,
+    pub normals: Option<Handle<Image>>,
+    /// The clips whose time is written to the buffer every frame.
+    pub clips: Vec<VertexAnimationClip>,
+}
+
+impl RenderAsset for VertexAnimationTexture {
+    type ExtractedAsset = VertexAnimationTexture;
+    type PreparedAsset = GpuVertexAnimationTexture;
+    type Param = SRes<RenderDevice>;
+
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        vertex_animation: Self::ExtractedAsset,
+        render_device: &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        Ok(GpuVertexAnimationTexture {
+            buffer: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("vertex animation buffer"),
+                contents: bytemuck::cast_slice(&vertex_animation.storage_words()),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            }),
+            positions: vertex_animation.positions,
+            normals: vertex_animation.normals,
+            clips: vertex_animation.clips,
+        })
+    }
+}
+
+/// Adds [`VertexAnimationTexture`] and its loader. Added by
+/// [`InstancesPlugin`](crate::InstancesPlugin).
+pub struct VertexAnimationPlugin;
+
+impl Plugin for VertexAnimationPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_VERTEX_ANIMATION_SHADER_HANDLE,
+            "instance_vertex_animation.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_asset::<VertexAnimationTexture>()
+            .init_asset_loader::<VertexAnimationLoader>()
+            .add_plugin(RenderAssetPlugin::<VertexAnimationTexture>::default())
+            .add_plugin(ExtractComponentPlugin::<Handle<VertexAnimationTexture>>::default())
+            .add_system(linearize_vertex_animation_textures);
+        app.sub_app_mut(RenderApp)
+            .add_system(write_vertex_animation_time.in_set(RenderSet::Prepare));
+    }
+}
+
+/// Replaces the sRGB textures of vertex animations with linear copies, since they hold positions
+/// and normals rather than colors. The originals may be shared with materials, so they are left
+/// as they are.
+fn linearize_vertex_animation_textures(
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut vertex_animation_events: EventReader<AssetEvent<VertexAnimationTexture>>,
+    mut linear_copies: Local<HashMap<HandleId, Handle<Image>>>,
+    mut vertex_animations: ResMut<Assets<VertexAnimationTexture>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let mut animations = Vec::new();
+    for event in image_events.iter() {
+        match event {
+            // Textures may load after the animation that uses them.
+            AssetEvent::Created { handle } => {
+                animations.extend(
+                    vertex_animations
+                        .iter()
+                        .filter_map(|(id, vertex_animation)| {
+                            (vertex_animation.positions == *handle
+                                || vertex_animation.normals.as_ref() == Some(handle))
+                            .then_some(id)
+                        }),
+                );
+            }
+            AssetEvent::Modified { handle } => {
+                let Some(copy) = linear_copies.get(&handle.id()) else {
+                    continue;
+                };
+                if let (true, Some(image)) = (images.contains(copy), images.get(handle)) {
+                    let linear_image = linear_copy(image);
+                    let copy = copy.clone_weak();
+                    images.set_untracked(copy, linear_image);
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                linear_copies.remove(&handle.id());
+            }
+        }
+    }
+    for event in vertex_animation_events.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            animations.push(handle.id());
+        }
+    }
+
+    for id in animations {
+        let handle = Handle::<VertexAnimationTexture>::weak(id);
+        let Some(vertex_animation) = vertex_animations.get(&handle) else {
+            continue;
+        };
+        let positions =
+            linear_texture(&vertex_animation.positions, &mut images, &mut linear_copies);
+        let normals = vertex_animation
+            .normals
+            .as_ref()
+            .and_then(|normals| linear_texture(normals, &mut images, &mut linear_copies));
+        // Only replacing a texture modifies the animation, which then finds nothing to replace.
+        if positions.is_none() && normals.is_none() {
+            continue;
+        }
+        if let Some(vertex_animation) = vertex_animations.get_mut(&handle) {
+            if let Some(positions) = positions {
+                vertex_animation.positions = positions;
+            }
+            if normals.is_some() {
+                vertex_animation.normals = normals;
+            }
+        }
+    }
+}
+
+/// The linear copy of `texture` if it is a loaded sRGB image, made once per texture and kept up
+/// to date with it.
+fn linear_texture(
+    texture: &Handle<Image>,
+    images: &mut Assets<Image>,
+    linear_copies: &mut HashMap<HandleId, Handle<Image>>,
+) -> Option<Handle<Image>> {
+    let image = images.get(texture)?;
+    if image.texture_descriptor.format.remove_srgb_suffix() == image.texture_descriptor.format {
+        return None;
+    }
+    if let Some(copy) = linear_copies.get(&texture.id()) {
+        if images.contains(copy) {
+            return Some(images.get_handle(copy));
+        }
+    }
+    let copy = images.add(linear_copy(image));
+    linear_copies.insert(texture.id(), copy.clone_weak());
+    Some(copy)
+}
+
+fn linear_copy(image: &Image) -> Image {
+    let mut copy = image.clone();
+    copy.texture_descriptor.format = copy.texture_descriptor.format.remove_srgb_suffix();
+    copy
+}
+
+/// Writes the time each clip is played at, which the prepass bindings do not have.
+fn write_vertex_animation_time(
+    time: Res<Time>,
+    render_queue: Res<RenderQueue>,
+    vertex_animations: Res<RenderAssets<VertexAnimationTexture>>,
+) {
+    let seconds = time.elapsed_seconds_f64();
+    for vertex_animation in vertex_animations.values() {
+        for (index, clip) in vertex_animation.clips.iter().enumerate() {
+            let offset = std::mem::size_of::<[u32; 4]>() * (3 + index) + 12;
+            render_queue.write_buffer(
+                &vertex_animation.buffer,
+                offset as u64,
+                bytemuck::bytes_of(&clip_time(clip, seconds)),
+            );
+        }
+    }
+}
+
+/// Loops of a clip after which its time wraps back to zero are a multiple of this, so that
+/// instances playing it at whole speeds, or at halves, thirds, quarters, fifths or sixths of its
+/// frame rate, do not skip when it wraps.
+const CLIP_WRAP_LOOPS: f64 = 60.0;
+
+/// `seconds` wrapped after a whole number of loops of `clip`, about an hour or more, so that it
+/// keeps its precision as an `f32` while the clip plays on seamlessly.
+fn clip_time(clip: &VertexAnimationClip, seconds: f64) -> f32 {
+    let loop_seconds = (clip.frame_count.max(1) as f64 / clip.frames_per_second as f64).abs();
+    if !loop_seconds.is_normal() {
+        return 0.0;
+    }
+    let loops = (3600.0 / loop_seconds / CLIP_WRAP_LOOPS).ceil() * CLIP_WRAP_LOOPS;
+    (seconds % (loop_seconds * loops)) as f32
+}
+
+/// Warns that instances of `I` with an [`InstanceSemantic::VertexAnimation`] keep the rest pose
+/// of their mesh when bound with `instance_storage`.
+pub(crate) fn warn_without_vertex_animations<I: InstanceData>(instance_storage: InstanceStorage) {
+    if instance_storage == InstanceStorage::VertexBuffer
+        && I::layout()
+            .semantic_offset(InstanceSemantic::VertexAnimation)
+            .is_some()
+    {
+        warn!(
+            "Instances of {} are not animated, since vertex animations are only read with \
+             InstanceStorage::StorageBuffer",
+            std::any::type_name::<I>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_time_wraps_after_whole_loops() {
+        let clip = VertexAnimationClip {
+            name: None,
+            start_frame: 0,
+            frame_count: 32,
+            frames_per_second: 30.0,
+        };
+        let loop_seconds = 32.0 / 30.0;
+        let wrap_seconds =
+            loop_seconds * (3600.0 / loop_seconds / CLIP_WRAP_LOOPS).ceil() * CLIP_WRAP_LOOPS;
+
+        assert!(clip_time(&clip, wrap_seconds - 0.01) > 3599.0);
+        assert!(clip_time(&clip, wrap_seconds + 0.01) < 0.02);
+        // Instances at half speed play on from the same frame across the wrap.
+        let frame = |time: f32| (0.5 * 30.0 * time).rem_euclid(32.0);
+        let wrapped = frame(clip_time(&clip, wrap_seconds + 0.5));
+        let unwrapped = frame((wrap_seconds + 0.5) as f32);
+        assert!((wrapped - unwrapped).abs() < 0.01);
+    }
+}